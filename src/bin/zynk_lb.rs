@@ -11,7 +11,10 @@ pub mod pb {
 }
 use pb::kv_client::KvClient;
 use pb::kv_server::{Kv, KvServer};
-use pb::{DelRequest, DelResponse, GetRequest, GetResponse, PutRequest, PutResponse};
+use pb::{
+    BatchWriteRequest, BatchWriteResponse, DelRequest, DelResponse, GetRequest, GetResponse,
+    PutRequest, PutResponse,
+};
 
 #[derive(Clone)]
 struct BackendPool {
@@ -52,10 +55,7 @@ impl Kv for LbSvc {
         let req = request.into_inner();
         let client = self.pool.pick();
         let mut cli = client.write().await;
-        cli.put(Request::new(req))
-            .await
-            .map(|_| Response::new(PutResponse {}))
-            .map_err(map_status)
+        cli.put(Request::new(req)).await.map_err(map_status)
     }
 
     async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
@@ -72,10 +72,17 @@ impl Kv for LbSvc {
         let req = request.into_inner();
         let client = self.pool.pick();
         let mut cli = client.write().await;
-        cli.del(Request::new(req))
-            .await
-            .map(|_| Response::new(DelResponse { removed: true }))
-            .map_err(map_status)
+        cli.del(Request::new(req)).await.map_err(map_status)
+    }
+
+    async fn batch_write(
+        &self,
+        request: Request<BatchWriteRequest>,
+    ) -> Result<Response<BatchWriteResponse>, Status> {
+        let req = request.into_inner();
+        let client = self.pool.pick();
+        let mut cli = client.write().await;
+        cli.batch_write(Request::new(req)).await.map_err(map_status)
     }
 }
 
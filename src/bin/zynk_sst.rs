@@ -0,0 +1,285 @@
+//! Offline inspection of table files.
+//!
+//! Prints a table's footer, its index and, for every data block, how many
+//! records it holds and whether its checksum matches. Exits with status 1
+//! if any table given is corrupt.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use zynk::storage::memtable::Entry;
+use zynk::storage::sstable::reader::SsTableReader;
+use zynk::storage::sstable::{Footer, FOOTER_SIZE, SSTABLE_MAGIC};
+
+const USAGE: &str = "usage: zynk-sst [--dump] [--hex] [--verify] FILE.sst...
+
+  --dump    print every record, tombstones included
+  --hex     print keys and values as hex instead of escaped UTF-8
+  --verify  print only whether each table is intact";
+
+struct Args {
+    dump: bool,
+    hex: bool,
+    verify: bool,
+    files: Vec<PathBuf>,
+}
+
+fn parse_args(args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut parsed = Args {
+        dump: false,
+        hex: false,
+        verify: false,
+        files: Vec::new(),
+    };
+    for arg in args {
+        match arg.as_str() {
+            "--dump" => parsed.dump = true,
+            "--hex" => parsed.hex = true,
+            "--verify" => parsed.verify = true,
+            "-h" | "--help" => return Err(String::new()),
+            flag if flag.starts_with('-') => return Err(format!("unknown option {flag}")),
+            _ => parsed.files.push(arg.into()),
+        }
+    }
+    if parsed.files.is_empty() {
+        return Err("no table given".to_string());
+    }
+    Ok(parsed)
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(msg) => {
+            if !msg.is_empty() {
+                eprintln!("zynk-sst: {msg}");
+            }
+            eprintln!("{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+    run(&args, &mut std::io::stdout().lock())
+}
+
+/// Checks or prints every table in `args` to `out`.
+fn run(args: &Args, out: &mut dyn Write) -> ExitCode {
+    let mut intact = true;
+    for path in &args.files {
+        let ok = if args.verify {
+            verify(path, out)
+        } else {
+            inspect(path, args, out)
+        };
+        match ok {
+            Ok(ok) => intact &= ok,
+            Err(e) => {
+                eprintln!("zynk-sst: {e}");
+                return ExitCode::from(2);
+            }
+        }
+    }
+    if intact {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Reads every block of the table at `path`, printing one line for it.
+fn verify(path: &Path, out: &mut dyn Write) -> std::io::Result<bool> {
+    let reader = match SsTableReader::open(path) {
+        Ok(reader) => reader,
+        Err(e) => {
+            writeln!(out, "{}: corrupt: {e}", path.display())?;
+            return Ok(false);
+        }
+    };
+    let index = reader.index();
+    let mut records = 0;
+    for i in 0..index.len() {
+        let (_, handle) = index.entry(i);
+        match reader.block(handle, false) {
+            Ok(block) => records += block.len(),
+            Err(e) => {
+                writeln!(
+                    out,
+                    "{}: corrupt: block {i} at offset {}: {e}",
+                    path.display(),
+                    handle.offset
+                )?;
+                return Ok(false);
+            }
+        }
+    }
+    writeln!(
+        out,
+        "{}: ok, {} block(s), {records} record(s)",
+        path.display(),
+        index.len()
+    )?;
+    Ok(true)
+}
+
+fn inspect(path: &Path, args: &Args, out: &mut dyn Write) -> std::io::Result<bool> {
+    writeln!(out, "{}", path.display())?;
+    let reader = match SsTableReader::open(path) {
+        Ok(reader) => reader,
+        Err(e) => {
+            // Show what the footer says even when it doesn't check out.
+            if let Some(footer) = read_footer(path) {
+                print_footer(&footer, out)?;
+            }
+            writeln!(out, "  cannot open: {e}")?;
+            return Ok(false);
+        }
+    };
+    print_footer(&reader.footer(), out)?;
+    if let Ok(len) = reader.file_len() {
+        writeln!(out, "  file size: {len}")?;
+    }
+    writeln!(
+        out,
+        "  filter: {}",
+        if reader.has_filter() { "bloom" } else { "none" }
+    )?;
+
+    let index = reader.index();
+    writeln!(out, "  index: {} block(s)", index.len())?;
+    for i in 0..index.len() {
+        let (last_key, handle) = index.entry(i);
+        writeln!(
+            out,
+            "    {i}: offset {} length {} last key {}",
+            handle.offset,
+            handle.length,
+            show(last_key, args.hex)
+        )?;
+    }
+
+    let mut intact = true;
+    let mut records = 0;
+    writeln!(out, "  blocks:")?;
+    for i in 0..index.len() {
+        let (_, handle) = index.entry(i);
+        let block = match reader.block(handle, false) {
+            Ok(block) => block,
+            Err(e) => {
+                writeln!(out, "    {i}: BAD: {e}")?;
+                intact = false;
+                continue;
+            }
+        };
+        writeln!(out, "    {i}: {} record(s), crc ok", block.len())?;
+        records += block.len();
+        if args.dump {
+            for (key, seq, entry) in block.iter() {
+                let what = match entry {
+                    Entry::Put(value) => format!("put {}", show(value, args.hex)),
+                    Entry::Expiring { value, expires_at } => {
+                        format!("put {} expires {expires_at}", show(value, args.hex))
+                    }
+                    Entry::Delete => "delete".to_string(),
+                    Entry::Merge(operand) => format!("merge {}", show(operand, args.hex)),
+                };
+                writeln!(out, "      {} @{seq} {what}", show(key, args.hex))?;
+            }
+        }
+    }
+    writeln!(out, "  records: {records}")?;
+    Ok(intact)
+}
+
+fn read_footer(path: &Path) -> Option<Footer> {
+    let bytes = std::fs::read(path).ok()?;
+    let tail = bytes.get(bytes.len().checked_sub(FOOTER_SIZE)?..)?;
+    Some(Footer::decode(tail.try_into().ok()?))
+}
+
+fn print_footer(footer: &Footer, out: &mut dyn Write) -> std::io::Result<()> {
+    let magic = if footer.magic == SSTABLE_MAGIC {
+        "ok"
+    } else {
+        "BAD"
+    };
+    writeln!(
+        out,
+        "  footer: version {}, magic {:#018x} ({magic}), index at offset {} length {}",
+        footer.version, footer.magic, footer.index.offset, footer.index.length
+    )
+}
+
+fn show(bytes: &[u8], hex: bool) -> String {
+    if hex {
+        return hex::encode(bytes);
+    }
+    match std::str::from_utf8(bytes) {
+        Ok(s) => format!("{s:?}"),
+        Err(_) => format!("\"{}\"", bytes.escape_ascii()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zynk::storage::sstable::builder::SsTableBuilder;
+    use zynk::storage::sstable::TableOptions;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        parse_args(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn parses_flags_and_files() {
+        let args = parse(&["--hex", "a.sst", "--dump", "b.sst"]).unwrap();
+        assert!(args.dump && args.hex && !args.verify);
+        assert_eq!(args.files, [PathBuf::from("a.sst"), PathBuf::from("b.sst")]);
+        assert!(parse(&["--verify", "a.sst"]).unwrap().verify);
+
+        assert_eq!(parse(&["--dump"]).err().unwrap(), "no table given");
+        assert_eq!(
+            parse(&["--fix", "a.sst"]).err().unwrap(),
+            "unknown option --fix"
+        );
+        assert_eq!(parse(&["a.sst", "--help"]).err().unwrap(), "");
+    }
+
+    #[test]
+    fn a_flipped_block_byte_fails_verify_and_inspect() {
+        let path = std::env::temp_dir().join(format!("zynk-sst-bin-{}.sst", std::process::id()));
+        let opts = TableOptions {
+            block_bytes: 64,
+            ..TableOptions::default()
+        };
+        let mut builder = SsTableBuilder::with_options(&path, &opts);
+        for i in 0..50u64 {
+            builder.add_put(format!("k{i:02}").as_bytes(), i + 1, b"v");
+        }
+        builder.finish().unwrap();
+        let run_with = |flags: &[&str]| {
+            let mut argv = flags.to_vec();
+            argv.push(path.to_str().unwrap());
+            let mut out = Vec::new();
+            let code = run(&parse(&argv).unwrap(), &mut out);
+            (code, String::from_utf8(out).unwrap())
+        };
+
+        let (code, out) = run_with(&["--verify"]);
+        assert_eq!(code, ExitCode::SUCCESS);
+        assert!(out.contains(": ok, "), "{out}");
+
+        let second = SsTableReader::open(&path).unwrap().index().entry(1).1;
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[second.offset as usize + 1] ^= 0xff;
+        std::fs::write(&path, bytes).unwrap();
+
+        let (code, out) = run_with(&["--verify"]);
+        assert_eq!(code, ExitCode::FAILURE);
+        assert!(out.contains(": corrupt: block 1 at offset"), "{out}");
+
+        let (code, out) = run_with(&[]);
+        assert_eq!(code, ExitCode::FAILURE);
+        assert!(out.contains(" record(s), crc ok\n    1: BAD: "), "{out}");
+        assert!(!out.contains("\n    2: BAD"), "{out}");
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -1,29 +1,135 @@
 use std::fs;
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, RwLock};
 use tonic::{Request, Response, Status};
+use zynk::engine::batch::WriteBatch;
+use zynk::engine::crdt::ElementId;
 use zynk::engine::kv::LsmEngine;
+use zynk::engine::options::EngineOptions;
+use zynk::storage::wal::SyncPolicy;
 
 pub mod pb {
     tonic::include_proto!("kv");
 }
 
+use pb::batch_op::Kind;
 use pb::kv_server::{Kv, KvServer};
-use pb::{DelRequest, DelResponse, GetRequest, GetResponse, PutRequest, PutResponse};
+use pb::{
+    BatchWriteRequest, BatchWriteResponse, DelRequest, DelResponse, GetRequest, GetResponse,
+    PutRequest, PutResponse,
+};
+
+/// Upper bound on how many queued writes share one log sync.
+const MAX_GROUP: usize = 256;
+
+enum WriteOp {
+    Put {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    },
+    Del {
+        key: Vec<u8>,
+    },
+    Batch(WriteBatch),
+}
+
+struct PendingWrite {
+    op: WriteOp,
+    done: oneshot::Sender<Result<bool, Status>>,
+}
+
+/// Funnels writes through a single thread so that everything queued while a
+/// commit is in flight is applied together and shares one WAL sync. Commits
+/// block on fsync and on write stalls, so they stay off the runtime.
+#[derive(Clone)]
+struct GroupCommitter {
+    tx: mpsc::UnboundedSender<PendingWrite>,
+}
+
+impl GroupCommitter {
+    fn spawn(engine: Arc<RwLock<LsmEngine>>) -> Self {
+        let (tx, mut rx) = mpsc::unbounded_channel::<PendingWrite>();
+        std::thread::spawn(move || {
+            while let Some(first) = rx.blocking_recv() {
+                let mut group = vec![first];
+                while group.len() < MAX_GROUP {
+                    match rx.try_recv() {
+                        Ok(w) => group.push(w),
+                        Err(_) => break,
+                    }
+                }
+
+                let mut eng = engine.blocking_write();
+                let results = eng.write_group(|eng| {
+                    group
+                        .iter()
+                        .map(|w| match &w.op {
+                            WriteOp::Put { key, value, ttl } => match ttl {
+                                Some(ttl) => eng.put_with_ttl(key, value, *ttl),
+                                None => eng.put(key, value),
+                            },
+                            WriteOp::Del { key } => eng.delete(key),
+                            WriteOp::Batch(batch) => eng.write(batch),
+                        })
+                        .collect::<Vec<_>>()
+                });
+                // Reads go on while the log syncs.
+                let eng = eng.downgrade();
+                let ack = eng.sync_group();
+                drop(eng);
+
+                // Writes that went in are applied and visible even if the
+                // shared sync failed, so clients must not retry them; they
+                // are only reported as not durable. The engine refuses any
+                // write after a failed sync.
+                let synced = match ack {
+                    Ok(ack) => ack.synced,
+                    Err(e) => {
+                        eprintln!("zynkd: log sync failed: {e}");
+                        false
+                    }
+                };
+                for (w, r) in group.into_iter().zip(results) {
+                    let _ = w.done.send(r.map(|_| synced).map_err(to_status));
+                }
+            }
+        });
+        Self { tx }
+    }
+
+    /// Queues a write and waits for its group to commit; returns whether it was synced.
+    async fn submit(&self, op: WriteOp) -> Result<bool, Status> {
+        let (done, rx) = oneshot::channel();
+        self.tx
+            .send(PendingWrite { op, done })
+            .map_err(|_| Status::unavailable("write committer stopped"))?;
+        rx.await
+            .map_err(|_| Status::internal("write committer dropped request"))?
+    }
+}
 
 struct KvSvc {
     engine: Arc<RwLock<LsmEngine>>,
+    committer: GroupCommitter,
 }
 
 #[tonic::async_trait]
 impl Kv for KvSvc {
     async fn put(&self, request: Request<PutRequest>) -> Result<Response<PutResponse>, Status> {
         let req = request.into_inner();
-        let mut eng = self.engine.write().await;
-        eng.put(&req.key, &req.value).map_err(to_status)?;
-        Ok(Response::new(PutResponse {}))
+        let durable = self
+            .committer
+            .submit(WriteOp::Put {
+                key: req.key,
+                value: req.value,
+                ttl: (req.ttl_ms > 0).then(|| Duration::from_millis(req.ttl_ms)),
+            })
+            .await?;
+        Ok(Response::new(PutResponse { durable }))
     }
 
     async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
@@ -43,13 +149,47 @@ impl Kv for KvSvc {
 
     async fn del(&self, request: Request<DelRequest>) -> Result<Response<DelResponse>, Status> {
         let req = request.into_inner();
-        let mut eng = self.engine.write().await;
-        eng.delete(&req.key).map_err(to_status)?;
-        Ok(Response::new(DelResponse { removed: true }))
+        let durable = self.committer.submit(WriteOp::Del { key: req.key }).await?;
+        Ok(Response::new(DelResponse {
+            removed: true,
+            durable,
+        }))
+    }
+
+    async fn batch_write(
+        &self,
+        request: Request<BatchWriteRequest>,
+    ) -> Result<Response<BatchWriteResponse>, Status> {
+        let batch = to_write_batch(request.into_inner()).map_err(Status::invalid_argument)?;
+        let durable = self.committer.submit(WriteOp::Batch(batch)).await?;
+        Ok(Response::new(BatchWriteResponse { durable }))
+    }
+}
+
+fn to_write_batch(req: BatchWriteRequest) -> Result<WriteBatch, String> {
+    let elem_id = |id: Option<pb::ElementId>| id.map(|e| ElementId::new(e.actor, e.counter));
+    let mut batch = WriteBatch::new();
+    for op in req.ops {
+        let kind = Kind::try_from(op.kind).map_err(|_| format!("unknown op kind {}", op.kind))?;
+        match kind {
+            Kind::Put => batch.put(&op.key, &op.value),
+            Kind::Del => batch.delete(&op.key),
+            Kind::GsetAdd => batch.gset_add(&op.key, op.value),
+            Kind::RgaInsert => {
+                let id = elem_id(op.id).ok_or("RGA_INSERT requires id")?;
+                batch.rga_insert_after(&op.key, elem_id(op.prev), id, op.value)
+            }
+            Kind::RgaDelete => {
+                let id = elem_id(op.id).ok_or("RGA_DELETE requires id")?;
+                batch.rga_delete(&op.key, id)
+            }
+            Kind::Merge => batch.merge(&op.key, &op.value),
+        };
     }
+    Ok(batch)
 }
 
-fn get_or_create_actor_id(data_dir: &PathBuf) -> std::io::Result<u64> {
+fn get_or_create_actor_id(data_dir: &Path) -> std::io::Result<u64> {
     let id_path = data_dir.join("actor_id");
     if id_path.exists() {
         let s = fs::read_to_string(&id_path)?;
@@ -64,7 +204,10 @@ fn get_or_create_actor_id(data_dir: &PathBuf) -> std::io::Result<u64> {
 }
 
 fn to_status(e: std::io::Error) -> Status {
-    Status::internal(e.to_string())
+    match e.kind() {
+        std::io::ErrorKind::InvalidInput => Status::invalid_argument(e.to_string()),
+        _ => Status::internal(e.to_string()),
+    }
 }
 
 #[tokio::main]
@@ -82,20 +225,99 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // derive actor id for this node:
     let actor_id = get_or_create_actor_id(&data_dir)?;
 
-    let engine = LsmEngine::new_with_manifest_and_actor(&data_dir, 64 * 1024, 8 * 1024, actor_id)?;
+    let sync_policy: SyncPolicy = match std::env::var("WAL_SYNC") {
+        Ok(s) => s.parse()?,
+        Err(_) => SyncPolicy::Always,
+    };
+    let mut opts = EngineOptions {
+        sync_policy,
+        ..EngineOptions::default()
+    };
+    if let Ok(s) = std::env::var("COMPACTION_STYLE") {
+        opts.compaction.style = s.parse()?;
+    }
+    if let Ok(s) = std::env::var("COMPRESSION") {
+        opts.compression = s.parse()?;
+    }
+    opts.quarantine_orphans = std::env::var("QUARANTINE_ORPHANS").is_ok_and(|s| s == "1");
+
+    if std::env::var("REPAIR").is_ok_and(|s| s == "1") {
+        let report = LsmEngine::repair(&data_dir, &opts)?;
+        for table in &report.quarantined {
+            println!("repair: quarantined {table}");
+        }
+        for id in &report.maybe_stale {
+            println!("repair: table {id} looks like a leftover compaction input; keys it deleted may be back");
+        }
+        println!(
+            "repair: kept {} table(s){}",
+            report.tables.len(),
+            if report.manifest_lost {
+                ", manifest rebuilt from sst/"
+            } else {
+                ""
+            }
+        );
+    }
+    let engine = LsmEngine::open_with_actor(&data_dir, opts, actor_id)?;
+    let cleanup = engine.startup_cleanup();
+    if !cleanup.is_empty() {
+        let action = match &cleanup.quarantine {
+            Some(dir) => format!("moved to {}", dir.display()),
+            None => "deleted".to_string(),
+        };
+        println!(
+            "startup cleanup: {} temp file(s) and {} unreferenced table(s) {action}",
+            cleanup.temp_files.len(),
+            cleanup.orphaned_tables.len(),
+        );
+    }
+    let compaction = engine.compaction_strategy();
+    let engine = Arc::new(RwLock::new(engine));
+    let committer = GroupCommitter::spawn(engine.clone());
+
+    // A time-based policy still needs a nudge when writes stop arriving.
+    if let SyncPolicy::EveryMillis(ms) = sync_policy {
+        let engine = engine.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(Duration::from_millis(ms.max(1)));
+            if let Err(e) = engine.blocking_read().sync_wal() {
+                eprintln!("wal sync failed: {e}");
+            }
+        });
+    }
+
     let svc = KvSvc {
-        engine: Arc::new(RwLock::new(engine)),
+        engine: engine.clone(),
+        committer,
     };
 
     println!(
-        "zynkd listening on {} (ACTOR_ID={}, DATA_DIR={})",
+        "zynkd {} listening on {} (ACTOR_ID={}, DATA_DIR={}, WAL_SYNC={}, compaction={})",
+        node_id,
         addr,
         actor_id,
-        data_dir.display()
+        data_dir.display(),
+        sync_policy,
+        compaction
     );
     tonic::transport::Server::builder()
         .add_service(KvServer::new(svc))
-        .serve(addr)
+        .serve_with_shutdown(addr, shutdown_signal())
         .await?;
+
+    // Let the background workers flush every frozen memtable before exiting.
+    engine.write().await.close()?;
     Ok(())
 }
+
+/// Resolves on Ctrl-C or SIGTERM (what `docker stop` sends).
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut term = signal(SignalKind::terminate()).expect("install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = term.recv() => {}
+    }
+}
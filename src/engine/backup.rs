@@ -0,0 +1,427 @@
+use crate::engine::kv::LsmEngine;
+use crate::storage::env::Env;
+use crate::storage::manifest::{fsync_dir, Manifest, ManifestState, TableRecord};
+use crate::storage::memtable::{now_millis, SeqNo};
+use crate::storage::sstable::filter::bloom_hash;
+use std::collections::HashSet;
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const CATALOG_HEADER: &str = "zynk-backup 1";
+
+/// Incremental backups of an engine, kept in a directory of their own.
+///
+/// Tables never change once written, so a backup copies only the tables the
+/// directory doesn't hold yet. `tables/` is shared by every backup and names
+/// each table by its id, size and checksum, since an engine restored from an
+/// older backup reuses ids that later backups already hold. Each backup is a
+/// catalog file under `catalog/` listing its tables, their checksums and the
+/// manifest state to restore them under.
+///
+/// The checksum is a 64-bit FNV-1a hash rather than a CRC32: every block of
+/// a table ends in a CRC32 of its own, which cancels out of a CRC32 of the
+/// whole file, so tables of the same layout would all share one.
+#[derive(Debug)]
+pub struct BackupEngine {
+    env: Arc<dyn Env>,
+    dir: PathBuf,
+}
+
+/// A backup as its catalog records it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupInfo {
+    pub id: u64,
+    /// Milliseconds since the Unix epoch.
+    pub created_at: u64,
+    pub last_sequence: SeqNo,
+    pub tables: usize,
+    /// Size of every table the backup needs.
+    pub total_bytes: u64,
+    /// Size of the tables this backup copied; the rest were already there.
+    pub copied_bytes: u64,
+}
+
+/// Everything a catalog file holds.
+struct Catalog {
+    info: BackupInfo,
+    state: ManifestState,
+    /// [`table_hash`] of each table of `state.tables`, in the same order.
+    checksums: Vec<u64>,
+}
+
+impl BackupEngine {
+    /// Opens the backups under `dir`, creating the directory if needed.
+    pub fn open<P: AsRef<Path>>(dir: P, env: Arc<dyn Env>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        env.create_dir_all(&dir.join("tables"))?;
+        env.create_dir_all(&dir.join("catalog"))?;
+        Ok(Self { env, dir })
+    }
+
+    /// Backs up `engine` as it is now, copying only the tables no earlier
+    /// backup has.
+    pub fn create_backup(&self, engine: &mut LsmEngine) -> Result<BackupInfo> {
+        let live = engine.live_files()?;
+        let source = engine.options().env.clone();
+        let catalogs = self.catalogs()?;
+
+        let mut checksums = Vec::new();
+        let mut copied_bytes = 0;
+        for (table, path) in live.state.tables.iter().zip(&live.paths) {
+            let data = source.read(path)?;
+            let hash = table_hash(&data);
+            let dest = self.table_path(table, hash);
+            // Tables are only ever renamed into place once complete, so one
+            // that a crashed backup copied but never cataloged is reused too.
+            if !self.env.exists(&dest) {
+                let tmp = dest.with_extension("tmp");
+                write_file(self.env.as_ref(), &tmp, &data)?;
+                self.env.rename(&tmp, &dest)?;
+                copied_bytes += data.len() as u64;
+            }
+            checksums.push(hash);
+        }
+        self.env.sync_dir(&self.dir.join("tables"))?;
+
+        let catalog = Catalog {
+            info: BackupInfo {
+                id: catalogs.last().map_or(1, |c| c.info.id + 1),
+                created_at: now_millis(),
+                last_sequence: live.state.last_sequence,
+                tables: live.state.tables.len(),
+                total_bytes: live.state.tables.iter().map(|t| t.file_size).sum(),
+                copied_bytes,
+            },
+            state: live.state.clone(),
+            checksums,
+        };
+        let path = self.catalog_path(catalog.info.id);
+        let tmp = path.with_extension("tmp");
+        write_file(self.env.as_ref(), &tmp, &catalog.encode())?;
+        self.env.rename(&tmp, &path)?;
+        fsync_dir(self.env.as_ref(), &path)?;
+        Ok(catalog.info)
+    }
+
+    /// Every backup, oldest first.
+    pub fn backups(&self) -> Result<Vec<BackupInfo>> {
+        Ok(self.catalogs()?.into_iter().map(|c| c.info).collect())
+    }
+
+    /// Deletes all but the newest `keep` backups, and the tables only they
+    /// used. Returns the ids of the deleted backups.
+    pub fn purge_old_backups(&self, keep: usize) -> Result<Vec<u64>> {
+        let catalogs = self.catalogs()?;
+        let (old, kept) = catalogs.split_at(catalogs.len().saturating_sub(keep));
+        for catalog in old {
+            self.env.remove_file(&self.catalog_path(catalog.info.id))?;
+        }
+        self.env.sync_dir(&self.dir.join("catalog"))?;
+
+        let live: HashSet<PathBuf> = kept
+            .iter()
+            .flat_map(|c| {
+                let tables = c.state.tables.iter().zip(&c.checksums);
+                tables.map(|(t, &hash)| self.table_path(t, hash))
+            })
+            .collect();
+        let tables_dir = self.dir.join("tables");
+        for name in self.env.list_dir(&tables_dir)? {
+            let path = tables_dir.join(name);
+            if !live.contains(&path) {
+                self.env.remove_file(&path)?;
+            }
+        }
+        self.env.sync_dir(&tables_dir)?;
+        Ok(old.iter().map(|c| c.info.id).collect())
+    }
+
+    /// Writes backup `id` out as a data directory at `dest_dir`, which must
+    /// not exist yet, checking every table against its checksum.
+    ///
+    /// The manifest is written last, so a restore that fails part way leaves
+    /// a directory without one; remove it before trying again. It rotates at
+    /// `max_manifest_bytes`, as the engine that opens the copy would.
+    pub fn restore<P: AsRef<Path>>(
+        &self,
+        id: u64,
+        dest_dir: P,
+        max_manifest_bytes: u64,
+    ) -> Result<()> {
+        let dest = dest_dir.as_ref();
+        let catalog = self.catalog(id)?;
+        if self.env.exists(dest) {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{} already exists", dest.display()),
+            ));
+        }
+        let sst_dir = dest.join("sst");
+        self.env.create_dir_all(&sst_dir)?;
+        self.env.create_dir_all(&dest.join("wal"))?;
+        for (table, &hash) in catalog.state.tables.iter().zip(&catalog.checksums) {
+            let data = self.env.read(&self.table_path(table, hash))?;
+            if data.len() as u64 != table.file_size || table_hash(&data) != hash {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("backup {id}: table {} fails its checksum", table.id),
+                ));
+            }
+            let to = sst_dir.join(format!("{:06}.sst", table.id));
+            write_file(self.env.as_ref(), &to, &data)?;
+        }
+        self.env.sync_dir(&sst_dir)?;
+        Manifest::create(&self.env, dest, 1, catalog.state, max_manifest_bytes)?;
+        fsync_dir(self.env.as_ref(), dest)
+    }
+
+    fn table_path(&self, table: &TableRecord, hash: u64) -> PathBuf {
+        let name = format!("{:06}-{}-{hash:016x}.sst", table.id, table.file_size);
+        self.dir.join("tables").join(name)
+    }
+
+    fn catalog_path(&self, id: u64) -> PathBuf {
+        self.dir.join("catalog").join(format!("{id:06}"))
+    }
+
+    fn catalog(&self, id: u64) -> Result<Catalog> {
+        let bytes = self.env.read(&self.catalog_path(id))?;
+        Catalog::decode(id, &bytes)
+    }
+
+    /// Every catalog, oldest first.
+    fn catalogs(&self) -> Result<Vec<Catalog>> {
+        let mut ids: Vec<u64> = self
+            .env
+            .list_dir(&self.dir.join("catalog"))?
+            .iter()
+            .filter_map(|name| name.parse().ok())
+            .collect();
+        ids.sort_unstable();
+        ids.into_iter().map(|id| self.catalog(id)).collect()
+    }
+}
+
+impl Catalog {
+    /// One line per field and per table, keys in hex, then a CRC32 of
+    /// everything before it.
+    fn encode(&self) -> Vec<u8> {
+        let s = &self.state;
+        let mut text = format!(
+            "{CATALOG_HEADER}\ncreated {}\ncopied_bytes {}\nlog_number {}\nnext_table_id {}\nlast_sequence {}\n",
+            self.info.created_at, self.info.copied_bytes, s.log_number, s.next_table_id, s.last_sequence,
+        );
+        for (t, hash) in s.tables.iter().zip(&self.checksums) {
+            text.push_str(&format!(
+                "table {} {} {} {hash:016x} {} {}\n",
+                t.id,
+                t.level,
+                t.file_size,
+                hex::encode(&t.smallest),
+                hex::encode(&t.largest),
+            ));
+        }
+        let crc = crc32fast::hash(text.as_bytes());
+        text.push_str(&format!("crc {crc:08x}\n"));
+        text.into_bytes()
+    }
+
+    fn decode(id: u64, bytes: &[u8]) -> Result<Self> {
+        let corrupt = || Error::new(ErrorKind::InvalidData, format!("backup {id}: bad catalog"));
+        let text = std::str::from_utf8(bytes).map_err(|_| corrupt())?;
+        let (body, crc) = text.rsplit_once("crc ").ok_or_else(corrupt)?;
+        let crc = u32::from_str_radix(crc.trim_end(), 16).map_err(|_| corrupt())?;
+        if crc32fast::hash(body.as_bytes()) != crc {
+            return Err(corrupt());
+        }
+        let mut lines = body.lines();
+        if lines.next() != Some(CATALOG_HEADER) {
+            return Err(corrupt());
+        }
+        let num = |s: &str| s.parse::<u64>().map_err(|_| corrupt());
+        let mut info = BackupInfo {
+            id,
+            created_at: 0,
+            last_sequence: 0,
+            tables: 0,
+            total_bytes: 0,
+            copied_bytes: 0,
+        };
+        let mut state = ManifestState::default();
+        let mut checksums = Vec::new();
+        for line in lines {
+            let fields: Vec<&str> = line.split(' ').collect();
+            match fields.as_slice() {
+                ["created", n] => info.created_at = num(n)?,
+                ["copied_bytes", n] => info.copied_bytes = num(n)?,
+                ["log_number", n] => state.log_number = num(n)?,
+                ["next_table_id", n] => state.next_table_id = num(n)?,
+                ["last_sequence", n] => state.last_sequence = num(n)?,
+                ["table", id, level, size, hash, smallest, largest] => {
+                    state.tables.push(TableRecord {
+                        id: num(id)?,
+                        level: num(level)? as usize,
+                        file_size: num(size)?,
+                        smallest: hex::decode(smallest).map_err(|_| corrupt())?,
+                        largest: hex::decode(largest).map_err(|_| corrupt())?,
+                    });
+                    checksums.push(u64::from_str_radix(hash, 16).map_err(|_| corrupt())?);
+                }
+                _ => return Err(corrupt()),
+            }
+        }
+        info.last_sequence = state.last_sequence;
+        info.tables = state.tables.len();
+        info.total_bytes = state.tables.iter().map(|t| t.file_size).sum();
+        Ok(Self {
+            info,
+            state,
+            checksums,
+        })
+    }
+}
+
+/// A 64-bit FNV-1a hash of a whole table file.
+fn table_hash(data: &[u8]) -> u64 {
+    bloom_hash(data)
+}
+
+/// Writes `data` to a new file at `path` and syncs it.
+fn write_file(env: &dyn Env, path: &Path, data: &[u8]) -> Result<()> {
+    let mut file = env.create(path)?;
+    file.write_all(data)?;
+    file.sync()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::env::MemEnv;
+    use crate::test_util::options;
+
+    const MANIFEST_BYTES: u64 = 4096;
+
+    fn write(eng: &mut LsmEngine, prefix: &str, value: &[u8]) {
+        for i in 0..20 {
+            eng.put(format!("{prefix}{i:02}").as_bytes(), value)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn backups_copy_only_new_tables_and_restore_each_generation() {
+        let env = Arc::new(MemEnv::new());
+        let backups = BackupEngine::open("/backup", env.clone()).unwrap();
+        let mut eng = LsmEngine::open("/db", options(&env, 100)).unwrap();
+        write(&mut eng, "a", b"1");
+        let first = backups.create_backup(&mut eng).unwrap();
+        assert_eq!(first.copied_bytes, first.total_bytes);
+
+        write(&mut eng, "b", b"2");
+        eng.delete(b"a00").unwrap();
+        let second = backups.create_backup(&mut eng).unwrap();
+        assert_eq!(second.tables, first.tables + 1);
+        assert_eq!(second.copied_bytes, second.total_bytes - first.total_bytes);
+        assert_eq!(backups.backups().unwrap(), [first.clone(), second.clone()]);
+
+        backups.restore(first.id, "/r1", MANIFEST_BYTES).unwrap();
+        backups.restore(second.id, "/r2", MANIFEST_BYTES).unwrap();
+        assert!(backups.restore(second.id, "/r2", MANIFEST_BYTES).is_err());
+        let r1 = LsmEngine::open("/r1", options(&env, 100)).unwrap();
+        assert_eq!(r1.get(b"a00").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(r1.get(b"b00").unwrap(), None);
+        assert_eq!(r1.last_sequence(), first.last_sequence);
+        let mut r2 = LsmEngine::open("/r2", options(&env, 100)).unwrap();
+        assert_eq!(r2.get(b"a00").unwrap(), None);
+        assert_eq!(r2.get(b"b19").unwrap(), Some(b"2".to_vec()));
+        // A restored engine carries on numbering its tables past the backup's.
+        r2.put(b"c", b"3").unwrap();
+        r2.flush().unwrap();
+        let third = backups.create_backup(&mut r2).unwrap();
+        assert_eq!(third.tables, second.tables + 1);
+    }
+
+    #[test]
+    fn an_engine_restored_from_an_older_backup_backs_up_its_own_tables() {
+        let env = Arc::new(MemEnv::new());
+        let backups = BackupEngine::open("/backup", env.clone()).unwrap();
+        let mut eng = LsmEngine::open("/db", options(&env, 100)).unwrap();
+        write(&mut eng, "a", b"1");
+        let first = backups.create_backup(&mut eng).unwrap();
+        // Reopened, like the restored engine below, so both number their
+        // next table alike.
+        drop(eng);
+        let mut eng = LsmEngine::open("/db", options(&env, 100)).unwrap();
+        write(&mut eng, "b", b"2");
+        let second = backups.create_backup(&mut eng).unwrap();
+
+        // The restored engine writes a table of the same id and size as the
+        // one the second backup took, but with other contents.
+        backups.restore(first.id, "/r1", MANIFEST_BYTES).unwrap();
+        let mut r1 = LsmEngine::open("/r1", options(&env, 100)).unwrap();
+        write(&mut r1, "b", b"9");
+        let third = backups.create_backup(&mut r1).unwrap();
+        let ids = |id| {
+            let catalog = backups.catalog(id).unwrap();
+            let tables = catalog.state.tables.into_iter();
+            tables.map(|t| (t.id, t.file_size)).collect::<Vec<_>>()
+        };
+        assert_eq!(ids(second.id), ids(third.id));
+        assert_eq!(third.copied_bytes, third.total_bytes - first.total_bytes);
+
+        backups.restore(second.id, "/r2", MANIFEST_BYTES).unwrap();
+        backups.restore(third.id, "/r3", MANIFEST_BYTES).unwrap();
+        let r2 = LsmEngine::open("/r2", options(&env, 100)).unwrap();
+        assert_eq!(r2.get(b"b07").unwrap(), Some(b"2".to_vec()));
+        let r3 = LsmEngine::open("/r3", options(&env, 100)).unwrap();
+        assert_eq!(r3.get(b"b07").unwrap(), Some(b"9".to_vec()));
+    }
+
+    #[test]
+    fn purge_drops_old_backups_and_the_tables_only_they_used() {
+        let env = Arc::new(MemEnv::new());
+        let backups = BackupEngine::open("/backup", env.clone()).unwrap();
+        let mut eng = LsmEngine::open("/db", options(&env, 2)).unwrap();
+        for value in [b"1", b"2", b"3"] {
+            write(&mut eng, "a", value);
+            eng.flush().unwrap();
+            eng.wait_for_background().unwrap();
+            backups.create_backup(&mut eng).unwrap();
+        }
+        let mut tables = env.list_dir(Path::new("/backup/tables")).unwrap();
+        let before = tables.len();
+
+        assert_eq!(backups.purge_old_backups(1).unwrap(), [1, 2]);
+        let ids: Vec<_> = backups.backups().unwrap().iter().map(|b| b.id).collect();
+        assert_eq!(ids, [3]);
+        tables = env.list_dir(Path::new("/backup/tables")).unwrap();
+        assert!(tables.len() < before);
+        assert_eq!(tables.len(), eng.tables().len());
+        assert!(backups.restore(1, "/r1", MANIFEST_BYTES).is_err());
+        backups.restore(3, "/r3", MANIFEST_BYTES).unwrap();
+        let restored = LsmEngine::open("/r3", options(&env, 2)).unwrap();
+        assert_eq!(restored.get(b"a07").unwrap(), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn restore_rejects_a_damaged_table() {
+        let env = Arc::new(MemEnv::new());
+        let backups = BackupEngine::open("/backup", env.clone()).unwrap();
+        let mut eng = LsmEngine::open("/db", options(&env, 100)).unwrap();
+        write(&mut eng, "a", b"1");
+        let info = backups.create_backup(&mut eng).unwrap();
+
+        let name = env.list_dir(Path::new("/backup/tables")).unwrap().remove(0);
+        let path = Path::new("/backup/tables").join(name);
+        let mut data = env.read(&path).unwrap();
+        data[0] ^= 0xff;
+        write_file(env.as_ref(), &path, &data).unwrap();
+
+        let e = backups
+            .restore(info.id, "/restored", MANIFEST_BYTES)
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert!(!env.exists(Path::new("/restored/CURRENT")));
+    }
+}
@@ -0,0 +1,116 @@
+use crate::engine::crdt::ElementId;
+
+/// A single mutation inside a [`WriteBatch`].
+#[derive(Clone, Debug)]
+pub enum BatchOp {
+    Put {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Delete {
+        key: Vec<u8>,
+    },
+    Merge {
+        key: Vec<u8>,
+        operand: Vec<u8>,
+    },
+    GSetAdd {
+        key: Vec<u8>,
+        elem: Vec<u8>,
+    },
+    RgaInsert {
+        key: Vec<u8>,
+        prev: Option<ElementId>,
+        id: ElementId,
+        value: Vec<u8>,
+    },
+    RgaDelete {
+        key: Vec<u8>,
+        id: ElementId,
+    },
+}
+
+/// A group of mutations applied atomically by `LsmEngine::write`.
+///
+/// Ops are applied in insertion order, so a later op on the same key sees the
+/// effect of an earlier one. The whole batch lands in one log record and one
+/// memtable: after a crash either every op is visible or none is.
+#[derive(Clone, Debug, Default)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self { ops: Vec::new() }
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> &mut Self {
+        self.ops.push(BatchOp::Put {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        });
+        self
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> &mut Self {
+        self.ops.push(BatchOp::Delete { key: key.to_vec() });
+        self
+    }
+
+    pub fn merge(&mut self, key: &[u8], operand: &[u8]) -> &mut Self {
+        self.ops.push(BatchOp::Merge {
+            key: key.to_vec(),
+            operand: operand.to_vec(),
+        });
+        self
+    }
+
+    pub fn gset_add(&mut self, key: &[u8], elem: Vec<u8>) -> &mut Self {
+        self.ops.push(BatchOp::GSetAdd {
+            key: key.to_vec(),
+            elem,
+        });
+        self
+    }
+
+    pub fn rga_insert_after(
+        &mut self,
+        key: &[u8],
+        prev: Option<ElementId>,
+        id: ElementId,
+        value: Vec<u8>,
+    ) -> &mut Self {
+        self.ops.push(BatchOp::RgaInsert {
+            key: key.to_vec(),
+            prev,
+            id,
+            value,
+        });
+        self
+    }
+
+    pub fn rga_delete(&mut self, key: &[u8], id: ElementId) -> &mut Self {
+        self.ops.push(BatchOp::RgaDelete {
+            key: key.to_vec(),
+            id,
+        });
+        self
+    }
+
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.ops.clear();
+    }
+}
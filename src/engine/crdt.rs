@@ -1,9 +1,118 @@
+use std::io::{Error, ErrorKind};
+
 pub trait CRDT: Sized {
+    const KIND: CrdtKind;
+
     fn to_bytes(&self) -> Vec<u8>;
     fn from_bytes(bytes: &[u8]) -> Self;
+    /// Like `from_bytes`, but fails on anything `to_bytes` couldn't have
+    /// written rather than salvaging what it can.
+    fn decode(bytes: &[u8]) -> std::io::Result<Self>;
     fn merge(&mut self, other: &Self);
 }
 
+/// The engine keeps the CRDT state of a key at the key with this prefix in
+/// front, apart from its plain values. Plain reads and writes refuse keys
+/// that start with it, and scans skip them.
+pub const CRDT_KEY_PREFIX: &[u8] = b"\xff\xffcrdt\x00";
+
+/// The first key past every key starting with [`CRDT_KEY_PREFIX`].
+pub(crate) const CRDT_KEYS_END: &[u8] = b"\xff\xffcrdt\x01";
+
+/// Where the engine keeps the CRDT state of `key`.
+pub(crate) fn crdt_key(key: &[u8]) -> Vec<u8> {
+    [CRDT_KEY_PREFIX, key].concat()
+}
+
+/// Which CRDT a key holds. The engine stores CRDT operands and the states
+/// they merge into with this tag in front, so that reads, flushes and
+/// compactions merge all versions of the key with [`CRDT::merge`] instead of
+/// keeping the newest, and never mix up two kinds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrdtKind {
+    GSet,
+    Rga,
+}
+
+impl CrdtKind {
+    fn tag(self) -> u8 {
+        match self {
+            CrdtKind::GSet => 1,
+            CrdtKind::Rga => 2,
+        }
+    }
+
+    /// `state` tagged with its kind.
+    pub fn encode<C: CRDT>(state: &C) -> Vec<u8> {
+        C::KIND.tag_state(&state.to_bytes())
+    }
+
+    /// The encoded `state` of this kind, tagged.
+    pub fn tag_state(self, state: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(state.len() + 1);
+        out.push(self.tag());
+        out.extend_from_slice(state);
+        out
+    }
+
+    /// The kind and encoded state of tagged `bytes`.
+    pub fn split(bytes: &[u8]) -> std::io::Result<(CrdtKind, &[u8])> {
+        match bytes.split_first() {
+            Some((1, state)) => Ok((CrdtKind::GSet, state)),
+            Some((2, state)) => Ok((CrdtKind::Rga, state)),
+            Some((tag, _)) => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown CRDT tag {tag}"),
+            )),
+            None => Err(Error::new(ErrorKind::InvalidData, "empty CRDT state")),
+        }
+    }
+
+    /// Fails unless this is `other`.
+    pub fn expect(self, other: CrdtKind) -> std::io::Result<()> {
+        if self != other {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("{self:?} state merged with {other:?} state"),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Fails unless `state` is a well-formed encoding of this kind.
+    pub fn check(self, state: &[u8]) -> std::io::Result<()> {
+        match self {
+            CrdtKind::GSet => GSet::decode(state).map(drop),
+            CrdtKind::Rga => Rga::decode(state).map(drop),
+        }
+    }
+
+    /// Merges the encoded `other` into the encoded `state`, both of this
+    /// kind; a missing state is an empty one.
+    pub fn merge_encoded(self, state: Option<&[u8]>, other: &[u8]) -> std::io::Result<Vec<u8>> {
+        fn merge<C: CRDT + Default>(
+            state: Option<&[u8]>,
+            other: &[u8],
+        ) -> std::io::Result<Vec<u8>> {
+            let mut merged = match state {
+                Some(state) => C::decode(state)?,
+                None => C::default(),
+            };
+            merged.merge(&C::decode(other)?);
+            Ok(merged.to_bytes())
+        }
+        match self {
+            CrdtKind::GSet => merge::<GSet>(state, other),
+            CrdtKind::Rga => merge::<Rga>(state, other),
+        }
+    }
+}
+
+/// The error for a state `decode` rejects.
+fn malformed(what: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("malformed {what} state"))
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct GSet {
     elems: Vec<Vec<u8>>,
@@ -35,6 +144,10 @@ impl GSet {
         self.elems.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.elems.is_empty()
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = &Vec<u8>> {
         self.elems.iter()
     }
@@ -45,6 +158,8 @@ impl GSet {
 }
 
 impl CRDT for GSet {
+    const KIND: CrdtKind = CrdtKind::GSet;
+
     fn to_bytes(&self) -> Vec<u8> {
         let mut out = Vec::new();
         out.extend(&(self.elems.len() as u32).to_be_bytes());
@@ -63,7 +178,8 @@ impl CRDT for GSet {
         }
         let cnt = u32::from_be_bytes(bytes[i..i + 4].try_into().unwrap()) as usize;
         i += 4;
-        let mut elems = Vec::with_capacity(cnt);
+        // Every element takes at least its length prefix.
+        let mut elems = Vec::with_capacity(cnt.min((bytes.len() - i) / 4));
         for _ in 0..cnt {
             if i + 4 > bytes.len() {
                 break;
@@ -81,6 +197,34 @@ impl CRDT for GSet {
         GSet { elems }
     }
 
+    fn decode(bytes: &[u8]) -> std::io::Result<Self> {
+        let (cnt, mut rest) = bytes
+            .split_first_chunk::<4>()
+            .ok_or_else(|| malformed("G-Set"))?;
+        let cnt = u32::from_be_bytes(*cnt) as usize;
+        if cnt > rest.len() / 4 {
+            return Err(malformed("G-Set"));
+        }
+        let mut elems = Vec::with_capacity(cnt);
+        for _ in 0..cnt {
+            let (len, tail) = rest
+                .split_first_chunk::<4>()
+                .ok_or_else(|| malformed("G-Set"))?;
+            let len = u32::from_be_bytes(*len) as usize;
+            if len > tail.len() {
+                return Err(malformed("G-Set"));
+            }
+            elems.push(tail[..len].to_vec());
+            rest = &tail[len..];
+        }
+        if !rest.is_empty() {
+            return Err(malformed("G-Set"));
+        }
+        elems.sort();
+        elems.dedup();
+        Ok(GSet { elems })
+    }
+
     fn merge(&mut self, other: &Self) {
         let mut out = Vec::with_capacity(self.elems.len() + other.elems.len());
         let a = &self.elems[..];
@@ -201,6 +345,31 @@ mod tests {
         assert_eq!(local.elements(), expected);
     }
 
+    #[test]
+    fn decode_rejects_what_to_bytes_cannot_write() {
+        let mut set = GSet::new();
+        set.insert(b("a"));
+        let bytes = set.to_bytes();
+        assert_eq!(GSet::decode(&bytes).unwrap(), set);
+        assert!(GSet::decode(&bytes[..bytes.len() - 1]).is_err());
+        assert!(GSet::decode(&[bytes.as_slice(), b"!"].concat()).is_err());
+        // The count alone must not make either decoder reserve memory.
+        let huge = [0xff, 0xff, 0xff, 0xff];
+        assert!(GSet::decode(&huge).is_err());
+        assert!(GSet::from_bytes(&huge).is_empty());
+        assert!(Rga::decode(&huge).is_err());
+
+        let mut rga = Rga::new();
+        rga.insert(ElementId::new(1, 1), None, b("x"));
+        let bytes = rga.to_bytes();
+        assert!(Rga::decode(&bytes).is_ok());
+        assert!(Rga::decode(&bytes[..bytes.len() - 1]).is_err());
+        assert!(CrdtKind::split(&[7]).is_err());
+        assert!(CrdtKind::GSet
+            .merge_encoded(Some(&bytes), &set.to_bytes())
+            .is_err());
+    }
+
     //malformed input: unsorted elements and duplicates
     #[test]
     fn merge_handles_unsorted_and_duplicates_in_bytes() {
@@ -245,7 +414,7 @@ impl ElementId {
         Self { actor, counter }
     }
 
-    fn to_bytes(&self, out: &mut Vec<u8>) {
+    fn to_bytes(self, out: &mut Vec<u8>) {
         out.extend(&self.actor.to_be_bytes());
         out.extend(&self.counter.to_be_bytes());
     }
@@ -458,12 +627,30 @@ impl Rga {
 }
 
 impl CRDT for Rga {
+    const KIND: CrdtKind = CrdtKind::Rga;
+
     fn to_bytes(&self) -> Vec<u8> {
         self.to_bytes()
     }
     fn from_bytes(bytes: &[u8]) -> Self {
         Rga::from_bytes(bytes)
     }
+    fn decode(bytes: &[u8]) -> std::io::Result<Self> {
+        let (count, mut rest) = bytes
+            .split_first_chunk::<4>()
+            .ok_or_else(|| malformed("RGA"))?;
+        let count = u32::from_be_bytes(*count) as usize;
+        let mut elems = BTreeMap::new();
+        for _ in 0..count {
+            let (elem, n) = Element::from_bytes(rest).ok_or_else(|| malformed("RGA"))?;
+            elems.insert(elem.id, elem);
+            rest = &rest[n..];
+        }
+        if !rest.is_empty() {
+            return Err(malformed("RGA"));
+        }
+        Ok(Rga { elems })
+    }
     fn merge(&mut self, other: &Self) {
         self.merge(other)
     }
@@ -1,20 +1,139 @@
-use crate::engine::crdt::{ElementId, Rga};
-use crate::storage::manifest::{fsync_dir, open_manifest_append, read_current_or_init, Manifest};
-use crate::storage::memtable::{flush_memtable_to_sstable, Entry, MemTable, MemTableSet};
-use crate::storage::sstable::{reader::SsTableReader, TableId};
-use std::fs;
+use crate::engine::batch::{BatchOp, WriteBatch};
+use crate::engine::crdt::{
+    crdt_key, CrdtKind, ElementId, GSet, Rga, CRDT, CRDT_KEYS_END, CRDT_KEY_PREFIX,
+};
+use crate::engine::merge::EngineMerge;
+use crate::engine::options::EngineOptions;
+use crate::engine::repair::CorruptTable;
+use crate::engine::scan::{is_empty_range, Scan};
+use crate::storage::compaction::{
+    new_strategy, run_compaction, CompactionStrategy, CompactionTask, VersionGc,
+};
+use crate::storage::env::Env;
+use crate::storage::iter::{EntryIter, MergingIter};
+use crate::storage::manifest::{fsync_dir, Manifest, ManifestState, TableRecord, VersionEdit};
+use crate::storage::memtable::{
+    flush_memtable_to_sstable, now_millis, Entry, MemTable, MemTableIter, MemTableSet, SeqNo,
+};
+use crate::storage::merge::MergeOperator;
+use crate::storage::sstable::cache::BlockCache;
+use crate::storage::sstable::filter::FilterStats;
+use crate::storage::sstable::reader::ReaderContext;
+use crate::storage::sstable::table_cache::TableCache;
+use crate::storage::sstable::{iter::SsTableIter, TableId};
+use crate::storage::version::{TableMeta, Version};
+use crate::storage::wal::{parse_wal_name, replay_wal, SyncPolicy, Wal};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::io::Write;
+use std::ops::{Bound, RangeBounds};
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::Duration;
 
+/// Acknowledgement for a write, telling the caller whether it is already on
+/// stable storage or only in the OS page cache under the current sync policy.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct WriteAck {
+    pub synced: bool,
+}
+
+/// Where a live table sits and the keys it spans, as listed by
+/// [`LsmEngine::tables`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TableInfo {
+    pub id: TableId,
+    pub level: usize,
+    pub smallest: Vec<u8>,
+    pub largest: Vec<u8>,
+    pub file_size: u64,
+}
+
+/// An engine's tables as of one point in time, from
+/// [`LsmEngine::live_files`].
+pub struct LiveFiles {
+    /// Lists exactly these tables; a manifest of it opens as a copy of the
+    /// engine.
+    pub state: ManifestState,
+    /// Where each table of `state.tables` is, in the same order.
+    pub paths: Vec<PathBuf>,
+    /// Keeps the tables from being deleted.
+    _version: Arc<Version>,
+}
+
+/// Files in `sst/` that the manifest did not account for when the engine
+/// was opened, left behind by a crash mid-flush or mid-compaction.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StartupCleanup {
+    /// Tables that were still being written.
+    pub temp_files: Vec<PathBuf>,
+    /// Finished tables no version refers to.
+    pub orphaned_tables: Vec<PathBuf>,
+    /// Where the files were moved, if they were quarantined rather than deleted.
+    pub quarantine: Option<PathBuf>,
+}
+
+impl StartupCleanup {
+    pub fn is_empty(&self) -> bool {
+        self.temp_files.is_empty() && self.orphaned_tables.is_empty()
+    }
+}
+
+/// Frozen memtables are flushed, and compactions run, on background threads.
+/// Writers only append to the log and the active memtable; once too many
+/// memtables are waiting to be flushed they stall until the flusher catches up.
 pub struct LsmEngine {
-    data_dir: PathBuf,
-    memtables: MemTableSet,
-    sstables: Vec<(TableId, PathBuf, SsTableReader)>,
-    block_bytes: usize,
+    shared: Arc<Shared>,
+    /// Locked only to sync it through a shared reference, as
+    /// [`sync_group`](Self::sync_group) does.
+    wal: Mutex<Wal>,
+    /// Sequence number of the last write; the next one gets the one after.
+    last_seq: SeqNo,
+    workers: Vec<JoinHandle<()>>,
     pub actor_id: u64,
     local_counter: AtomicU64,
-    next_table_id: TableId,
-    manifest: Manifest,
+    in_group: bool,
+    cleanup: StartupCleanup,
+}
+
+/// Everything the engine handle and its background workers share.
+struct Shared {
+    data_dir: PathBuf,
+    opts: EngineOptions,
+    /// `opts.merge_operator`, with CRDT state merged by its own operator.
+    merge: Arc<dyn MergeOperator>,
+    next_table_id: AtomicU64,
+    tables: Arc<TableCache>,
+    /// Written only by flushes and compactions, which hold it across the
+    /// edit's fsync and then take `state` just to install the result, so
+    /// reads and writes never wait on manifest I/O. Lock it before `state`.
+    manifest: Mutex<Manifest>,
+    state: Mutex<State>,
+    /// Wakes the workers: a memtable was frozen, a flush made compaction
+    /// worth checking, or the engine is shutting down.
+    work: Condvar,
+    /// Wakes anyone waiting on the workers: a flush or compaction was
+    /// installed, or a worker failed.
+    progress: Condvar,
+}
+
+struct State {
+    /// Frozen memtables stay readable here until their table is installed.
+    memtables: MemTableSet,
+    /// Log number holding the writes of each immutable memtable, oldest first.
+    immutable_logs: VecDeque<u64>,
+    version: Arc<Version>,
+    /// Sequence number of the last write applied to the memtables.
+    last_seq: SeqNo,
+    /// Sequence numbers of the live snapshots, with how many share each.
+    snapshots: BTreeMap<SeqNo, usize>,
+    compactor: Box<dyn CompactionStrategy>,
+    compaction_pending: bool,
+    compacting: bool,
+    shutting_down: bool,
+    /// The first error hit by a worker; the engine refuses writes after it.
+    bg_error: Option<std::io::Error>,
 }
 
 impl LsmEngine {
@@ -24,20 +143,47 @@ impl LsmEngine {
         block_bytes: usize,
     ) -> std::io::Result<Self> {
         let data_dir = data_dir.as_ref().to_path_buf();
-        let sst_dir = data_dir.join("sst");
-        fs::create_dir_all(&sst_dir)?;
-        let memtables = MemTableSet::with_capacity(memtable_max_bytes);
-        let manifest = Manifest::new(data_dir.join("MANIFEST-000001"))?;
-        Ok(Self {
-            data_dir,
-            memtables,
-            sstables: Vec::new(),
+        let opts = EngineOptions {
+            memtable_max_bytes,
             block_bytes,
-            actor_id: 0,
-            local_counter: AtomicU64::new(0),
-            next_table_id: 1,
-            manifest,
-        })
+            ..EngineOptions::default()
+        };
+        let env = opts.env.as_ref();
+        env.create_dir_all(&data_dir.join("sst"))?;
+        env.create_dir_all(&data_dir.join("wal"))?;
+        let manifest = Manifest::create(
+            &opts.env,
+            &data_dir,
+            1,
+            ManifestState::default(),
+            opts.max_manifest_bytes,
+        )?;
+        let wal = Wal::create(env, wal_path(&data_dir, 1), 1, opts.sync_policy)?;
+        fsync_dir(env, wal.path())?;
+        let state = State {
+            memtables: MemTableSet::with_capacity(memtable_max_bytes),
+            immutable_logs: VecDeque::new(),
+            version: Arc::new(Version::new(opts.compaction.num_levels)),
+            last_seq: 0,
+            snapshots: BTreeMap::new(),
+            compactor: new_strategy(&opts.compaction),
+            compaction_pending: false,
+            compacting: false,
+            shutting_down: false,
+            bg_error: None,
+        };
+        let shared = Shared {
+            data_dir,
+            tables: table_cache(&opts),
+            merge: engine_merge(&opts),
+            opts,
+            next_table_id: AtomicU64::new(2),
+            manifest: Mutex::new(manifest),
+            state: Mutex::new(state),
+            work: Condvar::new(),
+            progress: Condvar::new(),
+        };
+        Self::start(Arc::new(shared), wal, 0)
     }
 
     pub fn new_with_manifest<P: AsRef<Path>>(
@@ -45,35 +191,134 @@ impl LsmEngine {
         memtable_max_bytes: usize,
         block_bytes: usize,
     ) -> std::io::Result<Self> {
+        let opts = EngineOptions {
+            memtable_max_bytes,
+            block_bytes,
+            ..EngineOptions::default()
+        };
+        Self::open(data_dir, opts)
+    }
+
+    /// Opens (or creates) the engine under `data_dir`, replaying the manifest and any live logs.
+    ///
+    /// The compaction strategy is the built-in one named by `opts.compaction.style`.
+    pub fn open<P: AsRef<Path>>(data_dir: P, opts: EngineOptions) -> std::io::Result<Self> {
+        let strategy = new_strategy(&opts.compaction);
+        Self::open_with_strategy(data_dir, opts, strategy)
+    }
+
+    /// Like [`open`](Self::open), but compacts with a caller-supplied strategy.
+    pub fn open_with_strategy<P: AsRef<Path>>(
+        data_dir: P,
+        opts: EngineOptions,
+        strategy: Box<dyn CompactionStrategy>,
+    ) -> std::io::Result<Self> {
+        let memtable_max_bytes = opts.memtable_max_bytes;
         let data_dir = data_dir.as_ref().to_path_buf();
-        let sst_dir = data_dir.join("sst");
-        fs::create_dir_all(&sst_dir)?;
+        let env = opts.env.as_ref();
+        env.create_dir_all(&data_dir.join("sst"))?;
+        let wal_dir = data_dir.join("wal");
+        env.create_dir_all(&wal_dir)?;
+
+        let manifest = Manifest::open(&opts.env, &data_dir, opts.max_manifest_bytes)?;
+        let state = manifest.state().clone();
 
-        let memtables = MemTableSet::with_capacity(memtable_max_bytes);
-        let name = read_current_or_init(&data_dir, "MANIFEST-000001")?;
-        let mut manifest = open_manifest_append(&data_dir, &name)?;
-        let active_tables_ids = manifest.replay_manifest()?;
+        let live: HashSet<TableId> = state.tables.iter().map(|t| t.id).collect();
+        let cleanup = collect_garbage(env, &data_dir, &live, opts.quarantine_orphans)?;
+
+        let tables = table_cache(&opts);
+        let mut version = Version::new(opts.compaction.num_levels);
+        for record in &state.tables {
+            let path = data_dir.join("sst").join(format!("{:06}.sst", record.id));
+            let opened = tables.get(record.id, &path).and_then(|reader| {
+                // Text manifests recorded only ids; read those ranges back.
+                if record.file_size == 0 {
+                    let (smallest, largest) = reader.key_range()?.unwrap_or_default();
+                    Ok((smallest, largest, reader.file_len()?))
+                } else {
+                    Ok((
+                        record.smallest.clone(),
+                        record.largest.clone(),
+                        record.file_size,
+                    ))
+                }
+            });
+            let (smallest, largest, file_size) = match opened {
+                Ok(range) => range,
+                Err(error) => {
+                    let id = record.id;
+                    return Err(CorruptTable { id, path, error }.into_error());
+                }
+            };
+            let table = TableMeta {
+                id: record.id,
+                path,
+                tables: tables.clone(),
+                smallest,
+                largest,
+                file_size,
+                retired: AtomicBool::new(false),
+            };
+            let level = record.level.min(version.num_levels() - 1);
+            version.add(level, Arc::new(table));
+        }
 
-        let mut sstables = Vec::new();
-        for id in active_tables_ids.clone() {
-            let path = data_dir.join("sst").join(format!("{id:06}.sst"));
-            if let Ok(reader) = SsTableReader::open(&path) {
-                sstables.push((id, path, reader));
+        let logs = list_wal_numbers(env, &wal_dir)?;
+        let recovered = MemTable::new(memtable_max_bytes);
+        let mut last_seq = state.last_sequence;
+        for &n in logs.iter().filter(|&&n| n >= state.log_number) {
+            for (first_seq, record) in replay_wal(env, &wal_path(&data_dir, n))? {
+                for ((key, entry), seq) in record.into_iter().zip(first_seq..) {
+                    recovered.insert(&key, seq, entry);
+                    last_seq = last_seq.max(seq);
+                }
             }
         }
 
-        let next_table_id = active_tables_ids.iter().copied().max().unwrap_or(0) + 1;
+        let max_used = state
+            .tables
+            .iter()
+            .map(|t| t.id)
+            .chain(logs.iter().copied())
+            .max()
+            .unwrap_or(0)
+            .max(state.log_number);
+        let wal_number = (max_used + 1).max(state.next_table_id);
+        let wal = Wal::create(
+            env,
+            wal_path(&data_dir, wal_number),
+            wal_number,
+            opts.sync_policy,
+        )?;
+        fsync_dir(env, wal.path())?;
 
-        Ok(Self {
+        let state = State {
+            memtables: MemTableSet::with_capacity(memtable_max_bytes),
+            immutable_logs: VecDeque::new(),
+            version: Arc::new(version),
+            last_seq,
+            snapshots: BTreeMap::new(),
+            compactor: strategy,
+            compaction_pending: true,
+            compacting: false,
+            shutting_down: false,
+            bg_error: None,
+        };
+        let shared = Shared {
             data_dir,
-            memtables,
-            sstables,
-            block_bytes,
-            actor_id: 0,
-            local_counter: AtomicU64::new(0),
-            next_table_id,
-            manifest,
-        })
+            merge: engine_merge(&opts),
+            opts,
+            next_table_id: AtomicU64::new(wal_number + 1),
+            tables,
+            manifest: Mutex::new(manifest),
+            state: Mutex::new(state),
+            work: Condvar::new(),
+            progress: Condvar::new(),
+        };
+        shared.install_recovered(&recovered, &logs, wal_number)?;
+        let mut eng = Self::start(Arc::new(shared), wal, last_seq)?;
+        eng.cleanup = cleanup;
+        Ok(eng)
     }
 
     pub fn new_with_manifest_and_actor(
@@ -82,111 +327,588 @@ impl LsmEngine {
         block_size: usize,
         actor_id: u64,
     ) -> std::io::Result<Self> {
-        let mut eng = Self::new_with_manifest(data_dir, memtable_size, block_size)?;
+        let opts = EngineOptions {
+            memtable_max_bytes: memtable_size,
+            block_bytes: block_size,
+            ..EngineOptions::default()
+        };
+        Self::open_with_actor(data_dir, opts, actor_id)
+    }
+
+    pub fn open_with_actor(
+        data_dir: &std::path::Path,
+        opts: EngineOptions,
+        actor_id: u64,
+    ) -> std::io::Result<Self> {
+        let mut eng = Self::open(data_dir, opts)?;
         eng.actor_id = actor_id;
         eng.local_counter = AtomicU64::new(1);
         Ok(eng)
     }
 
+    fn start(shared: Arc<Shared>, wal: Wal, last_seq: SeqNo) -> std::io::Result<Self> {
+        let flusher = {
+            let shared = shared.clone();
+            std::thread::Builder::new()
+                .name("zynk-flush".into())
+                .spawn(move || shared.flush_loop())?
+        };
+        let compactor = {
+            let shared = shared.clone();
+            std::thread::Builder::new()
+                .name("zynk-compact".into())
+                .spawn(move || shared.compaction_loop())?
+        };
+        Ok(Self {
+            shared,
+            wal: Mutex::new(wal),
+            last_seq,
+            workers: vec![flusher, compactor],
+            actor_id: 0,
+            local_counter: AtomicU64::new(0),
+            in_group: false,
+            cleanup: StartupCleanup::default(),
+        })
+    }
+
+    pub fn options(&self) -> &EngineOptions {
+        &self.shared.opts
+    }
+
+    pub fn sync_policy(&self) -> SyncPolicy {
+        self.wal.lock().unwrap().policy()
+    }
+
+    /// Bloom filter checks made by point lookups since the engine was opened.
+    pub fn filter_stats(&self) -> &FilterStats {
+        &self.shared.tables.context().filter_stats
+    }
+
+    /// The block cache shared by every table, unless disabled by
+    /// `block_cache_bytes: 0`.
+    pub fn block_cache(&self) -> Option<&BlockCache> {
+        self.shared.tables.context().block_cache.as_deref()
+    }
+
+    /// The cache of open table files.
+    pub fn table_cache(&self) -> &TableCache {
+        &self.shared.tables
+    }
+
+    /// Sequence number of the last write.
+    pub fn last_sequence(&self) -> SeqNo {
+        self.last_seq
+    }
+
+    /// Every live table, level by level; level 0 oldest first, deeper
+    /// levels in key order.
+    pub fn tables(&self) -> Vec<TableInfo> {
+        let version = self.shared.lock().version.clone();
+        (0..version.num_levels())
+            .flat_map(|level| {
+                version.level(level).iter().map(move |t| TableInfo {
+                    id: t.id,
+                    level,
+                    smallest: t.smallest.clone(),
+                    largest: t.largest.clone(),
+                    file_size: t.file_size,
+                })
+            })
+            .collect()
+    }
+
+    /// What opening the engine removed from, or quarantined out of, `sst/`.
+    pub fn startup_cleanup(&self) -> &StartupCleanup {
+        &self.cleanup
+    }
+
+    pub fn compaction_strategy(&self) -> &'static str {
+        self.shared.lock().compactor.name()
+    }
+
     /// Generate a fresh ElementId for local inserts.
     pub fn next_element_id(&self) -> ElementId {
         let ctr = self.local_counter.fetch_add(1, Ordering::SeqCst);
         ElementId::new(self.actor_id, ctr)
     }
 
-    pub fn put(&mut self, key: &[u8], value: &[u8]) -> std::io::Result<()> {
-        if let Some(frozen) = self.memtables.put(key, value) {
-            self.flush_immutable(frozen)?;
+    /// Plain writes to a key holding CRDT state fail with `InvalidInput`,
+    /// as do writes to keys starting with
+    /// [`CRDT_KEY_PREFIX`](crate::engine::crdt::CRDT_KEY_PREFIX).
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> std::io::Result<WriteAck> {
+        self.check_plain_write(key)?;
+        self.make_room()?;
+        let seq = self.last_seq + 1;
+        self.log().append_put(key, seq, value)?;
+        self.apply(seq, |m| m.put(key, seq, value))?;
+        Ok(self.ack_append())
+    }
+
+    /// Refuses keys holding CRDT state, like [`put`](Self::put).
+    pub fn delete(&mut self, key: &[u8]) -> std::io::Result<WriteAck> {
+        self.check_plain_write(key)?;
+        self.make_room()?;
+        let seq = self.last_seq + 1;
+        self.log().append_delete(key, seq)?;
+        self.apply(seq, |m| m.delete(key, seq))?;
+        Ok(self.ack_append())
+    }
+
+    /// Like [`put`](Self::put), but `key` reads as absent once `ttl` has
+    /// passed, and compaction eventually drops it.
+    pub fn put_with_ttl(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        ttl: Duration,
+    ) -> std::io::Result<WriteAck> {
+        self.check_plain_write(key)?;
+        self.make_room()?;
+        let entry = Entry::Expiring {
+            value: value.to_vec(),
+            expires_at: now_millis().saturating_add(ttl.as_millis() as u64),
+        };
+        self.write_entries(&[(key.to_vec(), entry)])
+    }
+
+    /// Records `operand` for `key` without reading it. Reads apply the
+    /// engine's [`MergeOperator`] to the operands on top of the key's last
+    /// put, and flushes and compactions fold them in as they go. Operands
+    /// the operator rejects fail with `InvalidInput`.
+    pub fn merge(&mut self, key: &[u8], operand: &[u8]) -> std::io::Result<WriteAck> {
+        self.check_plain_write(key)?;
+        self.shared
+            .opts
+            .merge_operator
+            .check_operand(key, operand)?;
+        self.make_room()?;
+        self.write_entries(&[(key.to_vec(), Entry::Merge(operand.to_vec()))])
+    }
+
+    /// Merges `state` into the CRDT held at `key`, as when a replica ships
+    /// its state.
+    pub fn merge_crdt<C: CRDT>(&mut self, key: &[u8], state: &C) -> std::io::Result<WriteAck> {
+        self.make_room()?;
+        let mut staged = BTreeMap::new();
+        self.stage_crdt(&mut staged, key, C::KIND, &state.to_bytes())?;
+        self.write_entries(&flatten(staged))
+    }
+
+    /// Applies every op in `batch` atomically: one log record, one memtable.
+    pub fn write(&mut self, batch: &WriteBatch) -> std::io::Result<WriteAck> {
+        self.make_room()?;
+        let entries = self.resolve_batch(batch)?;
+        if entries.is_empty() {
+            return Ok(WriteAck { synced: true });
         }
-        Ok(())
+        self.write_entries(&entries)
+    }
+
+    fn write_entries(&mut self, entries: &[(Vec<u8>, Entry)]) -> std::io::Result<WriteAck> {
+        let first_seq = self.last_seq + 1;
+        self.log().append_batch(entries, first_seq)?;
+        let last_seq = first_seq + entries.len() as SeqNo - 1;
+        self.apply(last_seq, |m| m.apply(entries, first_seq))?;
+        Ok(self.ack_append())
+    }
+
+    /// Turns batch ops into the entries to write per key, oldest first. CRDT
+    /// ops become merge operands on the key's CRDT state, and an operand
+    /// folds into a put or delete earlier in the batch, or into the operand
+    /// before it where it can.
+    fn resolve_batch(&self, batch: &WriteBatch) -> std::io::Result<Vec<(Vec<u8>, Entry)>> {
+        let mut staged: BTreeMap<Vec<u8>, Vec<Entry>> = BTreeMap::new();
+        let check = |staged: &BTreeMap<_, _>, key: &[u8]| {
+            if staged.contains_key(&crdt_key(key)) {
+                return Err(holds_crdt_error());
+            }
+            self.check_plain_write(key)
+        };
+        for op in batch.ops() {
+            match op {
+                BatchOp::Put { key, value } => {
+                    check(&staged, key)?;
+                    staged.insert(key.clone(), vec![Entry::Put(value.clone())]);
+                }
+                BatchOp::Delete { key } => {
+                    check(&staged, key)?;
+                    staged.insert(key.clone(), vec![Entry::Delete]);
+                }
+                BatchOp::Merge { key, operand } => {
+                    check(&staged, key)?;
+                    self.shared
+                        .opts
+                        .merge_operator
+                        .check_operand(key, operand)?;
+                    self.stage_merge(&mut staged, key, operand)?;
+                }
+                BatchOp::GSetAdd { key, elem } => {
+                    let mut gs = GSet::new();
+                    gs.insert(elem.clone());
+                    self.stage_crdt(&mut staged, key, GSet::KIND, &gs.to_bytes())?;
+                }
+                BatchOp::RgaInsert {
+                    key,
+                    prev,
+                    id,
+                    value,
+                } => {
+                    let mut rga = Rga::new();
+                    rga.insert(*id, *prev, value.clone());
+                    self.stage_crdt(&mut staged, key, Rga::KIND, &rga.to_bytes())?;
+                }
+                BatchOp::RgaDelete { key, id } => {
+                    // A tombstone for an element not seen yet still wins
+                    // once its insert arrives.
+                    let mut rga = Rga::new();
+                    rga.delete(*id);
+                    self.stage_crdt(&mut staged, key, Rga::KIND, &rga.to_bytes())?;
+                }
+            }
+        }
+        Ok(flatten(staged))
     }
 
-    pub fn delete(&mut self, key: &[u8]) -> std::io::Result<()> {
-        if let Some(frozen) = self.memtables.delete(key) {
-            self.flush_immutable(frozen)?;
+    /// Fails unless `key` may take a plain write.
+    fn check_plain_write(&self, key: &[u8]) -> std::io::Result<()> {
+        check_key(key)?;
+        if self.shared.holds_crdt(key)? {
+            return Err(holds_crdt_error());
         }
         Ok(())
     }
 
-    pub fn get(&self, key: &[u8]) -> std::io::Result<Option<Vec<u8>>> {
-        if let Some(entry) = self.memtables.get(key) {
-            return Ok(match entry {
-                Entry::Put(v) => Some(v.clone()),
-                Entry::Delete => None,
-            });
-        }
-        for (_, _path, reader) in self.sstables.iter().rev() {
-            if let Some(v) = reader.get(key)? {
-                return Ok(Some(v));
+    /// Stages `state`, of `kind`, to be merged into the CRDT state of `key`.
+    ///
+    /// The first CRDT op on a key takes in the plain values it held, as
+    /// [`LsmEngine::gset_get`] reads them, and deletes them; after that the
+    /// key only takes CRDT ops.
+    fn stage_crdt(
+        &self,
+        staged: &mut BTreeMap<Vec<u8>, Vec<Entry>>,
+        key: &[u8],
+        kind: CrdtKind,
+        state: &[u8],
+    ) -> std::io::Result<()> {
+        check_key(key)?;
+        let internal = crdt_key(key);
+        let mut state = state.to_vec();
+        if !staged.contains_key(&internal) && !self.shared.holds_crdt(key)? {
+            if staged.contains_key(key) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "a batch cannot write a key both plainly and with CRDT ops",
+                ));
+            }
+            if let Some(plain) = self.shared.plain_crdt_at(key, SeqNo::MAX, kind)? {
+                state = kind.merge_encoded(Some(&plain), &state)?;
+                staged.insert(key.to_vec(), vec![Entry::Delete]);
             }
         }
-        Ok(None)
+        self.stage_merge(staged, &internal, &kind.tag_state(&state))
     }
 
-    pub fn flush(&mut self) -> std::io::Result<()> {
-        if let Some(frozen) = self.memtables.rotate() {
-            self.flush_immutable(frozen)?;
+    fn stage_merge(
+        &self,
+        staged: &mut BTreeMap<Vec<u8>, Vec<Entry>>,
+        key: &[u8],
+        operand: &[u8],
+    ) -> std::io::Result<()> {
+        let op = self.shared.merge.as_ref();
+        let entries = staged.entry(key.to_vec()).or_default();
+        match entries.last_mut() {
+            Some(Entry::Put(value)) => *value = op.full_merge(key, Some(value), &[operand])?,
+            Some(last @ Entry::Delete) => {
+                *last = Entry::Put(op.full_merge(key, None, &[operand])?)
+            }
+            Some(Entry::Merge(older)) => match op.partial_merge(key, older, operand) {
+                Some(combined) => *older = combined,
+                None => entries.push(Entry::Merge(operand.to_vec())),
+            },
+            _ => entries.push(Entry::Merge(operand.to_vec())),
         }
         Ok(())
     }
 
-    pub fn gset_add(&mut self, key: Vec<u8>, elem: Vec<u8>) -> std::io::Result<()> {
-        use crate::engine::crdt::{GSet, CRDT};
+    /// Runs `f` with log syncing deferred, then syncs at most once for every
+    /// write it made. This is how concurrent writers share a single fsync.
+    ///
+    /// The writes `f` made are applied and visible whatever the sync
+    /// returns. If it fails they may not be durable, and the engine refuses
+    /// every later write.
+    pub fn group_commit<R>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> R,
+    ) -> (R, std::io::Result<WriteAck>) {
+        let out = self.write_group(f);
+        (out, self.sync_group())
+    }
 
-        if let Some(entry) = self.memtables.get(&key) {
-            if let crate::storage::memtable::Entry::Put(existing_bytes) = entry {
-                let mut gs = GSet::from_bytes(existing_bytes);
-                gs.insert(elem);
-                let new_bytes = gs.to_bytes();
-                if let Some(frozen) = self.memtables.put(&key, &new_bytes) {
-                    self.flush_immutable(frozen)?;
-                }
-                return Ok(());
+    /// The first half of [`group_commit`](Self::group_commit): runs `f`
+    /// with log syncing deferred, leaving the sync to
+    /// [`sync_group`](Self::sync_group).
+    pub fn write_group<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R {
+        /// Ends the group even if `f` panics, so later writes sync again.
+        struct Group<'a>(&'a mut LsmEngine);
+
+        impl Drop for Group<'_> {
+            fn drop(&mut self) {
+                self.0.in_group = false;
             }
         }
 
-        for (_, _path, reader) in self.sstables.iter().rev() {
-            if let Some(bytes) = reader.get(&key)? {
-                let mut gs = GSet::from_bytes(&bytes);
-                gs.insert(elem);
-                let new_bytes = gs.to_bytes();
-                if let Some(frozen) = self.memtables.put(&key, &new_bytes) {
-                    self.flush_immutable(frozen)?;
-                }
+        self.in_group = true;
+        let group = Group(self);
+        f(&mut *group.0)
+    }
+
+    /// The second half of [`group_commit`](Self::group_commit): syncs the
+    /// log once for everything appended since the last sync, if the sync
+    /// policy says it is due. It only needs a shared reference, so reads can
+    /// go on while it runs.
+    pub fn sync_group(&self) -> std::io::Result<WriteAck> {
+        let synced = self.wal.lock().unwrap().sync_if_due();
+        if let Err(e) = &synced {
+            self.shared.fail(copy_error(e));
+        }
+        synced.map(|synced| WriteAck { synced })
+    }
+
+    /// Forces everything appended to the log so far onto stable storage.
+    pub fn sync_wal(&self) -> std::io::Result<()> {
+        let mut wal = self.wal.lock().unwrap();
+        if wal.unsynced_bytes() > 0 {
+            wal.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Syncs a write that is already applied, unless it is part of a group.
+    /// A failed sync is not the write's error: it is reported unsynced, and
+    /// the engine refuses every later write with the sync's error.
+    fn ack_append(&mut self) -> WriteAck {
+        if self.in_group {
+            return WriteAck { synced: false };
+        }
+        self.sync_group().unwrap_or(WriteAck { synced: false })
+    }
+
+    fn log(&mut self) -> &mut Wal {
+        self.wal.get_mut().unwrap()
+    }
+
+    /// Stalls the writer while the flusher is too far behind, and surfaces
+    /// any background failure.
+    fn make_room(&self) -> std::io::Result<()> {
+        let mut st = self.shared.lock();
+        loop {
+            if let Some(e) = &st.bg_error {
+                return Err(copy_error(e));
+            }
+            if st.shutting_down {
+                return Err(std::io::Error::other("engine is closed"));
+            }
+            if st.memtables.immutables_len() < self.shared.opts.max_immutable_memtables.max(1) {
                 return Ok(());
             }
+            st = self.shared.progress.wait(st).unwrap();
         }
+    }
 
-        let mut gs = GSet::new();
-        gs.insert(elem);
-        let new_bytes = gs.to_bytes();
-        if let Some(frozen) = self.memtables.put(&key, &new_bytes) {
-            self.flush_immutable(frozen)?;
+    /// Applies a write numbered up to `last_seq` to the memtables, making it
+    /// visible to new snapshots; if that froze the active memtable, moves on
+    /// to a fresh log and hands the frozen one to the flusher.
+    fn apply(
+        &mut self,
+        last_seq: SeqNo,
+        f: impl FnOnce(&mut MemTableSet) -> Option<Arc<MemTable>>,
+    ) -> std::io::Result<()> {
+        self.last_seq = last_seq;
+        let frozen = {
+            let mut st = self.shared.lock();
+            let frozen = f(&mut st.memtables).is_some();
+            st.last_seq = last_seq;
+            if frozen {
+                st.immutable_logs
+                    .push_back(self.wal.get_mut().unwrap().number());
+            }
+            frozen
+        };
+        if frozen {
+            // Later writes would otherwise go to a log that is about to be
+            // flushed and deleted.
+            if let Err(e) = self.switch_wal() {
+                self.shared.fail(copy_error(&e));
+                return Err(e);
+            }
+            self.shared.work.notify_all();
         }
         Ok(())
     }
 
-    pub fn gset_get(&self, key: &[u8]) -> std::io::Result<Vec<Vec<u8>>> {
-        use crate::engine::crdt::{GSet, CRDT};
-        use crate::storage::memtable::Entry;
+    /// The value of `key`, or for a key holding CRDT state, that state as
+    /// the CRDT's `to_bytes` encodes it.
+    pub fn get(&self, key: &[u8]) -> std::io::Result<Option<Vec<u8>>> {
+        self.shared.get_at(key, SeqNo::MAX)
+    }
+
+    /// Iterates the live pairs with keys in `range` in ascending key order,
+    /// e.g. `eng.scan(b"a".as_slice()..b"m")`. Only plain values are
+    /// listed; keys holding CRDT state are not.
+    ///
+    /// The scan sees the engine as it was when `scan` was called; later
+    /// writes, flushes and compactions do not affect it.
+    pub fn scan<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> std::io::Result<Scan> {
+        let (start, end) = owned_bounds(range);
+        self.shared.scan_at(start, end, false, SeqNo::MAX, true)
+    }
+
+    /// Like [`scan`](Self::scan), but in descending key order.
+    pub fn scan_rev<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> std::io::Result<Scan> {
+        let (start, end) = owned_bounds(range);
+        self.shared.scan_at(start, end, true, SeqNo::MAX, true)
+    }
+
+    /// Takes a consistent, read-only view of everything written so far.
+    ///
+    /// Flushes and compactions keep every version the snapshot can see until
+    /// it is dropped, so long-lived snapshots hold on to overwritten data.
+    pub fn snapshot(&self) -> Snapshot {
+        let mut st = self.shared.lock();
+        let seq = st.last_seq;
+        *st.snapshots.entry(seq).or_default() += 1;
+        Snapshot {
+            shared: self.shared.clone(),
+            seq,
+        }
+    }
 
-        let mut result = GSet::new();
+    /// Freezes the active memtable and waits until every frozen memtable
+    /// has been written out as a table.
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.apply(self.last_seq, |m| m.rotate())?;
+        let mut st = self.shared.lock();
+        loop {
+            if let Some(e) = &st.bg_error {
+                return Err(copy_error(e));
+            }
+            if st.memtables.immutables_len() == 0 {
+                return Ok(());
+            }
+            st = self.shared.progress.wait(st).unwrap();
+        }
+    }
 
-        if let Some(entry) = self.memtables.get(key) {
-            if let Entry::Put(bytes) = entry {
-                let gs = GSet::from_bytes(bytes);
-                result.merge(&gs);
+    /// Waits until all pending flushes and compactions have finished.
+    pub fn wait_for_background(&self) -> std::io::Result<()> {
+        let mut st = self.shared.lock();
+        loop {
+            if let Some(e) = &st.bg_error {
+                return Err(copy_error(e));
+            }
+            let idle = st.memtables.immutables_len() == 0 && !st.compaction_pending;
+            if st.shutting_down || (idle && !st.compacting) {
+                return Ok(());
             }
+            st = self.shared.progress.wait(st).unwrap();
         }
+    }
 
-        for (_, _path, reader) in self.sstables.iter().rev() {
-            if let Some(bytes) = reader.get(key)? {
-                let gs = GSet::from_bytes(&bytes);
-                result.merge(&gs);
+    /// Flushes the memtables and returns the tables that then hold
+    /// everything written so far. They stay on disk, even if compaction
+    /// replaces them, until the result is dropped.
+    pub fn live_files(&mut self) -> std::io::Result<LiveFiles> {
+        self.flush()?;
+        let manifest = self.shared.manifest.lock().unwrap();
+        let st = self.shared.lock();
+        let version = st.version.clone();
+        let mut tables = Vec::new();
+        let mut paths = Vec::new();
+        for level in 0..version.num_levels() {
+            for table in version.level(level) {
+                tables.push(table_record(table, level));
+                paths.push(table.path.clone());
             }
         }
+        let state = ManifestState {
+            tables,
+            log_number: manifest.state().log_number,
+            next_table_id: self.shared.next_table_id.load(Ordering::SeqCst),
+            last_sequence: self.last_seq,
+        };
+        Ok(LiveFiles {
+            state,
+            paths,
+            _version: version,
+        })
+    }
 
-        Ok(result.elements())
+    /// Writes a point-in-time copy of the engine to `dest_dir`, which must
+    /// not exist yet, while the engine stays open.
+    ///
+    /// The memtables are flushed first, so the copy needs no log: it holds
+    /// every live table, hard-linked where the filesystem allows and copied
+    /// otherwise, and a manifest of its own. It opens like any data directory.
+    pub fn checkpoint<P: AsRef<Path>>(&mut self, dest_dir: P) -> std::io::Result<()> {
+        let dest = dest_dir.as_ref();
+        let shared = self.shared.clone();
+        let env = shared.opts.env.as_ref();
+        if env.exists(dest) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} already exists", dest.display()),
+            ));
+        }
+        let live = self.live_files()?;
+        let sst_dir = dest.join("sst");
+        env.create_dir_all(&sst_dir)?;
+        env.create_dir_all(&dest.join("wal"))?;
+        for (table, path) in live.state.tables.iter().zip(&live.paths) {
+            let to = sst_dir.join(format!("{:06}.sst", table.id));
+            if env.hard_link(path, &to).is_err() {
+                copy_file(env, path, &to)?;
+            }
+        }
+        env.sync_dir(&sst_dir)?;
+        let opts = &shared.opts;
+        Manifest::create(&opts.env, dest, 1, live.state, opts.max_manifest_bytes)?;
+        fsync_dir(env, dest)
+    }
+
+    /// Stops the background workers after they have flushed every frozen
+    /// memtable and finished the compaction in progress, then syncs the log.
+    ///
+    /// Called on drop; calling it directly also reports any background error.
+    pub fn close(&mut self) -> std::io::Result<()> {
+        if self.workers.is_empty() {
+            return Ok(());
+        }
+        self.shared.lock().shutting_down = true;
+        self.shared.work.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+        self.log().sync()?;
+        match &self.shared.lock().bg_error {
+            Some(e) => Err(copy_error(e)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn gset_add(&mut self, key: Vec<u8>, elem: Vec<u8>) -> std::io::Result<WriteAck> {
+        let mut batch = WriteBatch::new();
+        batch.gset_add(&key, elem);
+        self.write(&batch)
+    }
+
+    /// The elements of the G-Set at `key`. A key no CRDT op has written
+    /// reads as the union of the plain values still stored for it, which is
+    /// how G-Sets were kept before they had state of their own.
+    pub fn gset_get(&self, key: &[u8]) -> std::io::Result<Vec<Vec<u8>>> {
+        Ok(match self.shared.crdt_at(key, SeqNo::MAX, GSet::KIND)? {
+            Some(state) => GSet::decode(&state)?.elements(),
+            None => Vec::new(),
+        })
     }
 
     pub fn rga_insert_after(
@@ -196,71 +918,460 @@ impl LsmEngine {
         value: Vec<u8>,
         actor_id: u64,
         counter: u64,
-    ) -> std::io::Result<()> {
-        let mut rga = match self.get(key)? {
-            Some(bs) => Rga::from_bytes(&bs),
-            None => Rga::new(),
+    ) -> std::io::Result<WriteAck> {
+        let mut batch = WriteBatch::new();
+        batch.rga_insert_after(key, prev, ElementId::new(actor_id, counter), value);
+        self.write(&batch)
+    }
+
+    pub fn rga_delete(&mut self, key: &[u8], id: ElementId) -> std::io::Result<WriteAck> {
+        let mut batch = WriteBatch::new();
+        batch.rga_delete(key, id);
+        self.write(&batch)
+    }
+
+    /// Like [`gset_get`](Self::gset_get), for the RGA at `key`.
+    pub fn rga_get_visible(&self, key: &[u8]) -> std::io::Result<Vec<Vec<u8>>> {
+        match self.shared.crdt_at(key, SeqNo::MAX, Rga::KIND)? {
+            Some(state) => Ok(Rga::decode(&state)?.visible_sequence()),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Starts a fresh log for the new active memtable.
+    fn switch_wal(&mut self) -> std::io::Result<()> {
+        self.log().sync()?;
+        let number = self.shared.alloc_table_id();
+        let env = self.shared.opts.env.as_ref();
+        let wal = Wal::create(
+            env,
+            wal_path(&self.shared.data_dir, number),
+            number,
+            self.shared.opts.sync_policy,
+        )?;
+        fsync_dir(env, wal.path())?;
+        *self.log() = wal;
+        Ok(())
+    }
+
+    #[cfg(test)]
+    fn current_version(&self) -> Arc<Version> {
+        self.shared.lock().version.clone()
+    }
+}
+
+impl Drop for LsmEngine {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+/// A read-only view of the engine as of [`LsmEngine::snapshot`]: reads see
+/// the writes made before it was taken and none made after.
+pub struct Snapshot {
+    shared: Arc<Shared>,
+    seq: SeqNo,
+}
+
+impl Snapshot {
+    /// Sequence number of the last write the snapshot sees.
+    pub fn sequence(&self) -> SeqNo {
+        self.seq
+    }
+
+    pub fn get(&self, key: &[u8]) -> std::io::Result<Option<Vec<u8>>> {
+        self.shared.get_at(key, self.seq)
+    }
+
+    /// Like [`LsmEngine::scan`], as of the snapshot.
+    pub fn scan<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> std::io::Result<Scan> {
+        let (start, end) = owned_bounds(range);
+        self.shared.scan_at(start, end, false, self.seq, true)
+    }
+
+    /// Like [`LsmEngine::scan_rev`], as of the snapshot.
+    pub fn scan_rev<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> std::io::Result<Scan> {
+        let (start, end) = owned_bounds(range);
+        self.shared.scan_at(start, end, true, self.seq, true)
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let mut st = self.shared.lock();
+        if let Some(n) = st.snapshots.get_mut(&self.seq) {
+            *n -= 1;
+            if *n == 0 {
+                st.snapshots.remove(&self.seq);
+            }
+        }
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// What [`LsmEngine::get`] reads of `key` among the writes numbered
+    /// `seq` or below.
+    fn get_at(&self, key: &[u8], seq: SeqNo) -> std::io::Result<Option<Vec<u8>>> {
+        check_key(key)?;
+        match self.value_at(&crdt_key(key), seq)? {
+            Some(tagged) => Ok(Some(CrdtKind::split(&tagged)?.1.to_vec())),
+            None => self.value_at(key, seq),
+        }
+    }
+
+    /// The newest value of `key` among the writes numbered `seq` or below.
+    fn value_at(&self, key: &[u8], seq: SeqNo) -> std::io::Result<Option<Vec<u8>>> {
+        let (entry, version) = {
+            let st = self.lock();
+            (st.memtables.get(key, seq), st.version.clone())
         };
+        let entry = match entry {
+            Some(entry) => Some(entry),
+            None => version.get(key, seq)?,
+        };
+        match entry {
+            // Operands need whatever lies beneath them, in any table.
+            Some(Entry::Merge(_)) => {
+                let bound = Bound::Included(key.to_vec());
+                let mut scan = self.scan_at(bound.clone(), bound, false, seq, false)?;
+                Ok(scan.next().transpose()?.map(|(_, value)| value))
+            }
+            entry => Ok(entry.and_then(|e| e.into_live_value(now_millis()))),
+        }
+    }
 
-        let id = ElementId::new(actor_id, counter);
-        rga.insert(id, prev, value.clone());
-
-        // println!(
-        //     "RGA INSERT -> key={}, id=({}:{}) prev={:?} value='{}'",
-        //     String::from_utf8_lossy(key),
-        //     actor_id,
-        //     counter,
-        //     prev,
-        //     String::from_utf8_lossy(&value)
-        // );
-
-        // println!(
-        //     "Current sequence for '{}': {:?}",
-        //     String::from_utf8_lossy(key),
-        //     rga.visible_sequence()
-        //         .iter()
-        //         .map(|v| String::from_utf8_lossy(v).to_string())
-        //         .collect::<Vec<_>>()
-        // );
-
-        let bytes = rga.to_bytes();
-        self.put(key, &bytes)
-    }
-
-    pub fn rga_delete(&mut self, key: &[u8], id: ElementId) -> std::io::Result<()> {
-        let mut rga = match self.get(key)? {
-            Some(bs) => Rga::from_bytes(&bs),
-            None => return Ok(()), // kuch nai hein delete karne ko
+    /// Whether any CRDT op has written `key`. CRDT state is never deleted,
+    /// so any version of it will do.
+    fn holds_crdt(&self, key: &[u8]) -> std::io::Result<bool> {
+        let key = crdt_key(key);
+        let version = {
+            let st = self.lock();
+            if st.memtables.get(&key, SeqNo::MAX).is_some() {
+                return Ok(true);
+            }
+            st.version.clone()
         };
-        rga.delete(id);
-        self.put(key, &rga.to_bytes())
+        Ok(version.get(&key, SeqNo::MAX)?.is_some())
     }
 
-    pub fn rga_get_visible(&self, key: &[u8]) -> std::io::Result<Vec<Vec<u8>>> {
-        match self.get(key)? {
-            Some(bs) => {
-                let rga = Rga::from_bytes(&bs);
-                Ok(rga.visible_sequence())
+    /// The encoded state of the CRDT of `kind` at `key` as of `seq`: the
+    /// state its CRDT ops merged, or for a key none has written, the plain
+    /// values it holds merged.
+    fn crdt_at(&self, key: &[u8], seq: SeqNo, kind: CrdtKind) -> std::io::Result<Option<Vec<u8>>> {
+        check_key(key)?;
+        match self.value_at(&crdt_key(key), seq)? {
+            Some(tagged) => {
+                let (found, state) = CrdtKind::split(&tagged)?;
+                found.expect(kind)?;
+                Ok(Some(state.to_vec()))
+            }
+            None => self.plain_crdt_at(key, seq, kind),
+        }
+    }
+
+    /// Every live plain value of `key` still stored, as of `seq`, merged as
+    /// states of `kind`.
+    fn plain_crdt_at(
+        &self,
+        key: &[u8],
+        seq: SeqNo,
+        kind: CrdtKind,
+    ) -> std::io::Result<Option<Vec<u8>>> {
+        let bound = Bound::Included(key.to_vec());
+        let (mut iter, _version, _) = self.entries(&bound, &bound)?;
+        iter.seek(key)?;
+        let now = now_millis();
+        let mut state: Option<Vec<u8>> = None;
+        while iter.valid() && iter.key() == key {
+            if iter.seq() <= seq {
+                if let Some(value) = iter.entry().live_value(now) {
+                    state = Some(kind.merge_encoded(state.as_deref(), value)?);
+                }
+            }
+            iter.next()?;
+        }
+        Ok(state)
+    }
+
+    /// A scan of the keys between `start` and `end`; `plain` hides the CRDT
+    /// state.
+    fn scan_at(
+        &self,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        reverse: bool,
+        seq: SeqNo,
+        plain: bool,
+    ) -> std::io::Result<Scan> {
+        let (iter, version, last_seq) = self.entries(&start, &end)?;
+        let seq = seq.min(last_seq);
+        let hidden = plain.then_some((CRDT_KEY_PREFIX, CRDT_KEYS_END));
+        let merge = self.merge.clone();
+        Scan::new(iter, version, (start, end), reverse, seq, merge, hidden)
+    }
+
+    /// Every version of the keys between `start` and `end`, from the
+    /// memtables and the tables that may hold them, with the version the
+    /// tables belong to and the sequence number of the last write included.
+    /// The memtables are pinned rather than copied and read as the cursor
+    /// moves, so later writes show up too. The tables stay readable only
+    /// while the version is held.
+    fn entries(
+        &self,
+        start: &Bound<Vec<u8>>,
+        end: &Bound<Vec<u8>>,
+    ) -> std::io::Result<(MergingIter, Arc<Version>, SeqNo)> {
+        let (mems, version, last_seq) = {
+            let st = self.lock();
+            let mems: Vec<_> = st.memtables.newest_first().cloned().collect();
+            (mems, st.version.clone(), st.last_seq)
+        };
+        if is_empty_range(start, end) {
+            return Ok((MergingIter::new(Vec::new()), version, last_seq));
+        }
+        let mut children: Vec<Box<dyn EntryIter>> = Vec::new();
+        for mem in mems {
+            children.push(Box::new(MemTableIter::new(mem)));
+        }
+        let (lo, hi) = (start.as_ref().map(|k| &k[..]), end.as_ref().map(|k| &k[..]));
+        for table in version
+            .tables_newest_first()
+            .filter(|t| t.overlaps_range(lo, hi))
+        {
+            children.push(Box::new(SsTableIter::new(table.reader()?)));
+        }
+        Ok((MergingIter::new(children), version, last_seq))
+    }
+
+    /// Sequence numbers of the live snapshots, ascending.
+    fn live_snapshots(&self) -> Vec<SeqNo> {
+        self.lock().snapshots.keys().copied().collect()
+    }
+
+    /// Records a worker failure and wakes everyone waiting on the workers.
+    fn fail(&self, e: std::io::Error) {
+        let mut st = self.lock();
+        if st.bg_error.is_none() {
+            st.bg_error = Some(e);
+        }
+        drop(st);
+        self.progress.notify_all();
+    }
+
+    /// Flushes frozen memtables, oldest first. On shutdown it keeps going
+    /// until none are left.
+    fn flush_loop(&self) {
+        loop {
+            let (mem, log) = {
+                let mut st = self.lock();
+                while st.memtables.immutables_len() == 0 && !st.shutting_down {
+                    st = self.work.wait(st).unwrap();
+                }
+                if st.bg_error.is_some() {
+                    return;
+                }
+                match (st.memtables.oldest_immutable(), st.immutable_logs.front()) {
+                    (Some(mem), Some(&log)) => (mem, log),
+                    _ => return,
+                }
+            };
+            if let Err(e) = self.flush_immutable(&mem, log) {
+                self.fail(e);
+                return;
+            }
+        }
+    }
+
+    /// Writes `mem` to a level-0 table and swaps it in for the memtable.
+    fn flush_immutable(&self, mem: &MemTable, log: u64) -> std::io::Result<()> {
+        let table = self.write_table(mem, &self.live_snapshots())?;
+        {
+            let mut manifest = self.manifest.lock().unwrap();
+            // Every write in `log` is now in the table; later logs stay live.
+            manifest.record(&VersionEdit {
+                added: vec![table_record(&table, 0)],
+                log_number: Some(log + 1),
+                next_table_id: Some(self.next_table_id.load(Ordering::SeqCst)),
+                last_sequence: Some(mem.max_seq()),
+                ..VersionEdit::default()
+            })?;
+            let mut st = self.lock();
+            Arc::make_mut(&mut st.version).add(0, Arc::new(table));
+            st.memtables.pop_oldest_immutable();
+            st.immutable_logs.pop_front();
+            st.compaction_pending = true;
+        }
+        self.progress.notify_all();
+        self.work.notify_all();
+        self.opts.env.remove_file(&wal_path(&self.data_dir, log))
+    }
+
+    /// Runs compactions whenever a flush may have pushed a level over budget.
+    fn compaction_loop(&self) {
+        loop {
+            {
+                let mut st = self.lock();
+                while !st.compaction_pending && !st.shutting_down {
+                    st = self.work.wait(st).unwrap();
+                }
+                if st.shutting_down || st.bg_error.is_some() {
+                    return;
+                }
+                st.compaction_pending = false;
+                st.compacting = true;
+            }
+            let res = self.compact_until_balanced();
+            self.lock().compacting = false;
+            self.progress.notify_all();
+            if let Err(e) = res {
+                self.fail(e);
+                return;
+            }
+        }
+    }
+
+    /// Runs the tasks the strategy hands out until it has nothing left, or
+    /// the engine starts shutting down.
+    fn compact_until_balanced(&self) -> std::io::Result<()> {
+        loop {
+            let task = {
+                let mut st = self.lock();
+                if st.shutting_down {
+                    return Ok(());
+                }
+                let State {
+                    compactor, version, ..
+                } = &mut *st;
+                compactor.pick(version)
+            };
+            match task {
+                Some(task) => self.compact(task)?,
+                None => return Ok(()),
             }
-            None => Ok(vec![]),
         }
     }
 
-    fn flush_immutable(&mut self, frozen: MemTable) -> Result<(), std::io::Error> {
+    /// Executes `task`, records the resulting edit in the manifest, installs
+    /// it in the version and retires the input tables.
+    ///
+    /// Flushes may install new level-0 tables meanwhile; outputs never land
+    /// in level 0, so those stay newer than everything compacted here.
+    fn compact(&self, task: CompactionTask) -> std::io::Result<()> {
+        let mut edit = VersionEdit {
+            removed: task.inputs.iter().map(|t| t.id).collect(),
+            next_table_id: Some(self.next_table_id.load(Ordering::SeqCst)),
+            ..VersionEdit::default()
+        };
+
+        if task.is_trivial_move() {
+            let id = task.inputs[0].id;
+            edit.added
+                .push(table_record(&task.inputs[0], task.output_level));
+            let mut manifest = self.manifest.lock().unwrap();
+            manifest.record(&edit)?;
+            let mut st = self.lock();
+            let version = Arc::make_mut(&mut st.version);
+            if let Some(table) = version.remove(id) {
+                version.add(task.output_level, table);
+            }
+            return Ok(());
+        }
+
+        let outputs = run_compaction(
+            &task,
+            &self.opts.compaction,
+            &self.opts.table_options(),
+            &self.data_dir.join("sst"),
+            &mut || self.alloc_table_id(),
+            &self.tables,
+            &VersionGc::new(&self.live_snapshots(), self.merge.as_ref()),
+        )?;
+        edit.next_table_id = Some(self.next_table_id.load(Ordering::SeqCst));
+        edit.added = outputs
+            .iter()
+            .map(|t| table_record(t, task.output_level))
+            .collect();
+        {
+            let mut manifest = self.manifest.lock().unwrap();
+            manifest.record(&edit)?;
+            let mut st = self.lock();
+            let version = Arc::make_mut(&mut st.version);
+            for table in &task.inputs {
+                version.remove(table.id);
+            }
+            for table in outputs {
+                version.add(task.output_level, Arc::new(table));
+            }
+        }
+        // Scans and lookups may still be reading the inputs through an older
+        // version they hold, so each file goes once the last version
+        // referring to it is dropped.
+        for table in &task.inputs {
+            table.retire();
+        }
+        Ok(())
+    }
+
+    /// Flushes whatever was replayed from the logs on open and retires them.
+    fn install_recovered(
+        &self,
+        recovered: &MemTable,
+        logs: &[u64],
+        log_number: u64,
+    ) -> std::io::Result<()> {
+        let mut manifest = self.manifest.lock().unwrap();
+        if recovered.is_empty() {
+            manifest.record(&VersionEdit {
+                log_number: Some(log_number),
+                next_table_id: Some(self.next_table_id.load(Ordering::SeqCst)),
+                ..VersionEdit::default()
+            })?;
+        } else {
+            // No snapshot can exist yet while the engine is being opened.
+            let table = self.write_table(recovered, &[])?;
+            manifest.record(&VersionEdit {
+                added: vec![table_record(&table, 0)],
+                log_number: Some(log_number),
+                next_table_id: Some(self.next_table_id.load(Ordering::SeqCst)),
+                last_sequence: Some(recovered.max_seq()),
+                ..VersionEdit::default()
+            })?;
+            Arc::make_mut(&mut self.lock().version).add(0, Arc::new(table));
+        }
+        for &n in logs {
+            self.opts.env.remove_file(&wal_path(&self.data_dir, n))?;
+        }
+        Ok(())
+    }
+
+    /// Writes `mem` to a new table, keeping the versions `snapshots` need.
+    fn write_table(&self, mem: &MemTable, snapshots: &[SeqNo]) -> std::io::Result<TableMeta> {
         let id = self.alloc_table_id();
         let tmp = self.sst_tmp_path(id);
         let final_path = self.sst_final_path(id);
 
-        let _ = fs::create_dir_all(final_path.parent().unwrap());
-        let _res = flush_memtable_to_sstable(frozen, &tmp, self.block_bytes)?;
+        let env = self.opts.env.as_ref();
+        let gc = VersionGc::new(snapshots, self.merge.as_ref());
+        let res = flush_memtable_to_sstable(env, mem, &gc, &tmp, &self.opts.table_options())?;
 
-        fs::rename(&tmp, &final_path)?;
-        fsync_dir(&final_path)?;
-        self.manifest.record_add_table(id)?;
+        env.rename(&tmp, &final_path)?;
+        fsync_dir(env, &final_path)?;
 
-        let reader = SsTableReader::open(&final_path)?;
-        self.sstables.push((id, final_path, reader));
-        Ok(())
+        // Opening the new table checks it is readable before it is installed.
+        self.tables.get(id, &final_path)?;
+        Ok(TableMeta {
+            id,
+            path: final_path,
+            tables: self.tables.clone(),
+            smallest: res.smallest,
+            largest: res.largest,
+            file_size: res.file_len,
+            retired: AtomicBool::new(false),
+        })
     }
 
     fn sst_tmp_path(&self, id: TableId) -> PathBuf {
@@ -271,9 +1382,740 @@ impl LsmEngine {
         self.data_dir.join("sst").join(format!("{id:06}.sst"))
     }
 
-    fn alloc_table_id(&mut self) -> TableId {
-        let id = self.next_table_id;
-        self.next_table_id += 1;
-        id
+    fn alloc_table_id(&self) -> TableId {
+        self.next_table_id.fetch_add(1, Ordering::SeqCst)
+    }
+}
+
+/// `io::Error` is not `Clone`; hand each caller its own copy of a stored one.
+fn engine_merge(opts: &EngineOptions) -> Arc<dyn MergeOperator> {
+    Arc::new(EngineMerge {
+        user: opts.merge_operator.clone(),
+    })
+}
+
+/// Staged entries, per key, as the entries to write.
+fn flatten(staged: BTreeMap<Vec<u8>, Vec<Entry>>) -> Vec<(Vec<u8>, Entry)> {
+    staged
+        .into_iter()
+        .flat_map(|(key, entries)| entries.into_iter().map(move |e| (key.clone(), e)))
+        .collect()
+}
+
+/// Fails for the keys the engine keeps CRDT state under.
+fn check_key(key: &[u8]) -> std::io::Result<()> {
+    if key.starts_with(CRDT_KEY_PREFIX) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "keys starting with the CRDT key prefix are reserved",
+        ));
+    }
+    Ok(())
+}
+
+fn holds_crdt_error() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        "key holds CRDT state and only takes CRDT ops",
+    )
+}
+
+fn copy_error(e: &std::io::Error) -> std::io::Error {
+    std::io::Error::new(e.kind(), e.to_string())
+}
+
+fn table_cache(opts: &EngineOptions) -> Arc<TableCache> {
+    let ctx = ReaderContext {
+        env: opts.env.clone(),
+        block_cache: (opts.block_cache_bytes > 0)
+            .then(|| Arc::new(BlockCache::new(opts.block_cache_bytes))),
+        filter_stats: Arc::default(),
+    };
+    Arc::new(TableCache::new(opts.max_open_tables, ctx))
+}
+
+fn owned_bounds<K: AsRef<[u8]>>(range: impl RangeBounds<K>) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    (
+        range.start_bound().map(|k| k.as_ref().to_vec()),
+        range.end_bound().map(|k| k.as_ref().to_vec()),
+    )
+}
+
+fn table_record(table: &TableMeta, level: usize) -> TableRecord {
+    TableRecord {
+        id: table.id,
+        level,
+        smallest: table.smallest.clone(),
+        largest: table.largest.clone(),
+        file_size: table.file_size,
+    }
+}
+
+/// Copies `from` to `to` and syncs the copy.
+fn copy_file(env: &dyn Env, from: &Path, to: &Path) -> std::io::Result<()> {
+    let mut file = env.create(to)?;
+    file.write_all(&env.read(from)?)?;
+    file.sync()
+}
+
+fn wal_path(data_dir: &Path, number: u64) -> PathBuf {
+    data_dir.join("wal").join(format!("{number:06}.log"))
+}
+
+/// Deletes, or moves to `quarantine/`, every table file under `sst/` that is
+/// half-written or not in `live`. Files with other names are left alone.
+fn collect_garbage(
+    env: &dyn Env,
+    data_dir: &Path,
+    live: &HashSet<TableId>,
+    quarantine: bool,
+) -> std::io::Result<StartupCleanup> {
+    let sst_dir = data_dir.join("sst");
+    let mut cleanup = StartupCleanup::default();
+    for name in env.list_dir(&sst_dir)? {
+        let path = sst_dir.join(&name);
+        if let Some(id) = name.strip_suffix(".sst.tmp") {
+            if id.parse::<TableId>().is_ok() {
+                cleanup.temp_files.push(path);
+            }
+        } else if let Some(id) = name.strip_suffix(".sst") {
+            if id.parse::<TableId>().is_ok_and(|id| !live.contains(&id)) {
+                cleanup.orphaned_tables.push(path);
+            }
+        }
+    }
+    if cleanup.is_empty() {
+        return Ok(cleanup);
+    }
+    cleanup.temp_files.sort();
+    cleanup.orphaned_tables.sort();
+
+    let dest = data_dir.join("quarantine");
+    if quarantine {
+        env.create_dir_all(&dest)?;
+    }
+    for path in cleanup.temp_files.iter().chain(&cleanup.orphaned_tables) {
+        if quarantine {
+            env.rename(path, &dest.join(path.file_name().unwrap()))?;
+        } else {
+            env.remove_file(path)?;
+        }
+    }
+    // Syncing the directory of any one of the files covers all of them.
+    let moved = cleanup
+        .temp_files
+        .iter()
+        .chain(&cleanup.orphaned_tables)
+        .next()
+        .unwrap();
+    fsync_dir(env, moved)?;
+    if quarantine {
+        fsync_dir(env, &dest.join(moved.file_name().unwrap()))?;
+        cleanup.quarantine = Some(dest);
+    }
+    Ok(cleanup)
+}
+
+fn list_wal_numbers(env: &dyn Env, wal_dir: &Path) -> std::io::Result<Vec<u64>> {
+    let mut numbers: Vec<u64> = env
+        .list_dir(wal_dir)?
+        .iter()
+        .filter_map(|name| parse_wal_name(name))
+        .collect();
+    numbers.sort_unstable();
+    Ok(numbers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::env::RealEnv;
+    use crate::test_util::{self, tmp_dir};
+    use std::fs;
+
+    #[test]
+    fn unflushed_writes_survive_reopen() {
+        let dir = tmp_dir("wal-reopen");
+        {
+            let mut eng = LsmEngine::new_with_manifest(&dir, 64 * 1024, 4 * 1024).unwrap();
+            eng.put(b"a", b"1").unwrap();
+            eng.put(b"b", b"2").unwrap();
+            eng.delete(b"a").unwrap();
+        }
+        let eng = LsmEngine::new_with_manifest(&dir, 64 * 1024, 4 * 1024).unwrap();
+        assert_eq!(eng.get(b"a").unwrap(), None);
+        assert_eq!(eng.get(b"b").unwrap(), Some(b"2".to_vec()));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn batch_is_applied_as_a_unit() {
+        let dir = tmp_dir("batch");
+        {
+            let mut eng = LsmEngine::new_with_manifest(&dir, 64, 4 * 1024).unwrap();
+            let mut batch = WriteBatch::new();
+            batch
+                .put(b"obj", &[b'x'; 100])
+                .put(b"idx", b"obj")
+                .gset_add(b"tags", b"a".to_vec())
+                .gset_add(b"tags", b"b".to_vec())
+                .delete(b"idx");
+            eng.write(&batch).unwrap();
+            // The batch overflowed the memtable only after it was fully applied.
+            assert_eq!(eng.shared.lock().memtables.active_bytes(), 0);
+        }
+        let eng = LsmEngine::new_with_manifest(&dir, 64, 4 * 1024).unwrap();
+        assert_eq!(eng.get(b"obj").unwrap(), Some(vec![b'x'; 100]));
+        assert_eq!(eng.get(b"idx").unwrap(), None);
+        assert_eq!(
+            eng.gset_get(b"tags").unwrap(),
+            vec![b"a".to_vec(), b"b".to_vec()]
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn leveled_compaction_retires_tables() {
+        use crate::storage::compaction::CompactionOptions;
+        use std::collections::HashMap;
+
+        let dir = tmp_dir("leveled");
+        let opts = EngineOptions {
+            memtable_max_bytes: 512,
+            block_bytes: 128,
+            max_open_tables: 2,
+            sync_policy: SyncPolicy::Never,
+            compaction: CompactionOptions {
+                level_base_bytes: 4 * 1024,
+                target_file_bytes: 1024,
+                ..CompactionOptions::default()
+            },
+            ..EngineOptions::default()
+        };
+        let mut model = HashMap::new();
+        {
+            let mut eng = LsmEngine::open(&dir, opts.clone()).unwrap();
+            for i in 0..2000u32 {
+                let key = format!("k{:04}", i % 300).into_bytes();
+                if i % 7 == 0 {
+                    eng.delete(&key).unwrap();
+                    model.remove(&key);
+                } else {
+                    let value = format!("v{i}").into_bytes();
+                    eng.put(&key, &value).unwrap();
+                    model.insert(key, value);
+                }
+            }
+            eng.wait_for_background().unwrap();
+            let version = eng.current_version();
+            assert!(version.level(0).len() < opts.compaction.level0_file_trigger);
+            for level in 1..version.num_levels() {
+                let tables = version.level(level);
+                assert!(tables.windows(2).all(|w| w[0].largest < w[1].smallest));
+            }
+        }
+        let eng = LsmEngine::open(&dir, opts).unwrap();
+        for i in 0..300u32 {
+            let key = format!("k{i:04}").into_bytes();
+            assert_eq!(eng.get(&key).unwrap(), model.get(&key).cloned());
+        }
+        eng.wait_for_background().unwrap();
+        // Repeated lookups are served from the block cache.
+        let cache = eng.block_cache().unwrap();
+        let hits = cache.hits();
+        for i in 0..300u32 {
+            let key = format!("k{i:04}").into_bytes();
+            assert_eq!(eng.get(&key).unwrap(), model.get(&key).cloned());
+        }
+        assert!(cache.hits() > hits);
+        assert!(!cache.is_empty());
+        assert!(eng.table_cache().len() <= 2);
+        assert!(eng.table_cache().evictions() > 0);
+        let version = eng.current_version();
+        let live = fs::read_dir(dir.join("sst")).unwrap().count();
+        let tracked: usize = (0..version.num_levels())
+            .map(|l| version.level(l).len())
+            .sum();
+        assert_eq!(live, tracked);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn size_tiered_compaction_keeps_latest_values() {
+        use crate::storage::compaction::{CompactionOptions, CompactionStyle};
+        use std::collections::HashMap;
+
+        let dir = tmp_dir("size-tiered");
+        let opts = EngineOptions {
+            memtable_max_bytes: 512,
+            block_bytes: 128,
+            sync_policy: SyncPolicy::Never,
+            compaction: CompactionOptions {
+                style: CompactionStyle::SizeTiered,
+                ..CompactionOptions::default()
+            },
+            ..EngineOptions::default()
+        };
+        let mut model = HashMap::new();
+        {
+            let mut eng = LsmEngine::open(&dir, opts.clone()).unwrap();
+            assert_eq!(eng.compaction_strategy(), "size-tiered");
+            for i in 0..2000u32 {
+                let key = format!("k{:04}", i % 300).into_bytes();
+                if i % 5 == 0 {
+                    eng.delete(&key).unwrap();
+                    model.remove(&key);
+                } else {
+                    let value = format!("v{i}").into_bytes();
+                    eng.put(&key, &value).unwrap();
+                    model.insert(key, value);
+                }
+            }
+            eng.wait_for_background().unwrap();
+            let version = eng.current_version();
+            assert!(version.level(0).len() < opts.compaction.level0_file_trigger);
+        }
+        let eng = LsmEngine::open(&dir, opts).unwrap();
+        for i in 0..300u32 {
+            let key = format!("k{i:04}").into_bytes();
+            assert_eq!(eng.get(&key).unwrap(), model.get(&key).cloned());
+        }
+        eng.wait_for_background().unwrap();
+        let version = eng.current_version();
+        let live = fs::read_dir(dir.join("sst")).unwrap().count();
+        let tracked: usize = (0..version.num_levels())
+            .map(|l| version.level(l).len())
+            .sum();
+        assert_eq!(live, tracked);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn close_drains_frozen_memtables() {
+        let dir = tmp_dir("drain");
+        let opts = EngineOptions {
+            memtable_max_bytes: 256,
+            max_immutable_memtables: 8,
+            sync_policy: SyncPolicy::Never,
+            ..EngineOptions::default()
+        };
+        let mut eng = LsmEngine::open(&dir, opts.clone()).unwrap();
+        for i in 0..500u32 {
+            let key = format!("k{:03}", i % 100).into_bytes();
+            eng.put(&key, format!("v{i}").as_bytes()).unwrap();
+            // Frozen memtables stay readable until their table is installed.
+            assert_eq!(eng.get(&key).unwrap(), Some(format!("v{i}").into_bytes()));
+        }
+        eng.close().unwrap();
+        assert!(eng.put(b"late", b"x").is_err());
+        assert_eq!(eng.shared.lock().memtables.immutables_len(), 0);
+        // Only the active memtable's log is left to replay.
+        assert_eq!(
+            list_wal_numbers(&RealEnv, &dir.join("wal")).unwrap().len(),
+            1
+        );
+        drop(eng);
+
+        let eng = LsmEngine::open(&dir, opts).unwrap();
+        for i in 400..500u32 {
+            let key = format!("k{:03}", i % 100).into_bytes();
+            assert_eq!(eng.get(&key).unwrap(), Some(format!("v{i}").into_bytes()));
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn scan_merges_memtables_and_tables() {
+        let dir = tmp_dir("scan");
+        let mut eng = LsmEngine::new_with_manifest(&dir, 64 * 1024, 64).unwrap();
+        for i in 0..50u32 {
+            eng.put(format!("k{i:02}").as_bytes(), b"old").unwrap();
+        }
+        eng.flush().unwrap();
+        for i in (0..50u32).step_by(2) {
+            eng.put(format!("k{i:02}").as_bytes(), b"new").unwrap();
+        }
+        eng.flush().unwrap();
+        for i in (0..50u32).step_by(3) {
+            eng.delete(format!("k{i:02}").as_bytes()).unwrap();
+        }
+        eng.put(b"k12", b"mem").unwrap();
+
+        let got: Vec<_> = eng
+            .scan(b"k05".as_slice()..b"k15")
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        let want: Vec<_> = (5..15u32)
+            .filter(|i| i % 3 != 0 || *i == 12)
+            .map(|i| {
+                let value: &[u8] = match i {
+                    12 => b"mem",
+                    _ if i % 2 == 0 => b"new",
+                    _ => b"old",
+                };
+                (format!("k{i:02}").into_bytes(), value.to_vec())
+            })
+            .collect();
+        assert_eq!(got, want);
+
+        assert_eq!(eng.scan::<&[u8]>(..).unwrap().count(), 50 - 17 + 1);
+        assert_eq!(eng.scan(b"k20".as_slice()..b"k10").unwrap().count(), 0);
+
+        let mut rev: Vec<_> = eng
+            .scan_rev(b"k05".as_slice()..b"k15")
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        rev.reverse();
+        assert_eq!(rev, want);
+        let newest: Vec<_> = eng
+            .scan_rev::<&[u8]>(..)
+            .unwrap()
+            .take(3)
+            .map(|r| r.unwrap().0)
+            .collect();
+        assert_eq!(newest, [b"k49".to_vec(), b"k47".to_vec(), b"k46".to_vec()]);
+        assert_eq!(eng.scan_rev(b"k20".as_slice()..b"k10").unwrap().count(), 0);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn scans_read_the_memtables_they_pinned_while_writes_go_on() {
+        let dir = tmp_dir("scan-pinned");
+        let mut eng = LsmEngine::new_with_manifest(&dir, 64 * 1024, 64).unwrap();
+        for key in [b"a", b"c", b"e"] {
+            eng.put(key, b"old").unwrap();
+        }
+        let mut fwd = eng.scan::<&[u8]>(..).unwrap();
+        let mut rev = eng.scan_rev::<&[u8]>(..).unwrap();
+        assert_eq!(fwd.next().unwrap().unwrap().0, b"a");
+        assert_eq!(rev.next().unwrap().unwrap().0, b"e");
+
+        for key in [b"b", b"c", b"d"] {
+            eng.put(key, b"new").unwrap();
+        }
+        eng.delete(b"e").unwrap();
+        // The active memtable the scans hold moves on to a table.
+        eng.flush().unwrap();
+
+        let rest: Vec<_> = fwd.map(|r| r.unwrap()).collect();
+        assert_eq!(
+            rest,
+            [
+                (b"c".to_vec(), b"old".to_vec()),
+                (b"e".to_vec(), b"old".to_vec())
+            ]
+        );
+        let rest: Vec<_> = rev.map(|r| r.unwrap().0).collect();
+        assert_eq!(rest, [b"c".to_vec(), b"a".to_vec()]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn scans_keep_compacted_tables_until_dropped() {
+        use crate::storage::env::MemEnv;
+
+        let env = Arc::new(MemEnv::new());
+        let mut eng = LsmEngine::open("/db", test_util::options(&env, 2)).unwrap();
+        let tables = |env: &MemEnv| env.list_dir(Path::new("/db/sst")).unwrap().len();
+        eng.put(b"a", b"1").unwrap();
+        eng.flush().unwrap();
+        let mut scan = eng.scan::<&[u8]>(..).unwrap();
+        eng.put(b"b", b"2").unwrap();
+        eng.flush().unwrap();
+        eng.wait_for_background().unwrap();
+        assert!(eng.tables().iter().all(|t| t.level > 0));
+
+        // Both inputs are retired, but the scan's one stays until it's done.
+        assert_eq!(tables(&env), eng.tables().len() + 1);
+        assert_eq!(
+            scan.next().unwrap().unwrap(),
+            (b"a".to_vec(), b"1".to_vec())
+        );
+        assert!(scan.next().is_none());
+        drop(scan);
+        assert_eq!(tables(&env), eng.tables().len());
+    }
+
+    #[test]
+    fn key_ranges_prune_lookups_and_survive_reopen() {
+        let dir = tmp_dir("key-ranges");
+        let ranges = {
+            let mut eng = LsmEngine::new_with_manifest(&dir, 64 * 1024, 64).unwrap();
+            eng.put(b"a", b"1").unwrap();
+            eng.put(b"c", b"2").unwrap();
+            eng.flush().unwrap();
+            eng.put(b"x", b"3").unwrap();
+            eng.put(b"z", b"4").unwrap();
+            eng.flush().unwrap();
+            let tables = eng.tables();
+            let ranges: Vec<_> = tables
+                .iter()
+                .map(|t| (t.level, t.smallest.clone(), t.largest.clone()))
+                .collect();
+            assert_eq!(
+                ranges,
+                vec![
+                    (0, b"a".to_vec(), b"c".to_vec()),
+                    (0, b"x".to_vec(), b"z".to_vec()),
+                ]
+            );
+            assert!(tables.iter().all(|t| t.file_size > 0));
+            tables
+        };
+
+        let eng = LsmEngine::new_with_manifest(&dir, 64 * 1024, 64).unwrap();
+        assert_eq!(eng.tables(), ranges);
+        let stats = eng.filter_stats();
+        let probes = || stats.hits() + stats.misses();
+        assert_eq!(eng.get(b"m").unwrap(), None);
+        assert_eq!(probes(), 0);
+        assert_eq!(eng.get(b"c").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(probes(), 1);
+        assert_eq!(eng.scan(b"d".as_slice()..b"w").unwrap().count(), 0);
+        assert_eq!(eng.scan(b"b".as_slice()..=b"x").unwrap().count(), 2);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn open_clears_out_orphaned_table_files() {
+        let dir = tmp_dir("orphans");
+        let sst = dir.join("sst");
+        let plant = || {
+            fs::write(sst.join("000900.sst.tmp"), b"half a table").unwrap();
+            fs::write(sst.join("000901.sst"), b"compacted away").unwrap();
+        };
+        {
+            let mut eng = LsmEngine::new_with_manifest(&dir, 64 * 1024, 64).unwrap();
+            eng.put(b"k", b"v").unwrap();
+            eng.flush().unwrap();
+            assert!(eng.startup_cleanup().is_empty());
+        }
+        plant();
+        fs::write(sst.join("README"), b"not ours").unwrap();
+
+        let eng = LsmEngine::new_with_manifest(&dir, 64 * 1024, 64).unwrap();
+        let cleanup = eng.startup_cleanup().clone();
+        assert_eq!(cleanup.temp_files, vec![sst.join("000900.sst.tmp")]);
+        assert_eq!(cleanup.orphaned_tables, vec![sst.join("000901.sst")]);
+        assert_eq!(cleanup.quarantine, None);
+        assert!(!sst.join("000900.sst.tmp").exists() && !sst.join("000901.sst").exists());
+        assert!(sst.join("README").exists());
+        assert_eq!(eng.get(b"k").unwrap(), Some(b"v".to_vec()));
+        drop(eng);
+
+        plant();
+        let opts = EngineOptions {
+            quarantine_orphans: true,
+            ..EngineOptions::default()
+        };
+        let eng = LsmEngine::open(&dir, opts).unwrap();
+        let quarantine = dir.join("quarantine");
+        assert_eq!(eng.startup_cleanup().quarantine, Some(quarantine.clone()));
+        assert!(quarantine.join("000900.sst.tmp").exists());
+        assert!(quarantine.join("000901.sst").exists());
+        assert_eq!(eng.get(b"k").unwrap(), Some(b"v".to_vec()));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn snapshots_survive_overwrites_and_compaction() {
+        let dir = tmp_dir("snapshot");
+        let opts = EngineOptions {
+            sync_policy: SyncPolicy::Never,
+            ..EngineOptions::default()
+        };
+        let mut eng = LsmEngine::open(&dir, opts).unwrap();
+        eng.put(b"a", b"1").unwrap();
+        eng.put(b"b", b"1").unwrap();
+        let snap = eng.snapshot();
+        assert_eq!(snap.sequence(), 2);
+
+        eng.put(b"a", b"2").unwrap();
+        eng.delete(b"b").unwrap();
+        eng.put(b"c", b"1").unwrap();
+        let pairs = |scan: Scan| scan.map(|r| r.unwrap()).collect::<Vec<_>>();
+        let old = vec![
+            (b"a".to_vec(), b"1".to_vec()),
+            (b"b".to_vec(), b"1".to_vec()),
+        ];
+        assert_eq!(pairs(snap.scan::<&[u8]>(..).unwrap()), old);
+        assert_eq!(eng.get(b"b").unwrap(), None);
+
+        // Enough flushes to compact level 0 into level 1 underneath the snapshot.
+        let versions_of_a = |eng: &LsmEngine| -> usize {
+            let version = eng.current_version();
+            let mut n = 0;
+            for table in version.tables_newest_first() {
+                let mut it = SsTableIter::new(table.reader().unwrap());
+                it.seek(b"a").unwrap();
+                while it.valid() && it.key() == b"a" {
+                    n += 1;
+                    it.next().unwrap();
+                }
+            }
+            n
+        };
+        for i in 0..4u32 {
+            eng.put(b"a", format!("x{i}").as_bytes()).unwrap();
+            eng.flush().unwrap();
+        }
+        eng.wait_for_background().unwrap();
+        assert!(eng.current_version().level(0).is_empty());
+        assert_eq!(snap.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(snap.get(b"b").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(snap.get(b"c").unwrap(), None);
+        let mut rev = pairs(snap.scan_rev::<&[u8]>(..).unwrap());
+        rev.reverse();
+        assert_eq!(rev, old);
+        assert_eq!(eng.get(b"a").unwrap(), Some(b"x3".to_vec()));
+        assert_eq!(versions_of_a(&eng), 2);
+
+        // Once the snapshot is gone the next compaction drops what only it saw.
+        drop(snap);
+        for _ in 0..4 {
+            eng.put(b"a", b"y").unwrap();
+            eng.put(b"d", b"1").unwrap();
+            eng.flush().unwrap();
+        }
+        eng.wait_for_background().unwrap();
+        assert_eq!(versions_of_a(&eng), 1);
+        assert_eq!(eng.scan::<&[u8]>(..).unwrap().count(), 3);
+        let last = eng.last_sequence();
+        drop(eng);
+
+        let opts = EngineOptions::default();
+        let eng = LsmEngine::open(&dir, opts).unwrap();
+        assert_eq!(eng.last_sequence(), last);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn expired_keys_read_as_absent_and_compact_away() {
+        let dir = tmp_dir("ttl");
+        let opts = EngineOptions {
+            sync_policy: SyncPolicy::Never,
+            ..EngineOptions::default()
+        };
+        let hour = Duration::from_secs(3600);
+        {
+            let mut eng = LsmEngine::open(&dir, opts.clone()).unwrap();
+            eng.put_with_ttl(b"a", b"1", Duration::ZERO).unwrap();
+            eng.put(b"b", b"old").unwrap();
+            eng.flush().unwrap();
+            // An expired value hides older versions, like a tombstone.
+            eng.put_with_ttl(b"b", b"new", Duration::ZERO).unwrap();
+            eng.put_with_ttl(b"c", b"1", hour).unwrap();
+            assert_eq!(eng.get(b"a").unwrap(), None);
+            assert_eq!(eng.get(b"b").unwrap(), None);
+            assert_eq!(eng.get(b"c").unwrap(), Some(b"1".to_vec()));
+            let keys: Vec<_> = eng
+                .scan::<&[u8]>(..)
+                .unwrap()
+                .map(|r| r.unwrap().0)
+                .collect();
+            assert_eq!(keys, [b"c".to_vec()]);
+
+            // With the flush above, enough level-0 tables to compact.
+            for i in 0..3u32 {
+                eng.put(b"d", format!("{i}").as_bytes()).unwrap();
+                eng.flush().unwrap();
+            }
+            eng.wait_for_background().unwrap();
+            let version = eng.current_version();
+            assert!(version.level(0).is_empty());
+            let mut stored = Vec::new();
+            for table in version.tables_newest_first() {
+                let mut it = SsTableIter::new(table.reader().unwrap());
+                it.seek_to_first().unwrap();
+                while it.valid() {
+                    stored.push(it.key().to_vec());
+                    it.next().unwrap();
+                }
+            }
+            assert_eq!(stored, [b"c".to_vec(), b"d".to_vec()]);
+            eng.put_with_ttl(b"e", b"1", hour).unwrap();
+        }
+        // Expiry times survive both the log and the tables.
+        let eng = LsmEngine::open(&dir, opts).unwrap();
+        assert_eq!(eng.get(b"c").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(eng.get(b"e").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(eng.get(b"b").unwrap(), None);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn group_commit_syncs_once_per_group() {
+        use crate::storage::env::{FaultEnv, MemEnv};
+
+        let env = Arc::new(FaultEnv::new(Arc::new(MemEnv::new())));
+        let opts = EngineOptions {
+            sync_policy: SyncPolicy::Always,
+            env: env.clone(),
+            ..EngineOptions::default()
+        };
+        let mut eng = LsmEngine::open("/db", opts).unwrap();
+        let before = env.file_syncs();
+        assert!(eng.put(b"a", b"1").unwrap().synced);
+        assert_eq!(env.file_syncs(), before + 1);
+
+        let (acks, group) = eng.group_commit(|eng| {
+            [
+                eng.put(b"b", b"2").unwrap(),
+                eng.delete(b"a").unwrap(),
+                eng.put(b"c", b"3").unwrap(),
+            ]
+        });
+        assert!(acks.iter().all(|a| !a.synced));
+        assert!(group.unwrap().synced);
+        assert_eq!(env.file_syncs(), before + 2);
+        assert_eq!(eng.log().unsynced_bytes(), 0);
+
+        // A panicking group still ends, and the writes after it sync again.
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            eng.write_group(|eng| {
+                eng.put(b"d", b"4").unwrap();
+                panic!("group failed");
+            })
+        }));
+        assert!(panicked.is_err());
+        assert!(eng.put(b"e", b"5").unwrap().synced);
+        assert_eq!(env.file_syncs(), before + 3);
+    }
+
+    #[test]
+    fn checkpoint_opens_as_a_point_in_time_copy() {
+        let dir = tmp_dir("checkpoint-src");
+        let dest = tmp_dir("checkpoint-dest");
+        let mut eng = LsmEngine::new_with_manifest(&dir, 256, 128).unwrap();
+        for i in 0..50u32 {
+            eng.put(format!("k{i:02}").as_bytes(), b"old").unwrap();
+        }
+        eng.delete(b"k07").unwrap();
+        // Still in the memtable when the checkpoint is taken.
+        eng.put(b"unflushed", b"1").unwrap();
+        eng.checkpoint(&dest).unwrap();
+        assert!(eng.checkpoint(&dest).is_err());
+
+        for i in 0..50u32 {
+            eng.put(format!("k{i:02}").as_bytes(), b"new").unwrap();
+        }
+        eng.flush().unwrap();
+        eng.wait_for_background().unwrap();
+
+        let mut copy = LsmEngine::new_with_manifest(&dest, 256, 128).unwrap();
+        assert_eq!(copy.get(b"k00").unwrap(), Some(b"old".to_vec()));
+        assert_eq!(copy.get(b"k07").unwrap(), None);
+        assert_eq!(copy.get(b"unflushed").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(copy.last_sequence(), 52);
+        // The copy moves on independently of the engine it was taken from.
+        copy.put(b"k01", b"copy").unwrap();
+        copy.flush().unwrap();
+        assert_eq!(eng.get(b"k01").unwrap(), Some(b"new".to_vec()));
+        drop(eng);
+        drop(copy);
+        let _ = fs::remove_dir_all(&dir);
+        let _ = fs::remove_dir_all(&dest);
     }
 }
@@ -0,0 +1,417 @@
+//! Built-in [`MergeOperator`]s.
+
+use crate::engine::crdt::{CrdtKind, GSet, CRDT, CRDT_KEY_PREFIX};
+pub use crate::storage::merge::MergeOperator;
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+
+/// Operands are CRDT states tagged with their [`CrdtKind`], and are merged
+/// into the value with [`CRDT::merge`]; the merged value is tagged too. The
+/// engine merges the state its G-Set and RGA ops write with this, whatever
+/// its own operator is.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CrdtMerge;
+
+impl MergeOperator for CrdtMerge {
+    fn name(&self) -> &'static str {
+        "crdt"
+    }
+
+    fn full_merge(
+        &self,
+        _key: &[u8],
+        existing: Option<&[u8]>,
+        operands: &[&[u8]],
+    ) -> std::io::Result<Vec<u8>> {
+        let mut merged: Option<(CrdtKind, Vec<u8>)> = None;
+        for operand in operands {
+            let (kind, other) = CrdtKind::split(operand)?;
+            let state = match (&merged, existing) {
+                (Some((merged_kind, state)), _) => {
+                    merged_kind.expect(kind)?;
+                    Some(state.as_slice())
+                }
+                (None, Some(existing)) => {
+                    let (existing_kind, state) = CrdtKind::split(existing)?;
+                    existing_kind.expect(kind)?;
+                    Some(state)
+                }
+                (None, None) => None,
+            };
+            merged = Some((kind, kind.merge_encoded(state, other)?));
+        }
+        match merged {
+            Some((kind, state)) => Ok(kind.tag_state(&state)),
+            None => Ok(existing.unwrap_or_default().to_vec()),
+        }
+    }
+
+    fn partial_merge(&self, _key: &[u8], older: &[u8], newer: &[u8]) -> Option<Vec<u8>> {
+        let (kind, older) = CrdtKind::split(older).ok()?;
+        let (newer_kind, newer) = CrdtKind::split(newer).ok()?;
+        kind.expect(newer_kind).ok()?;
+        let merged = kind.merge_encoded(Some(older), newer).ok()?;
+        Some(kind.tag_state(&merged))
+    }
+
+    fn check_operand(&self, _key: &[u8], operand: &[u8]) -> std::io::Result<()> {
+        let invalid = |e: Error| Error::new(ErrorKind::InvalidInput, e.to_string());
+        let (kind, state) = CrdtKind::split(operand).map_err(invalid)?;
+        kind.check(state).map_err(invalid)
+    }
+}
+
+/// What the engine merges with: [`CrdtMerge`] for the CRDT state kept under
+/// [`CRDT_KEY_PREFIX`], and the configured operator for every other key.
+#[derive(Debug)]
+pub(crate) struct EngineMerge {
+    pub(crate) user: Arc<dyn MergeOperator>,
+}
+
+impl EngineMerge {
+    fn pick(&self, key: &[u8]) -> &dyn MergeOperator {
+        if key.starts_with(CRDT_KEY_PREFIX) {
+            &CrdtMerge
+        } else {
+            self.user.as_ref()
+        }
+    }
+}
+
+impl MergeOperator for EngineMerge {
+    fn name(&self) -> &'static str {
+        self.user.name()
+    }
+
+    fn full_merge(
+        &self,
+        key: &[u8],
+        existing: Option<&[u8]>,
+        operands: &[&[u8]],
+    ) -> std::io::Result<Vec<u8>> {
+        self.pick(key).full_merge(key, existing, operands)
+    }
+
+    fn partial_merge(&self, key: &[u8], older: &[u8], newer: &[u8]) -> Option<Vec<u8>> {
+        self.pick(key).partial_merge(key, older, newer)
+    }
+
+    fn check_operand(&self, key: &[u8], operand: &[u8]) -> std::io::Result<()> {
+        self.pick(key).check_operand(key, operand)
+    }
+}
+
+/// Values and operands are encoded [`GSet`]s, merged by union. This is the
+/// default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct GSetUnion;
+
+impl MergeOperator for GSetUnion {
+    fn name(&self) -> &'static str {
+        "gset"
+    }
+
+    fn full_merge(
+        &self,
+        _key: &[u8],
+        existing: Option<&[u8]>,
+        operands: &[&[u8]],
+    ) -> std::io::Result<Vec<u8>> {
+        let mut set = existing.map_or_else(GSet::new, GSet::from_bytes);
+        for operand in operands {
+            set.merge(&GSet::from_bytes(operand));
+        }
+        Ok(set.to_bytes())
+    }
+
+    fn partial_merge(&self, key: &[u8], older: &[u8], newer: &[u8]) -> Option<Vec<u8>> {
+        self.full_merge(key, Some(older), &[newer]).ok()
+    }
+}
+
+/// Values and operands are little-endian `i64`s, and operands are added to
+/// the value. Anything that isn't eight bytes counts as zero.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Counter;
+
+impl Counter {
+    pub fn encode(n: i64) -> [u8; 8] {
+        n.to_le_bytes()
+    }
+
+    pub fn decode(bytes: &[u8]) -> i64 {
+        bytes.try_into().map_or(0, i64::from_le_bytes)
+    }
+}
+
+impl MergeOperator for Counter {
+    fn name(&self) -> &'static str {
+        "counter"
+    }
+
+    fn full_merge(
+        &self,
+        _key: &[u8],
+        existing: Option<&[u8]>,
+        operands: &[&[u8]],
+    ) -> std::io::Result<Vec<u8>> {
+        let total = operands
+            .iter()
+            .fold(existing.map_or(0, Counter::decode), |n, o| {
+                n.wrapping_add(Counter::decode(o))
+            });
+        Ok(Counter::encode(total).to_vec())
+    }
+
+    fn partial_merge(&self, key: &[u8], older: &[u8], newer: &[u8]) -> Option<Vec<u8>> {
+        self.full_merge(key, Some(older), &[newer]).ok()
+    }
+}
+
+/// Values and operands are lists of byte strings, each item prefixed with
+/// its length as a big-endian `u32`, and operands are appended to the value.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AppendList;
+
+impl AppendList {
+    pub fn encode<I: AsRef<[u8]>>(items: impl IntoIterator<Item = I>) -> Vec<u8> {
+        let mut out = Vec::new();
+        for item in items {
+            let item = item.as_ref();
+            out.extend(&(item.len() as u32).to_be_bytes());
+            out.extend(item);
+        }
+        out
+    }
+
+    /// The items of `bytes`, stopping at the first one that is cut short.
+    pub fn decode(mut bytes: &[u8]) -> Vec<Vec<u8>> {
+        let mut items = Vec::new();
+        while let Some((len, rest)) = bytes.split_first_chunk::<4>() {
+            let len = u32::from_be_bytes(*len) as usize;
+            if rest.len() < len {
+                break;
+            }
+            items.push(rest[..len].to_vec());
+            bytes = &rest[len..];
+        }
+        items
+    }
+}
+
+impl MergeOperator for AppendList {
+    fn name(&self) -> &'static str {
+        "append-list"
+    }
+
+    fn full_merge(
+        &self,
+        _key: &[u8],
+        existing: Option<&[u8]>,
+        operands: &[&[u8]],
+    ) -> std::io::Result<Vec<u8>> {
+        // The encoding concatenates, so appending is just that.
+        let mut out = existing.unwrap_or_default().to_vec();
+        for operand in operands {
+            out.extend_from_slice(operand);
+        }
+        Ok(out)
+    }
+
+    fn partial_merge(&self, _key: &[u8], older: &[u8], newer: &[u8]) -> Option<Vec<u8>> {
+        Some([older, newer].concat())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::kv::LsmEngine;
+    use crate::engine::options::EngineOptions;
+    use crate::storage::env::MemEnv;
+    use crate::test_util;
+    use std::sync::Arc;
+
+    fn options(env: &Arc<MemEnv>, merge_operator: Arc<dyn MergeOperator>) -> EngineOptions {
+        EngineOptions {
+            merge_operator,
+            ..test_util::options(env, 2)
+        }
+    }
+
+    fn error_kind<T>(r: std::io::Result<T>) -> ErrorKind {
+        r.map(drop).unwrap_err().kind()
+    }
+
+    fn count(eng: &LsmEngine, key: &[u8]) -> Option<i64> {
+        eng.get(key).unwrap().map(|v| Counter::decode(&v))
+    }
+
+    #[test]
+    fn crdt_keys_merge_every_version_instead_of_keeping_the_newest() {
+        use crate::engine::crdt::{ElementId, Rga};
+
+        let env = Arc::new(MemEnv::new());
+        let mut eng = LsmEngine::open("/db", options(&env, Arc::new(GSetUnion))).unwrap();
+        let (a, b, c) = (
+            ElementId::new(1, 1),
+            ElementId::new(1, 2),
+            ElementId::new(2, 1),
+        );
+        // A state put as a plain value is taken in by the first CRDT op.
+        let mut base = Rga::new();
+        base.insert(a, None, b"A".to_vec());
+        eng.put(b"doc", &base.to_bytes()).unwrap();
+        eng.gset_add(b"tags".to_vec(), b"x".to_vec()).unwrap();
+        eng.flush().unwrap();
+        let snap = eng.snapshot();
+
+        eng.rga_insert_after(b"doc", Some(a), b"B".to_vec(), 1, 2)
+            .unwrap();
+        // Deleted before its insert shows up.
+        eng.rga_delete(b"doc", c).unwrap();
+        eng.flush().unwrap();
+        eng.wait_for_background().unwrap();
+        assert!(eng.tables().iter().all(|t| t.level > 0));
+
+        let mut remote = Rga::new();
+        remote.insert(c, Some(b), b"C".to_vec());
+        remote.insert(ElementId::new(2, 2), Some(c), b"D".to_vec());
+        eng.merge_crdt(b"doc", &remote).unwrap();
+        let mut tags = GSet::new();
+        tags.insert(b"y".to_vec());
+        eng.merge_crdt(b"tags", &tags).unwrap();
+
+        let visible = eng.rga_get_visible(b"doc").unwrap();
+        assert_eq!(visible, [b"A", b"B", b"D"]);
+        assert_eq!(eng.gset_get(b"tags").unwrap(), [b"x", b"y"]);
+        let at_snap = Rga::decode(&snap.get(b"doc").unwrap().unwrap()).unwrap();
+        assert_eq!(at_snap.visible_sequence(), [b"A"]);
+        drop(snap);
+        let state = Rga::decode(&eng.get(b"doc").unwrap().unwrap()).unwrap();
+        assert_eq!(state.visible_sequence(), visible);
+
+        drop(eng);
+        let eng = LsmEngine::open("/db", options(&env, Arc::new(GSetUnion))).unwrap();
+        assert_eq!(eng.rga_get_visible(b"doc").unwrap(), visible);
+    }
+
+    #[test]
+    fn crdt_keys_only_take_crdt_ops_and_kinds_never_mix() {
+        use crate::engine::crdt::CRDT_KEY_PREFIX;
+
+        let env = Arc::new(MemEnv::new());
+        let mut eng = LsmEngine::open("/db", options(&env, Arc::new(GSetUnion))).unwrap();
+        // G-Sets put as plain values, in a table and the memtable, are
+        // unioned.
+        for elem in ["x", "y"] {
+            let mut set = GSet::new();
+            set.insert(elem.as_bytes().to_vec());
+            eng.flush().unwrap();
+            eng.put(b"tags", &set.to_bytes()).unwrap();
+        }
+        assert_eq!(eng.gset_get(b"tags").unwrap(), [b"x", b"y"]);
+        eng.gset_add(b"tags".to_vec(), b"z".to_vec()).unwrap();
+        eng.flush().unwrap();
+        assert_eq!(eng.gset_get(b"tags").unwrap(), [b"x", b"y", b"z"]);
+        let state = GSet::decode(&eng.get(b"tags").unwrap().unwrap()).unwrap();
+        assert_eq!(state.elements(), [b"x", b"y", b"z"]);
+
+        let set = GSet::new().to_bytes();
+        assert_eq!(error_kind(eng.put(b"tags", &set)), ErrorKind::InvalidInput);
+        assert_eq!(error_kind(eng.delete(b"tags")), ErrorKind::InvalidInput);
+        assert_eq!(
+            error_kind(eng.merge(b"tags", &set)),
+            ErrorKind::InvalidInput
+        );
+        let mut batch = crate::engine::batch::WriteBatch::new();
+        batch.put(b"a", b"1").gset_add(b"a", b"x".to_vec());
+        assert_eq!(error_kind(eng.write(&batch)), ErrorKind::InvalidInput);
+        assert_eq!(eng.get(b"a").unwrap(), None);
+        let reserved = [CRDT_KEY_PREFIX, b"tags"].concat();
+        assert_eq!(
+            error_kind(eng.put(&reserved, b"1")),
+            ErrorKind::InvalidInput
+        );
+        assert_eq!(error_kind(eng.get(&reserved)), ErrorKind::InvalidInput);
+        assert_eq!(eng.scan::<&[u8]>(..).unwrap().count(), 0);
+
+        drop(eng);
+
+        let mut eng = LsmEngine::open("/mixed", options(&env, Arc::new(GSetUnion))).unwrap();
+        for _ in 0..2 {
+            eng.gset_add(b"mixed".to_vec(), b"x".to_vec()).unwrap();
+            eng.rga_insert_after(b"mixed", None, b"A".to_vec(), 1, 1)
+                .unwrap();
+            assert_eq!(error_kind(eng.get(b"mixed")), ErrorKind::InvalidData);
+            assert_eq!(error_kind(eng.gset_get(b"mixed")), ErrorKind::InvalidData);
+            // Flushing and compacting keep the operands for reads to report.
+            eng.flush().unwrap();
+            eng.wait_for_background().unwrap();
+        }
+        assert_eq!(
+            error_kind(eng.rga_get_visible(b"mixed")),
+            ErrorKind::InvalidData
+        );
+        assert!(eng.tables().iter().all(|t| t.level > 0));
+    }
+
+    #[test]
+    fn counter_folds_operands_across_flushes_compactions_and_reopens() {
+        let env = Arc::new(MemEnv::new());
+        let mut eng = LsmEngine::open("/db", options(&env, Arc::new(Counter))).unwrap();
+        eng.merge(b"hits", &Counter::encode(2)).unwrap();
+        eng.flush().unwrap();
+        let snap = eng.snapshot();
+        eng.merge(b"hits", &Counter::encode(3)).unwrap();
+        eng.put(b"reset", &Counter::encode(100)).unwrap();
+        eng.merge(b"reset", &Counter::encode(-1)).unwrap();
+        assert_eq!(count(&eng, b"hits"), Some(5));
+        assert_eq!(count(&eng, b"reset"), Some(99));
+
+        eng.flush().unwrap();
+        eng.wait_for_background().unwrap();
+        assert!(eng.tables().iter().all(|t| t.level > 0));
+        assert_eq!(count(&eng, b"hits"), Some(5));
+        assert_eq!(
+            snap.get(b"hits").unwrap().map(|v| Counter::decode(&v)),
+            Some(2)
+        );
+        drop(snap);
+
+        eng.delete(b"hits").unwrap();
+        eng.merge(b"hits", &Counter::encode(7)).unwrap();
+        drop(eng);
+        let eng = LsmEngine::open("/db", options(&env, Arc::new(Counter))).unwrap();
+        assert_eq!(count(&eng, b"hits"), Some(7));
+        let scanned: Vec<_> = eng
+            .scan::<&[u8]>(..)
+            .unwrap()
+            .map(|kv| kv.map(|(k, v)| (k, Counter::decode(&v))))
+            .collect::<std::io::Result<_>>()
+            .unwrap();
+        assert_eq!(scanned, [(b"hits".to_vec(), 7), (b"reset".to_vec(), 99)]);
+    }
+
+    #[test]
+    fn append_list_keeps_operands_in_write_order() {
+        let env = Arc::new(MemEnv::new());
+        let mut eng = LsmEngine::open("/db", options(&env, Arc::new(AppendList))).unwrap();
+        let mut batch = crate::engine::batch::WriteBatch::new();
+        batch
+            .merge(b"log", &AppendList::encode(["a"]))
+            .merge(b"log", &AppendList::encode(["b", "c"]));
+        eng.write(&batch).unwrap();
+        eng.flush().unwrap();
+        eng.merge(b"log", &AppendList::encode(["d"])).unwrap();
+        let items = AppendList::decode(&eng.get(b"log").unwrap().unwrap());
+        assert_eq!(items, [b"a", b"b", b"c", b"d"]);
+
+        // CRDT ops merge their own way, and scans leave them out.
+        eng.gset_add(b"tags".to_vec(), b"x".to_vec()).unwrap();
+        assert_eq!(eng.gset_get(b"tags").unwrap(), [b"x"]);
+        let rev: Vec<_> = eng.scan_rev::<&[u8]>(..).unwrap().collect();
+        assert_eq!(rev.len(), 1);
+        assert_eq!(AppendList::decode(&rev[0].as_ref().unwrap().1), items);
+    }
+}
@@ -1,2 +1,11 @@
+pub mod backup;
+pub mod batch;
 pub mod crdt;
 pub mod kv;
+pub mod merge;
+pub mod options;
+pub mod repair;
+pub mod scan;
+
+#[cfg(test)]
+mod crash_tests;
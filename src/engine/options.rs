@@ -0,0 +1,73 @@
+use crate::engine::merge::GSetUnion;
+use crate::storage::compaction::CompactionOptions;
+use crate::storage::env::{Env, RealEnv};
+use crate::storage::merge::MergeOperator;
+use crate::storage::sstable::compression::Compression;
+use crate::storage::sstable::TableOptions;
+use crate::storage::wal::SyncPolicy;
+use std::sync::Arc;
+
+/// Tunables for opening an [`LsmEngine`](crate::engine::kv::LsmEngine).
+#[derive(Clone, Debug)]
+pub struct EngineOptions {
+    /// Size at which the active memtable is frozen and flushed.
+    pub memtable_max_bytes: usize,
+    /// Writers stall while this many frozen memtables are waiting to be flushed.
+    pub max_immutable_memtables: usize,
+    /// Target size of an SSTable data block.
+    pub block_bytes: usize,
+    /// Bloom filter bits per key in new tables; `0` disables filters.
+    pub bloom_bits_per_key: usize,
+    /// Codec new tables compress their data blocks with.
+    pub compression: Compression,
+    /// Capacity of the block cache shared by all tables; `0` disables it.
+    pub block_cache_bytes: usize,
+    /// Most tables held open at once; others are reopened when next read.
+    pub max_open_tables: usize,
+    /// When log appends are fsynced.
+    pub sync_policy: SyncPolicy,
+    /// Once the manifest grows past this it is rewritten as a snapshot of
+    /// the live tables.
+    pub max_manifest_bytes: u64,
+    /// Move files in `sst/` that no table in the manifest owns to
+    /// `quarantine/` on open, rather than deleting them.
+    pub quarantine_orphans: bool,
+    pub compaction: CompactionOptions,
+    /// Filesystem the engine keeps its files in.
+    pub env: Arc<dyn Env>,
+    /// Combines the operands written by
+    /// [`LsmEngine::merge`](crate::engine::kv::LsmEngine::merge). The G-Set
+    /// and RGA ops keep their state apart and merge it with
+    /// [`CrdtMerge`](crate::engine::merge::CrdtMerge) whatever this is.
+    pub merge_operator: Arc<dyn MergeOperator>,
+}
+
+impl Default for EngineOptions {
+    fn default() -> Self {
+        Self {
+            memtable_max_bytes: 64 * 1024,
+            max_immutable_memtables: 2,
+            block_bytes: 8 * 1024,
+            bloom_bits_per_key: 10,
+            compression: Compression::default(),
+            block_cache_bytes: 8 * 1024 * 1024,
+            max_open_tables: 1000,
+            sync_policy: SyncPolicy::Always,
+            max_manifest_bytes: 4 * 1024 * 1024,
+            quarantine_orphans: false,
+            compaction: CompactionOptions::default(),
+            env: Arc::new(RealEnv),
+            merge_operator: Arc::new(GSetUnion),
+        }
+    }
+}
+
+impl EngineOptions {
+    pub fn table_options(&self) -> TableOptions {
+        TableOptions {
+            block_bytes: self.block_bytes,
+            bloom_bits_per_key: self.bloom_bits_per_key,
+            compression: self.compression,
+        }
+    }
+}
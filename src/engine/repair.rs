@@ -0,0 +1,375 @@
+use crate::engine::kv::{LsmEngine, TableInfo};
+use crate::engine::options::EngineOptions;
+use crate::storage::iter::EntryIter;
+use crate::storage::manifest::{
+    current_manifest, fsync_dir, manifest_name, parse_manifest_name, replay_manifest, Manifest,
+    ManifestState, TableRecord,
+};
+use crate::storage::memtable::SeqNo;
+use crate::storage::sstable::iter::SsTableIter;
+use crate::storage::sstable::reader::{ReaderContext, SsTableReader};
+use crate::storage::sstable::TableId;
+use crate::storage::wal::parse_wal_name;
+use std::io::{Error, ErrorKind};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// A table the manifest lists that could not be opened or read back.
+///
+/// Opening an engine fails with an [`ErrorKind::InvalidData`] error carrying
+/// one of these; [`CorruptTable::find`] gets it back out.
+#[derive(Debug)]
+pub struct CorruptTable {
+    pub id: TableId,
+    pub path: PathBuf,
+    pub error: Error,
+}
+
+impl CorruptTable {
+    /// The corrupt table behind `e`, if that is what went wrong.
+    pub fn find(e: &Error) -> Option<&CorruptTable> {
+        e.get_ref()?.downcast_ref()
+    }
+
+    pub(crate) fn into_error(self) -> Error {
+        Error::new(ErrorKind::InvalidData, self)
+    }
+}
+
+impl std::fmt::Display for CorruptTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "table {} ({}) is corrupt: {}",
+            self.id,
+            self.path.display(),
+            self.error
+        )
+    }
+}
+
+impl std::error::Error for CorruptTable {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// What [`LsmEngine::repair`] kept and what it set aside.
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    /// The tables the rebuilt manifest lists.
+    pub tables: Vec<TableInfo>,
+    /// Tables that failed to verify; those still on disk were moved to
+    /// `quarantine/`.
+    pub quarantined: Vec<CorruptTable>,
+    /// The old manifest could not be read, so every intact table in `sst/`
+    /// was adopted into level 0.
+    pub manifest_lost: bool,
+    /// Adopted tables whose key range a newer table spans, and whose newest
+    /// sequence number falls within that table's. They are most likely
+    /// compaction inputs that were not deleted yet, and may bring back keys
+    /// that compaction deleted; see [`LsmEngine::repair`].
+    pub maybe_stale: Vec<TableId>,
+}
+
+impl LsmEngine {
+    /// Verifies the footer, index and every block of each table under
+    /// `data_dir`, moves the ones that fail to `quarantine/` and writes a
+    /// manifest listing only the rest. The engine must not be open.
+    ///
+    /// Tables keep the level the old manifest gave them. If it cannot be read
+    /// at all, every table in `sst/` goes into level 0, ordered by the newest
+    /// sequence number it holds.
+    ///
+    /// That includes the inputs of a compaction that were retired but not
+    /// yet deleted. Their versions are older than the compaction's output,
+    /// so reads still see the newest value of every key the output holds;
+    /// but when the output dropped a key whose newest version was a delete,
+    /// the old input brings the key back. Nothing left on disk tells those
+    /// inputs apart from live tables for certain, so they are all kept, and
+    /// the likely ones are listed in [`RepairReport::maybe_stale`].
+    pub fn repair<P: AsRef<Path>>(
+        data_dir: P,
+        opts: &EngineOptions,
+    ) -> std::io::Result<RepairReport> {
+        let data_dir = data_dir.as_ref();
+        let env = opts.env.as_ref();
+        let sst_dir = data_dir.join("sst");
+        env.create_dir_all(&sst_dir)?;
+
+        let old = match current_manifest(env, data_dir) {
+            Ok(Some(n)) => replay_manifest(env, &data_dir.join(manifest_name(n))).ok(),
+            Ok(None) | Err(_) => None,
+        };
+        let mut on_disk: Vec<TableId> = env
+            .list_dir(&sst_dir)?
+            .iter()
+            .filter_map(|name| name.strip_suffix(".sst")?.parse().ok())
+            .collect();
+        on_disk.sort_unstable();
+        // Tables the manifest doesn't list are left for `open` to clean up.
+        let candidates: Vec<(TableId, usize)> = match &old {
+            Some(state) => state.tables.iter().map(|t| (t.id, t.level)).collect(),
+            None => on_disk.iter().map(|&id| (id, 0)).collect(),
+        };
+
+        let ctx = ReaderContext {
+            env: opts.env.clone(),
+            ..ReaderContext::default()
+        };
+        let mut report = RepairReport {
+            manifest_lost: old.is_none(),
+            ..RepairReport::default()
+        };
+        let mut survivors = Vec::new();
+        for (id, level) in candidates {
+            let path = sst_dir.join(format!("{id:06}.sst"));
+            match check_table(&path, id, &ctx) {
+                Ok((smallest, largest, seqs)) => {
+                    let file_size = env.file_size(&path)?;
+                    let record = TableRecord {
+                        id,
+                        level,
+                        smallest,
+                        largest,
+                        file_size,
+                    };
+                    survivors.push((record, seqs));
+                }
+                Err(error) => report.quarantined.push(CorruptTable { id, path, error }),
+            }
+        }
+
+        let dest = data_dir.join("quarantine");
+        for table in &report.quarantined {
+            if env.exists(&table.path) {
+                env.create_dir_all(&dest)?;
+                let to = dest.join(table.path.file_name().unwrap());
+                env.rename(&table.path, &to)?;
+                fsync_dir(env, &table.path)?;
+                fsync_dir(env, &to)?;
+            }
+        }
+
+        if old.is_none() {
+            survivors.sort_by_key(|(t, seqs)| (*seqs.end(), t.id));
+            report.maybe_stale = survivors
+                .iter()
+                .filter(|(t, seqs)| {
+                    survivors.iter().any(|(newer, newer_seqs)| {
+                        newer.id > t.id
+                            && newer.smallest <= t.smallest
+                            && t.largest <= newer.largest
+                            && newer_seqs.contains(seqs.end())
+                    })
+                })
+                .map(|(t, _)| t.id)
+                .collect();
+        }
+        let mut state = old.unwrap_or_default();
+        let newest = survivors.iter().map(|(_, s)| *s.end()).max().unwrap_or(0);
+        state.last_sequence = state.last_sequence.max(newest);
+        state.tables = survivors.into_iter().map(|(t, _)| t).collect();
+        let wals = env.list_dir(&data_dir.join("wal")).unwrap_or_default();
+        let highest_used = on_disk
+            .iter()
+            .copied()
+            .chain(wals.iter().filter_map(|name| parse_wal_name(name)))
+            .max()
+            .unwrap_or(0);
+        state.next_table_id = state.next_table_id.max(highest_used + 1);
+        report.tables = state
+            .tables
+            .iter()
+            .map(|t| TableInfo {
+                id: t.id,
+                level: t.level,
+                smallest: t.smallest.clone(),
+                largest: t.largest.clone(),
+                file_size: t.file_size,
+            })
+            .collect();
+        rewrite_manifest(data_dir, opts, state)?;
+        Ok(report)
+    }
+}
+
+/// Reads every record of the table at `path`, returning its key range and
+/// its oldest and newest sequence numbers.
+fn check_table(
+    path: &Path,
+    id: TableId,
+    ctx: &ReaderContext,
+) -> std::io::Result<(Vec<u8>, Vec<u8>, RangeInclusive<SeqNo>)> {
+    let mut it = SsTableIter::new(Arc::new(SsTableReader::open_in(path, id, ctx)?));
+    it.seek_to_first()?;
+    let mut range: Option<(Vec<u8>, Vec<u8>)> = None;
+    let (mut min_seq, mut max_seq) = (SeqNo::MAX, 0);
+    while it.valid() {
+        match &mut range {
+            Some((_, largest)) => {
+                if largest.as_slice() != it.key() {
+                    *largest = it.key().to_vec();
+                }
+            }
+            None => range = Some((it.key().to_vec(), it.key().to_vec())),
+        }
+        min_seq = min_seq.min(it.seq());
+        max_seq = max_seq.max(it.seq());
+        it.next()?;
+    }
+    let (smallest, largest) = range.unwrap_or_default();
+    Ok((smallest, largest, min_seq.min(max_seq)..=max_seq))
+}
+
+/// Writes `state` to a manifest numbered past every existing one, points
+/// `CURRENT` at it and deletes the others.
+fn rewrite_manifest(
+    data_dir: &Path,
+    opts: &EngineOptions,
+    state: ManifestState,
+) -> std::io::Result<()> {
+    let env = opts.env.as_ref();
+    let old: Vec<u64> = env
+        .list_dir(data_dir)?
+        .iter()
+        .filter_map(|name| parse_manifest_name(name))
+        .collect();
+    let number = old.iter().max().map_or(1, |n| n + 1);
+    Manifest::create(&opts.env, data_dir, number, state, opts.max_manifest_bytes)?;
+    for n in old {
+        env.remove_file(&data_dir.join(manifest_name(n)))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::env::{Env, MemEnv};
+    use crate::test_util::options;
+    use std::io::Write;
+
+    /// Writes `a0..a9` to one table, `b0..b9` to the next, and so on.
+    fn populate(env: &Arc<MemEnv>, groups: &str) -> Vec<TableInfo> {
+        let mut eng = LsmEngine::open("/db", options(env, 100)).unwrap();
+        for g in groups.chars() {
+            for i in 0..10 {
+                eng.put(format!("{g}{i}").as_bytes(), b"v").unwrap();
+            }
+            eng.flush().unwrap();
+        }
+        eng.tables()
+    }
+
+    fn flip_byte(env: &MemEnv, path: &Path, at: impl FnOnce(usize) -> usize) {
+        let mut bytes = env.read(path).unwrap();
+        let at = at(bytes.len());
+        bytes[at] ^= 0xff;
+        let mut f = env.create(path).unwrap();
+        f.write_all(&bytes).unwrap();
+        f.sync().unwrap();
+    }
+
+    fn table_path(id: TableId) -> PathBuf {
+        PathBuf::from(format!("/db/sst/{id:06}.sst"))
+    }
+
+    #[test]
+    fn open_names_the_corrupt_table_and_repair_sets_it_aside() {
+        let env = Arc::new(MemEnv::new());
+        let tables = populate(&env, "abc");
+        // Bad magic in `a`'s table, a bad data block in `c`'s.
+        flip_byte(&env, &table_path(tables[0].id), |len| len - 1);
+        flip_byte(&env, &table_path(tables[2].id), |_| 10);
+
+        let Err(e) = LsmEngine::open("/db", options(&env, 100)) else {
+            panic!("opened a corrupt table");
+        };
+        assert_eq!(CorruptTable::find(&e).unwrap().id, tables[0].id);
+
+        let report = LsmEngine::repair("/db", &options(&env, 100)).unwrap();
+        assert!(!report.manifest_lost);
+        let bad: Vec<_> = report.quarantined.iter().map(|t| t.id).collect();
+        assert_eq!(bad, [tables[0].id, tables[2].id]);
+        assert_eq!(report.tables, [tables[1].clone()]);
+        assert!(env.exists(&PathBuf::from(format!(
+            "/db/quarantine/{:06}.sst",
+            tables[0].id
+        ))));
+
+        let eng = LsmEngine::open("/db", options(&env, 100)).unwrap();
+        assert_eq!(eng.get(b"a1").unwrap(), None);
+        assert_eq!(eng.get(b"b1").unwrap(), Some(b"v".to_vec()));
+        assert_eq!(eng.get(b"c1").unwrap(), None);
+    }
+
+    #[test]
+    fn repair_rebuilds_a_lost_manifest_from_the_tables() {
+        let env = Arc::new(MemEnv::new());
+        let tables = populate(&env, "ab");
+        let mut eng = LsmEngine::open("/db", options(&env, 100)).unwrap();
+        eng.put(b"a1", b"newer").unwrap();
+        eng.flush().unwrap();
+        drop(eng);
+        let n = current_manifest(env.as_ref(), Path::new("/db"))
+            .unwrap()
+            .unwrap();
+        env.remove_file(&PathBuf::from(format!("/db/{}", manifest_name(n))))
+            .unwrap();
+        env.remove_file(Path::new("/db/CURRENT")).unwrap();
+
+        let report = LsmEngine::repair("/db", &options(&env, 100)).unwrap();
+        assert!(report.manifest_lost);
+        assert!(report.quarantined.is_empty());
+        assert_eq!(report.tables.len(), tables.len() + 1);
+
+        let eng = LsmEngine::open("/db", options(&env, 100)).unwrap();
+        assert_eq!(eng.get(b"a1").unwrap(), Some(b"newer".to_vec()));
+        assert_eq!(eng.get(b"b9").unwrap(), Some(b"v".to_vec()));
+        assert!(report.maybe_stale.is_empty());
+    }
+
+    #[test]
+    fn repair_reports_compaction_inputs_left_behind() {
+        let env = Arc::new(MemEnv::new());
+        let opts = options(&env, 2);
+        let mut eng = LsmEngine::open("/db", opts.clone()).unwrap();
+        for i in 0..10 {
+            eng.put(format!("a{i}").as_bytes(), b"v").unwrap();
+        }
+        eng.flush().unwrap();
+        let input = eng.tables()[0].clone();
+        let input_bytes = env.read(&table_path(input.id)).unwrap();
+        eng.put(b"a0", b"w").unwrap();
+        eng.delete(b"a5").unwrap();
+        eng.put(b"a9", b"w").unwrap();
+        eng.flush().unwrap();
+        eng.wait_for_background().unwrap();
+        let output = eng.tables();
+        assert_eq!(output.len(), 1);
+        assert_eq!(output[0].level, 1);
+        drop(eng);
+
+        // A crash after the manifest was lost and before the input went.
+        let mut f = env.create(&table_path(input.id)).unwrap();
+        f.write_all(&input_bytes).unwrap();
+        f.sync().unwrap();
+        let n = current_manifest(env.as_ref(), Path::new("/db"))
+            .unwrap()
+            .unwrap();
+        env.remove_file(&PathBuf::from(format!("/db/{}", manifest_name(n))))
+            .unwrap();
+        env.remove_file(Path::new("/db/CURRENT")).unwrap();
+
+        let report = LsmEngine::repair("/db", &opts).unwrap();
+        assert!(report.manifest_lost);
+        assert_eq!(report.tables.len(), 2);
+        assert_eq!(report.maybe_stale, [input.id]);
+        let eng = LsmEngine::open("/db", opts).unwrap();
+        assert_eq!(eng.get(b"a0").unwrap(), Some(b"w".to_vec()));
+        // The delete compaction dropped is undone, as documented.
+        assert_eq!(eng.get(b"a5").unwrap(), Some(b"v".to_vec()));
+    }
+}
@@ -0,0 +1,176 @@
+use crate::storage::iter::{EntryIter, MergingIter};
+use crate::storage::memtable::{now_millis, Entry, SeqNo};
+use crate::storage::merge::{resolve, MergeOperator};
+use crate::storage::version::Version;
+use std::ops::Bound;
+use std::sync::Arc;
+
+/// Live key-value pairs in a key range, as of when the scan was created or
+/// as of a [`Snapshot`](crate::engine::kv::Snapshot). Returned by [`LsmEngine::scan`](crate::engine::kv::LsmEngine::scan) in
+/// ascending key order and by
+/// [`LsmEngine::scan_rev`](crate::engine::kv::LsmEngine::scan_rev) in
+/// descending order.
+pub struct Scan {
+    iter: MergingIter,
+    /// Keeps the tables `iter` reads from being deleted.
+    _version: Arc<Version>,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    reverse: bool,
+    /// Only versions numbered this or below are visible.
+    seq: SeqNo,
+    /// Values expiring by this time read as absent.
+    now: u64,
+    merge: Arc<dyn MergeOperator>,
+    /// Keys from the first of these up to the second are skipped.
+    hidden: Option<(&'static [u8], &'static [u8])>,
+    done: bool,
+}
+
+impl Scan {
+    pub(crate) fn new(
+        mut iter: MergingIter,
+        version: Arc<Version>,
+        (start, end): (Bound<Vec<u8>>, Bound<Vec<u8>>),
+        reverse: bool,
+        seq: SeqNo,
+        merge: Arc<dyn MergeOperator>,
+        hidden: Option<(&'static [u8], &'static [u8])>,
+    ) -> std::io::Result<Self> {
+        if reverse {
+            match &end {
+                Bound::Included(k) => iter.seek_for_prev(k)?,
+                Bound::Excluded(k) => {
+                    iter.seek_for_prev(k)?;
+                    while iter.valid() && iter.key() == k.as_slice() {
+                        iter.prev()?;
+                    }
+                }
+                Bound::Unbounded => iter.seek_to_last()?,
+            }
+        } else {
+            match &start {
+                Bound::Included(k) => iter.seek(k)?,
+                Bound::Excluded(k) => {
+                    iter.seek(k)?;
+                    while iter.valid() && iter.key() == k.as_slice() {
+                        iter.next()?;
+                    }
+                }
+                Bound::Unbounded => iter.seek_to_first()?,
+            }
+        }
+        Ok(Self {
+            iter,
+            _version: version,
+            start,
+            end,
+            reverse,
+            seq,
+            now: now_millis(),
+            merge,
+            hidden,
+            done: false,
+        })
+    }
+
+    /// Moves past the hidden keys if the iterator is on one, returning
+    /// whether it did.
+    fn skip_hidden(&mut self) -> std::io::Result<bool> {
+        let Some((lo, hi)) = self.hidden else {
+            return Ok(false);
+        };
+        let key = self.iter.key();
+        if key < lo || key >= hi {
+            return Ok(false);
+        }
+        if self.reverse {
+            self.iter.seek_for_prev(lo)?;
+            while self.iter.valid() && self.iter.key() >= lo {
+                self.iter.prev()?;
+            }
+        } else {
+            self.iter.seek(hi)?;
+        }
+        Ok(true)
+    }
+
+    /// Moves past every version of the current key, returning the key and
+    /// the value its versions visible at `self.seq` add up to.
+    fn step_over_key(&mut self) -> std::io::Result<(Vec<u8>, Option<Vec<u8>>)> {
+        let key = self.iter.key().to_vec();
+        // Newest first; only versions down to the first that isn't a merge
+        // operand matter.
+        let mut visible: Vec<Entry> = Vec::new();
+        while self.iter.valid() && self.iter.key() == key.as_slice() {
+            if self.iter.seq() <= self.seq {
+                // Forward the newest version comes first; in reverse, last.
+                if self.reverse {
+                    if !matches!(self.iter.entry(), Entry::Merge(_)) {
+                        visible.clear();
+                    }
+                    visible.insert(0, self.iter.entry().clone());
+                } else if visible.last().is_none_or(|e| matches!(e, Entry::Merge(_))) {
+                    visible.push(self.iter.entry().clone());
+                }
+            }
+            if self.reverse {
+                self.iter.prev()?;
+            } else {
+                self.iter.next()?;
+            }
+        }
+        let value = resolve(self.merge.as_ref(), &key, &visible, self.now)?;
+        Ok((key, value))
+    }
+
+    fn in_range(&self, key: &[u8]) -> bool {
+        if self.reverse {
+            match &self.start {
+                Bound::Included(s) => key >= s.as_slice(),
+                Bound::Excluded(s) => key > s.as_slice(),
+                Bound::Unbounded => true,
+            }
+        } else {
+            match &self.end {
+                Bound::Included(e) => key <= e.as_slice(),
+                Bound::Excluded(e) => key < e.as_slice(),
+                Bound::Unbounded => true,
+            }
+        }
+    }
+}
+
+/// Whether no key can fall between `start` and `end`.
+pub(crate) fn is_empty_range(start: &Bound<Vec<u8>>, end: &Bound<Vec<u8>>) -> bool {
+    match (start, end) {
+        (Bound::Included(s), Bound::Included(e)) => s > e,
+        (Bound::Included(s) | Bound::Excluded(s), Bound::Excluded(e))
+        | (Bound::Excluded(s), Bound::Included(e)) => s >= e,
+        _ => false,
+    }
+}
+
+impl Iterator for Scan {
+    type Item = std::io::Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done && self.iter.valid() && self.in_range(self.iter.key()) {
+            let stepped = match self.skip_hidden() {
+                Ok(true) => continue,
+                Ok(false) => self.step_over_key(),
+                Err(e) => Err(e),
+            };
+            match stepped {
+                Ok((key, Some(value))) => return Some(Ok((key, value))),
+                Ok((_, None)) => {}
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+        self.done = true;
+        None
+    }
+}
@@ -1,2 +1,5 @@
 pub mod engine;
 pub mod storage;
+
+#[cfg(test)]
+mod test_util;
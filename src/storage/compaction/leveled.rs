@@ -0,0 +1,93 @@
+use super::{CompactionOptions, CompactionStrategy, CompactionTask};
+use crate::storage::version::{TableMeta, Version};
+use std::sync::Arc;
+
+/// Classic leveled compaction: level 0 is merged wholesale into level 1 once
+/// it has too many files, and any deeper level that outgrows its size budget
+/// pushes one table at a time into the level below.
+pub struct LeveledCompaction {
+    opts: CompactionOptions,
+    /// Per level, the largest key of the last table compacted out of it, so
+    /// successive compactions rotate through the key space.
+    cursors: Vec<Vec<u8>>,
+}
+
+impl LeveledCompaction {
+    pub fn new(opts: CompactionOptions) -> Self {
+        let cursors = vec![Vec::new(); opts.num_levels];
+        Self { opts, cursors }
+    }
+
+    fn max_bytes_for_level(&self, level: usize) -> u64 {
+        let mut bytes = self.opts.level_base_bytes;
+        for _ in 1..level {
+            bytes = bytes.saturating_mul(self.opts.level_multiplier);
+        }
+        bytes
+    }
+}
+
+impl CompactionStrategy for LeveledCompaction {
+    fn name(&self) -> &'static str {
+        "leveled"
+    }
+
+    /// Picks the level most in need of compaction, if any is over budget.
+    fn pick(&mut self, version: &Version) -> Option<CompactionTask> {
+        let last = version.num_levels() - 1;
+        let mut best: Option<(f64, usize)> = None;
+        for level in 0..last {
+            let score = if level == 0 {
+                version.level(0).len() as f64 / self.opts.level0_file_trigger.max(1) as f64
+            } else {
+                version.level_bytes(level) as f64 / self.max_bytes_for_level(level) as f64
+            };
+            if score >= 1.0 && best.is_none_or(|(s, _)| score > s) {
+                best = Some((score, level));
+            }
+        }
+        let (_, level) = best?;
+
+        let mut inputs: Vec<Arc<TableMeta>> = if level == 0 {
+            version.level(0).iter().rev().cloned().collect()
+        } else {
+            let tables = version.level(level);
+            let cursor = &self.cursors[level];
+            let pick = tables
+                .iter()
+                .find(|t| t.smallest.as_slice() > cursor.as_slice())
+                .unwrap_or(&tables[0]);
+            vec![pick.clone()]
+        };
+
+        let (smallest, largest) = key_span(&inputs);
+        self.cursors[level] = largest.clone();
+        inputs.extend(version.overlapping(level + 1, &smallest, &largest));
+
+        let (smallest, largest) = key_span(&inputs);
+        let bottommost = (level + 2..version.num_levels())
+            .all(|l| version.overlapping(l, &smallest, &largest).is_empty());
+        Some(CompactionTask {
+            inputs,
+            output_level: level + 1,
+            bottommost,
+        })
+    }
+}
+
+/// Smallest and largest key covered by any of `tables`.
+pub(crate) fn key_span(tables: &[Arc<TableMeta>]) -> (Vec<u8>, Vec<u8>) {
+    let smallest = tables
+        .iter()
+        .map(|t| &t.smallest)
+        .min()
+        .cloned()
+        .unwrap_or_default();
+    let largest = tables
+        .iter()
+        .map(|t| &t.largest)
+        .max()
+        .cloned()
+        .unwrap_or_default();
+    (smallest, largest)
+}
@@ -0,0 +1,303 @@
+pub mod leveled;
+pub mod size_tiered;
+
+use crate::storage::iter::{EntryIter, MergingIter};
+use crate::storage::manifest::fsync_dir;
+use crate::storage::memtable::{now_millis, Entry, SeqNo};
+use crate::storage::merge::{operands_oldest_first, partial_merge_chain, MergeOperator};
+use crate::storage::sstable::builder::SsTableBuilder;
+use crate::storage::sstable::iter::SsTableIter;
+use crate::storage::sstable::table_cache::TableCache;
+use crate::storage::sstable::{TableId, TableOptions};
+use crate::storage::version::{TableMeta, Version};
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+/// Built-in compaction strategies, selectable when a data dir is opened.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum CompactionStyle {
+    /// Low read and space amplification, at the cost of rewriting data more often.
+    #[default]
+    Leveled,
+    /// Merges similarly sized sorted runs; cheap writes, more runs to read.
+    SizeTiered,
+}
+
+impl std::str::FromStr for CompactionStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "leveled" => Ok(CompactionStyle::Leveled),
+            "size-tiered" | "tiered" => Ok(CompactionStyle::SizeTiered),
+            _ => Err(format!("unknown compaction style: {s}")),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CompactionOptions {
+    pub style: CompactionStyle,
+    pub num_levels: usize,
+    /// Number of level-0 tables that triggers a compaction into level 1.
+    pub level0_file_trigger: usize,
+    /// Size budget of level 1; each deeper level gets `level_multiplier` times more.
+    pub level_base_bytes: u64,
+    pub level_multiplier: u64,
+    /// Compaction output is split into files of roughly this size.
+    pub target_file_bytes: u64,
+    /// Size-tiered: an older run joins a merge while it is at most this many
+    /// percent larger than the newer runs already chosen.
+    pub size_ratio_percent: u64,
+    /// Size-tiered: fewest sorted runs worth merging together.
+    pub min_merge_width: usize,
+}
+
+impl Default for CompactionOptions {
+    fn default() -> Self {
+        Self {
+            style: CompactionStyle::Leveled,
+            num_levels: 7,
+            level0_file_trigger: 4,
+            level_base_bytes: 10 * 1024 * 1024,
+            level_multiplier: 10,
+            target_file_bytes: 2 * 1024 * 1024,
+            size_ratio_percent: 20,
+            min_merge_width: 2,
+        }
+    }
+}
+
+/// Decides which tables to merge. The engine consults it after every flush
+/// and keeps running the tasks it hands out until it returns `None`.
+pub trait CompactionStrategy: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn pick(&mut self, version: &Version) -> Option<CompactionTask>;
+}
+
+/// Builds the strategy selected by `opts.style`.
+pub fn new_strategy(opts: &CompactionOptions) -> Box<dyn CompactionStrategy> {
+    match opts.style {
+        CompactionStyle::Leveled => Box::new(leveled::LeveledCompaction::new(opts.clone())),
+        CompactionStyle::SizeTiered => {
+            Box::new(size_tiered::SizeTieredCompaction::new(opts.clone()))
+        }
+    }
+}
+
+/// A unit of compaction work chosen by a picker.
+pub struct CompactionTask {
+    /// Input tables, newest data first.
+    pub inputs: Vec<Arc<TableMeta>>,
+    pub output_level: usize,
+    /// No level below `output_level` holds any of the input keys, so
+    /// tombstones have nothing left to shadow and can be dropped.
+    pub bottommost: bool,
+}
+
+impl CompactionTask {
+    /// A single input can simply be relabelled into the output level.
+    pub fn is_trivial_move(&self) -> bool {
+        self.inputs.len() == 1
+    }
+}
+
+/// Decides which versions of a key are still visible to someone, given the
+/// sequence numbers of the live snapshots in ascending order.
+///
+/// The snapshots split sequence numbers into stripes, and a reader sees only
+/// the newest version in its stripe, so each key keeps one version per
+/// stripe. Merge operands are the exception: a reader also needs whatever
+/// lies beneath them, so they are folded into the version below them when it
+/// is in the same stripe, and otherwise kept.
+pub struct VersionGc<'a> {
+    snapshots: &'a [SeqNo],
+    merge: &'a dyn MergeOperator,
+}
+
+impl<'a> VersionGc<'a> {
+    pub fn new(snapshots: &'a [SeqNo], merge: &'a dyn MergeOperator) -> Self {
+        Self { snapshots, merge }
+    }
+
+    /// Reduces `versions` of `key`, newest first, to the ones some reader
+    /// needs. With `bottommost`, nothing older exists elsewhere, so a
+    /// tombstone no snapshot needs is dropped and an oldest chain of
+    /// operands is folded onto nothing.
+    pub fn collapse(
+        &self,
+        key: &[u8],
+        versions: Vec<(SeqNo, Entry)>,
+        bottommost: bool,
+    ) -> Vec<(SeqNo, Entry)> {
+        let mut out = Vec::new();
+        let mut versions = versions.into_iter().peekable();
+        while let Some((seq, entry)) = versions.next() {
+            let stripe = self.stripe(seq);
+            let mut rest_of_stripe =
+                std::iter::from_fn(|| versions.next_if(|(s, _)| self.stripe(*s) == stripe));
+            if !matches!(entry, Entry::Merge(_)) {
+                rest_of_stripe.for_each(drop);
+                out.push((seq, entry));
+                continue;
+            }
+            let mut chain = vec![(seq, entry)];
+            let mut base = None;
+            for (s, e) in rest_of_stripe.by_ref() {
+                if matches!(e, Entry::Merge(_)) {
+                    chain.push((s, e));
+                } else {
+                    base = Some((s, e));
+                    break;
+                }
+            }
+            rest_of_stripe.for_each(drop);
+            let existing = match &base {
+                Some((_, Entry::Put(v))) => Some(Some(v.as_slice())),
+                Some((_, Entry::Delete)) => Some(None),
+                // An expiring base must go on expiring, so it stays put.
+                Some(_) => None,
+                None if bottommost && versions.peek().is_none() => Some(None),
+                None => None,
+            };
+            let merged = existing.and_then(|existing| {
+                let operands = operands_oldest_first(chain.iter().map(|(_, e)| e));
+                // Operands that don't merge stay as they are, for reads to
+                // report; failing here would stop the engine instead.
+                self.merge.full_merge(key, existing, &operands).ok()
+            });
+            match merged {
+                Some(merged) => out.push((seq, Entry::Put(merged))),
+                None => {
+                    out.extend(partial_merge_chain(self.merge, key, chain));
+                    out.extend(base);
+                }
+            }
+        }
+        if bottommost {
+            if let Some((seq, Entry::Delete)) = out.last() {
+                if self.stripe(*seq) == 0 {
+                    out.pop();
+                }
+            }
+        }
+        out
+    }
+
+    fn stripe(&self, seq: SeqNo) -> usize {
+        self.snapshots.partition_point(|&s| s < seq)
+    }
+}
+
+/// The output file currently being written.
+struct Output {
+    id: TableId,
+    builder: SsTableBuilder,
+    smallest: Vec<u8>,
+    largest: Vec<u8>,
+}
+
+/// Merges the task inputs into new tables under `sst_dir`, keeping only the
+/// versions `gc` says someone can see. Expired values become tombstones, and
+/// tombstones are dropped at the bottommost level once no snapshot needs
+/// them.
+///
+/// All versions of a key go into the same output, so outputs never overlap.
+///
+/// Outputs are written through the env of `tables`, fsynced, renamed into
+/// place and opened, but not yet recorded in the manifest; the caller
+/// installs them.
+pub fn run_compaction(
+    task: &CompactionTask,
+    opts: &CompactionOptions,
+    table_opts: &TableOptions,
+    sst_dir: &Path,
+    alloc_id: &mut dyn FnMut() -> TableId,
+    tables: &Arc<TableCache>,
+    gc: &VersionGc,
+) -> std::io::Result<Vec<TableMeta>> {
+    let mut children: Vec<Box<dyn EntryIter>> = Vec::new();
+    for table in &task.inputs {
+        // A one-off pass over the inputs; keep it out of the block cache.
+        let mut it = SsTableIter::new(table.reader()?);
+        it.set_fill_cache(false);
+        children.push(Box::new(it));
+    }
+    let mut iter = MergingIter::new(children);
+    iter.seek_to_first()?;
+
+    let now = now_millis();
+    let mut outputs = Vec::new();
+    let mut current: Option<Output> = None;
+
+    while iter.valid() {
+        let key = iter.key().to_vec();
+        let mut versions = Vec::new();
+        while iter.valid() && iter.key() == key.as_slice() {
+            let entry = match iter.entry() {
+                // It must still hide older versions of the key.
+                e if e.is_expired(now) => Entry::Delete,
+                e => e.clone(),
+            };
+            versions.push((iter.seq(), entry));
+            iter.next()?;
+        }
+        let versions = gc.collapse(&key, versions, task.bottommost);
+        if versions.is_empty() {
+            continue;
+        }
+        if let Some(out) = &current {
+            if out.builder.estimated_size() >= opts.target_file_bytes {
+                outputs.push(finish_output(current.take().unwrap(), sst_dir, tables)?);
+            }
+        }
+
+        let out = match current.as_mut() {
+            Some(out) => out,
+            None => {
+                let id = alloc_id();
+                let tmp = sst_dir.join(format!("{id:06}.sst.tmp"));
+                let env = tables.context().env.as_ref();
+                current.insert(Output {
+                    id,
+                    builder: SsTableBuilder::create(env, &tmp, table_opts)?,
+                    smallest: key.clone(),
+                    largest: Vec::new(),
+                })
+            }
+        };
+        for (seq, entry) in &versions {
+            out.builder.add_entry(&key, *seq, entry);
+        }
+        out.largest = key;
+    }
+    if let Some(out) = current.take() {
+        outputs.push(finish_output(out, sst_dir, tables)?);
+    }
+    Ok(outputs)
+}
+
+fn finish_output(
+    out: Output,
+    sst_dir: &Path,
+    tables: &Arc<TableCache>,
+) -> std::io::Result<TableMeta> {
+    let tmp = sst_dir.join(format!("{:06}.sst.tmp", out.id));
+    let path = sst_dir.join(format!("{:06}.sst", out.id));
+    let env = tables.context().env.as_ref();
+    out.builder.finish()?;
+    env.rename(&tmp, &path)?;
+    fsync_dir(env, &path)?;
+    let file_size = tables.get(out.id, &path)?.file_len()?;
+    Ok(TableMeta {
+        id: out.id,
+        path,
+        tables: tables.clone(),
+        smallest: out.smallest,
+        largest: out.largest,
+        file_size,
+        retired: AtomicBool::new(false),
+    })
+}
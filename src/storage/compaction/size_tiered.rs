@@ -0,0 +1,122 @@
+use super::leveled::key_span;
+use super::{CompactionOptions, CompactionStrategy, CompactionTask};
+use crate::storage::version::{TableMeta, Version};
+use std::sync::Arc;
+
+/// One sorted run: a single level-0 table, or a whole deeper level.
+struct Run {
+    tables: Vec<Arc<TableMeta>>,
+    bytes: u64,
+    /// `0` for level-0 tables, otherwise the level holding the run.
+    level: usize,
+}
+
+/// Size-tiered (universal) compaction.
+///
+/// Every level-0 table and every non-empty deeper level is a sorted run,
+/// ordered from newest to oldest. Once there are at least
+/// `level0_file_trigger` runs, a window of adjacent runs with similar sizes is
+/// merged into a single run. Only adjacent runs are merged so newer data keeps
+/// shadowing older data, and each deeper level holds exactly one run.
+pub struct SizeTieredCompaction {
+    opts: CompactionOptions,
+}
+
+impl SizeTieredCompaction {
+    pub fn new(opts: CompactionOptions) -> Self {
+        Self { opts }
+    }
+
+    fn sorted_runs(version: &Version) -> Vec<Run> {
+        let mut runs: Vec<Run> = version
+            .level(0)
+            .iter()
+            .rev()
+            .map(|t| Run {
+                tables: vec![t.clone()],
+                bytes: t.file_size,
+                level: 0,
+            })
+            .collect();
+        for level in 1..version.num_levels() {
+            if !version.level(level).is_empty() {
+                runs.push(Run {
+                    tables: version.level(level).to_vec(),
+                    bytes: version.level_bytes(level),
+                    level,
+                });
+            }
+        }
+        runs
+    }
+
+    /// Finds the first window of runs, newest first, in which each older run
+    /// is not much larger than everything chosen before it.
+    fn pick_window(&self, runs: &[Run]) -> Option<(usize, usize)> {
+        for start in 0..runs.len() {
+            let mut total = runs[start].bytes;
+            let mut end = start + 1;
+            while end < runs.len()
+                && runs[end].bytes * 100 <= total * (100 + self.opts.size_ratio_percent)
+            {
+                total += runs[end].bytes;
+                end += 1;
+            }
+            if end - start >= self.opts.min_merge_width.max(2) {
+                return Some((start, end));
+            }
+        }
+        None
+    }
+}
+
+impl CompactionStrategy for SizeTieredCompaction {
+    fn name(&self) -> &'static str {
+        "size-tiered"
+    }
+
+    fn pick(&mut self, version: &Version) -> Option<CompactionTask> {
+        let runs = Self::sorted_runs(version);
+        if runs.len() < self.opts.level0_file_trigger.max(2) {
+            return None;
+        }
+        // Without a window of similar runs, merge the newest ones just enough
+        // to get back under the trigger.
+        let (start, mut end) = self
+            .pick_window(&runs)
+            .unwrap_or((0, runs.len() + 2 - self.opts.level0_file_trigger.max(2)));
+
+        // The output has to sit between the runs around the window. A window
+        // of level-0 tables may only take the oldest of them, and lands on
+        // the deepest empty level above the next run; with no room left it
+        // absorbs that run too.
+        let output_level = if runs[end - 1].level > 0 {
+            runs[end - 1].level
+        } else {
+            if end < runs.len() && runs[end].level == 0 {
+                end = runs.iter().rposition(|r| r.level == 0).unwrap() + 1;
+            }
+            match runs.get(end) {
+                None => version.num_levels() - 1,
+                Some(next) if next.level > 1 => next.level - 1,
+                Some(next) => {
+                    end += 1;
+                    next.level
+                }
+            }
+        };
+
+        let inputs: Vec<Arc<TableMeta>> = runs[start..end]
+            .iter()
+            .flat_map(|r| r.tables.iter().cloned())
+            .collect();
+        let (smallest, largest) = key_span(&inputs);
+        let bottommost = (output_level + 1..version.num_levels())
+            .all(|l| version.overlapping(l, &smallest, &largest).is_empty());
+        Some(CompactionTask {
+            inputs,
+            output_level,
+            bottommost,
+        })
+    }
+}
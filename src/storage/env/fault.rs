@@ -0,0 +1,198 @@
+use super::{Env, MemEnv, RandomAccessFile, WritableFile};
+use std::io::{Error, Result, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// A [`MemEnv`] that fails writes or crashes on request.
+///
+/// Writes, syncs, creations, renames, links and removals count as
+/// mutations. Once [`crash_after`](Self::crash_after) mutations have gone
+/// through the next one fails and so does every operation after it, as if
+/// the process died there; [`restart`](Self::restart) then drops whatever
+/// was not durable and lets a new engine open the files.
+#[derive(Debug)]
+pub struct FaultEnv {
+    base: Arc<MemEnv>,
+    faults: Arc<Faults>,
+}
+
+#[derive(Debug, Default)]
+struct Faults {
+    fail_writes: AtomicBool,
+    /// Mutations left before the crash, if one is scheduled.
+    crash_after: Mutex<Option<u64>>,
+    crashed: AtomicBool,
+    /// File syncs that went through.
+    syncs: AtomicU64,
+}
+
+impl Faults {
+    fn check_alive(&self) -> Result<()> {
+        if self.crashed.load(Ordering::SeqCst) {
+            return Err(Error::other("simulated crash"));
+        }
+        Ok(())
+    }
+
+    /// Lets a mutation through, unless a fault stops it.
+    fn mutate(&self) -> Result<()> {
+        self.check_alive()?;
+        if self.fail_writes.load(Ordering::SeqCst) {
+            return Err(Error::other("injected write failure"));
+        }
+        let mut left = self.crash_after.lock().unwrap();
+        match left.as_mut() {
+            Some(0) => {
+                *left = None;
+                self.crashed.store(true, Ordering::SeqCst);
+            }
+            Some(n) => *n -= 1,
+            None => {}
+        }
+        drop(left);
+        self.check_alive()
+    }
+}
+
+impl FaultEnv {
+    pub fn new(base: Arc<MemEnv>) -> Self {
+        Self {
+            base,
+            faults: Arc::default(),
+        }
+    }
+
+    /// While set, every mutation fails; everything else keeps working.
+    pub fn fail_writes(&self, fail: bool) {
+        self.faults.fail_writes.store(fail, Ordering::SeqCst);
+    }
+
+    /// Crashes on the mutation after the next `ops`.
+    pub fn crash_after(&self, ops: u64) {
+        *self.faults.crash_after.lock().unwrap() = Some(ops);
+    }
+
+    pub fn crashed(&self) -> bool {
+        self.faults.crashed.load(Ordering::SeqCst)
+    }
+
+    /// How many times a file has been synced so far.
+    pub fn file_syncs(&self) -> u64 {
+        self.faults.syncs.load(Ordering::SeqCst)
+    }
+
+    /// Crashes the underlying [`MemEnv`] and clears every fault, once the
+    /// engine that was using it has been dropped.
+    pub fn restart(&self) {
+        self.base.crash();
+        self.fail_writes(false);
+        *self.faults.crash_after.lock().unwrap() = None;
+        self.faults.crashed.store(false, Ordering::SeqCst);
+    }
+}
+
+impl Env for FaultEnv {
+    fn create(&self, path: &Path) -> Result<Box<dyn WritableFile>> {
+        self.faults.mutate()?;
+        Ok(Box::new(FaultWriter {
+            inner: self.base.create(path)?,
+            faults: self.faults.clone(),
+        }))
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn RandomAccessFile>> {
+        self.faults.check_alive()?;
+        Ok(Box::new(FaultReader {
+            inner: self.base.open(path)?,
+            faults: self.faults.clone(),
+        }))
+    }
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>> {
+        self.faults.check_alive()?;
+        self.base.read(path)
+    }
+
+    fn file_size(&self, path: &Path) -> Result<u64> {
+        self.faults.check_alive()?;
+        self.base.file_size(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.base.exists(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        self.faults.mutate()?;
+        self.base.rename(from, to)
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> Result<()> {
+        self.faults.mutate()?;
+        self.base.hard_link(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        self.faults.mutate()?;
+        self.base.remove_file(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<()> {
+        self.faults.mutate()?;
+        self.base.create_dir_all(path)
+    }
+
+    fn list_dir(&self, dir: &Path) -> Result<Vec<String>> {
+        self.faults.check_alive()?;
+        self.base.list_dir(dir)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> Result<()> {
+        self.faults.mutate()?;
+        self.base.sync_dir(dir)
+    }
+}
+
+struct FaultWriter {
+    inner: Box<dyn WritableFile>,
+    faults: Arc<Faults>,
+}
+
+impl Write for FaultWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.faults.mutate()?;
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.faults.check_alive()?;
+        self.inner.flush()
+    }
+}
+
+impl WritableFile for FaultWriter {
+    fn sync(&mut self) -> Result<()> {
+        self.faults.mutate()?;
+        self.inner.sync()?;
+        self.faults.syncs.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+struct FaultReader {
+    inner: Box<dyn RandomAccessFile>,
+    faults: Arc<Faults>,
+}
+
+impl RandomAccessFile for FaultReader {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        self.faults.check_alive()?;
+        self.inner.read_exact_at(buf, offset)
+    }
+
+    fn size(&self) -> Result<u64> {
+        self.faults.check_alive()?;
+        self.inner.size()
+    }
+}
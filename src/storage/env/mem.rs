@@ -0,0 +1,238 @@
+use super::{Env, RandomAccessFile, WritableFile};
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Files kept in memory, for fast tests and for simulating power loss.
+///
+/// Data written to a file becomes durable once the file is synced, and the
+/// creation, rename or removal of a file once its directory is synced.
+/// [`crash`](Self::crash) throws away everything that is not yet durable.
+#[derive(Debug, Default)]
+pub struct MemEnv {
+    fs: Mutex<MemFs>,
+}
+
+#[derive(Debug, Default)]
+struct MemFs {
+    files: HashMap<PathBuf, SharedFile>,
+    /// The files of each directory as of the last time it was synced.
+    durable: HashMap<PathBuf, SharedFile>,
+    dirs: HashSet<PathBuf>,
+}
+
+type SharedFile = Arc<Mutex<FileData>>;
+
+#[derive(Debug, Default)]
+struct FileData {
+    bytes: Vec<u8>,
+    /// How many of `bytes` survive a crash.
+    synced: usize,
+}
+
+impl MemEnv {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loses everything not yet durable, as if the machine lost power.
+    /// Handles opened before the crash no longer affect the files.
+    pub fn crash(&self) {
+        let mut fs = self.fs.lock().unwrap();
+        let survivors: HashMap<_, _> = fs
+            .durable
+            .iter()
+            .map(|(path, file)| {
+                let file = file.lock().unwrap();
+                let data = FileData {
+                    bytes: file.bytes[..file.synced].to_vec(),
+                    synced: file.synced,
+                };
+                (path.clone(), Arc::new(Mutex::new(data)))
+            })
+            .collect();
+        fs.durable = survivors.clone();
+        fs.files = survivors;
+    }
+
+    fn file(&self, path: &Path) -> Result<SharedFile> {
+        let fs = self.fs.lock().unwrap();
+        fs.files.get(path).cloned().ok_or_else(|| not_found(path))
+    }
+}
+
+fn not_found(path: &Path) -> Error {
+    Error::new(ErrorKind::NotFound, format!("{} not found", path.display()))
+}
+
+impl Env for MemEnv {
+    fn create(&self, path: &Path) -> Result<Box<dyn WritableFile>> {
+        let mut fs = self.fs.lock().unwrap();
+        let dir = path.parent().unwrap_or(Path::new(""));
+        if !fs.dirs.contains(dir) {
+            return Err(not_found(dir));
+        }
+        let file = SharedFile::default();
+        fs.files.insert(path.to_path_buf(), file.clone());
+        Ok(Box::new(MemWriter(file)))
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn RandomAccessFile>> {
+        Ok(Box::new(MemReader(self.file(path)?)))
+    }
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>> {
+        Ok(self.file(path)?.lock().unwrap().bytes.clone())
+    }
+
+    fn file_size(&self, path: &Path) -> Result<u64> {
+        Ok(self.file(path)?.lock().unwrap().bytes.len() as u64)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        let fs = self.fs.lock().unwrap();
+        fs.files.contains_key(path) || fs.dirs.contains(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let mut fs = self.fs.lock().unwrap();
+        let file = fs.files.remove(from).ok_or_else(|| not_found(from))?;
+        fs.files.insert(to.to_path_buf(), file);
+        Ok(())
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> Result<()> {
+        let mut fs = self.fs.lock().unwrap();
+        let dir = to.parent().unwrap_or(Path::new(""));
+        if !fs.dirs.contains(dir) {
+            return Err(not_found(dir));
+        }
+        if fs.files.contains_key(to) {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{} already exists", to.display()),
+            ));
+        }
+        let file = fs.files.get(from).cloned().ok_or_else(|| not_found(from))?;
+        fs.files.insert(to.to_path_buf(), file);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        let mut fs = self.fs.lock().unwrap();
+        fs.files
+            .remove(path)
+            .map(|_| ())
+            .ok_or_else(|| not_found(path))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<()> {
+        let mut fs = self.fs.lock().unwrap();
+        for dir in path.ancestors() {
+            fs.dirs.insert(dir.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn list_dir(&self, dir: &Path) -> Result<Vec<String>> {
+        let fs = self.fs.lock().unwrap();
+        if !fs.dirs.contains(dir) {
+            return Err(not_found(dir));
+        }
+        Ok(fs
+            .files
+            .keys()
+            .chain(&fs.dirs)
+            .filter(|p| p.parent() == Some(dir))
+            .filter_map(|p| Some(p.file_name()?.to_str()?.to_string()))
+            .collect())
+    }
+
+    fn sync_dir(&self, dir: &Path) -> Result<()> {
+        let mut fs = self.fs.lock().unwrap();
+        let MemFs { files, durable, .. } = &mut *fs;
+        durable.retain(|p, _| p.parent() != Some(dir));
+        for (path, file) in files.iter() {
+            if path.parent() == Some(dir) {
+                durable.insert(path.clone(), file.clone());
+            }
+        }
+        Ok(())
+    }
+}
+
+struct MemWriter(SharedFile);
+
+impl Write for MemWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.0.lock().unwrap().bytes.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl WritableFile for MemWriter {
+    fn sync(&mut self) -> Result<()> {
+        let mut file = self.0.lock().unwrap();
+        file.synced = file.bytes.len();
+        Ok(())
+    }
+}
+
+struct MemReader(SharedFile);
+
+impl RandomAccessFile for MemReader {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        let file = self.0.lock().unwrap();
+        let start = usize::try_from(offset).unwrap_or(usize::MAX);
+        let src = start
+            .checked_add(buf.len())
+            .and_then(|end| file.bytes.get(start..end))
+            .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "read past end of file"))?;
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self.0.lock().unwrap().bytes.len() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crash_keeps_only_synced_data_and_directory_entries() {
+        let env = MemEnv::new();
+        let dir = Path::new("/db");
+        env.create_dir_all(dir).unwrap();
+
+        let mut synced = env.create(&dir.join("a")).unwrap();
+        synced.write_all(b"durable").unwrap();
+        synced.sync().unwrap();
+        synced.write_all(b" lost").unwrap();
+        env.sync_dir(dir).unwrap();
+
+        let mut unlinked = env.create(&dir.join("b")).unwrap();
+        unlinked.write_all(b"x").unwrap();
+        unlinked.sync().unwrap();
+        env.rename(&dir.join("a"), &dir.join("c")).unwrap();
+        assert_eq!(env.read(&dir.join("c")).unwrap(), b"durable lost");
+
+        env.crash();
+        // Neither the new file nor the rename reached the directory.
+        let mut names = env.list_dir(dir).unwrap();
+        names.sort();
+        assert_eq!(names, ["a"]);
+        assert_eq!(env.read(&dir.join("a")).unwrap(), b"durable");
+        // Writes through a handle from before the crash go nowhere.
+        synced.write_all(b"!").unwrap();
+        assert_eq!(env.file_size(&dir.join("a")).unwrap(), 7);
+        assert!(env.create(Path::new("/missing/f")).is_err());
+    }
+}
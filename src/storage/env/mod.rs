@@ -0,0 +1,133 @@
+pub mod fault;
+pub mod mem;
+
+pub use fault::FaultEnv;
+pub use mem::MemEnv;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Result, Write};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+/// The filesystem the storage layer keeps its files in.
+///
+/// Everything an engine reads or writes goes through an `Env`, so tests can
+/// swap the disk for memory and inject failures and crashes.
+pub trait Env: Send + Sync + std::fmt::Debug {
+    /// Creates `path` for writing, truncating any existing file.
+    fn create(&self, path: &Path) -> Result<Box<dyn WritableFile>>;
+
+    fn open(&self, path: &Path) -> Result<Box<dyn RandomAccessFile>>;
+
+    /// Reads the whole file at `path`.
+    fn read(&self, path: &Path) -> Result<Vec<u8>>;
+
+    fn file_size(&self, path: &Path) -> Result<u64>;
+
+    fn exists(&self, path: &Path) -> bool;
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()>;
+
+    /// Makes `to` a second name for the file at `from`.
+    fn hard_link(&self, from: &Path, to: &Path) -> Result<()>;
+
+    fn remove_file(&self, path: &Path) -> Result<()>;
+
+    fn create_dir_all(&self, path: &Path) -> Result<()>;
+
+    /// Names of the files in `dir`, in no particular order.
+    fn list_dir(&self, dir: &Path) -> Result<Vec<String>>;
+
+    /// Makes the files created, renamed or removed in `dir` so far durable.
+    fn sync_dir(&self, dir: &Path) -> Result<()>;
+}
+
+/// A file being written front to back.
+pub trait WritableFile: Write + Send + Sync {
+    /// Makes everything written so far durable.
+    fn sync(&mut self) -> Result<()>;
+}
+
+/// A file read with positioned reads, shared between threads.
+pub trait RandomAccessFile: Send + Sync {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<()>;
+
+    fn size(&self) -> Result<u64>;
+}
+
+/// The local disk, through `std::fs`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RealEnv;
+
+impl Env for RealEnv {
+    fn create(&self, path: &Path) -> Result<Box<dyn WritableFile>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Box::new(file))
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn RandomAccessFile>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn file_size(&self, path: &Path) -> Result<u64> {
+        Ok(fs::metadata(path)?.len())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> Result<()> {
+        fs::hard_link(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn list_dir(&self, dir: &Path) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            if let Some(name) = entry?.file_name().to_str() {
+                names.push(name.to_string());
+            }
+        }
+        Ok(names)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> Result<()> {
+        File::open(dir)?.sync_all()
+    }
+}
+
+impl WritableFile for File {
+    fn sync(&mut self) -> Result<()> {
+        self.sync_all()
+    }
+}
+
+impl RandomAccessFile for File {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        FileExt::read_exact_at(self, buf, offset)
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+}
@@ -0,0 +1,364 @@
+use crate::storage::memtable::{Entry, SeqNo};
+use std::cmp::Reverse;
+use std::io::Result;
+
+/// A cursor over entries in order of key, and for each key from the newest
+/// version to the oldest, tombstones included.
+///
+/// A new cursor is unpositioned until one of the seek methods is called.
+/// `key`, `seq` and `entry` may only be called while `valid` returns true.
+pub trait EntryIter: Send {
+    fn valid(&self) -> bool;
+
+    fn key(&self) -> &[u8];
+
+    fn seq(&self) -> SeqNo;
+
+    fn entry(&self) -> &Entry;
+
+    fn seek_to_first(&mut self) -> Result<()>;
+
+    /// Positions at the newest version of the first key `>= key`.
+    fn seek(&mut self, key: &[u8]) -> Result<()>;
+
+    fn next(&mut self) -> Result<()>;
+
+    fn seek_to_last(&mut self) -> Result<()>;
+
+    /// Positions at the oldest version of the last key `<= key`.
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<()>;
+
+    fn prev(&mut self) -> Result<()>;
+}
+
+/// Cursor over an owned, sorted run of entries, such as a copied memtable range.
+pub struct VecIter {
+    entries: Vec<(Vec<u8>, SeqNo, Entry)>,
+    pos: usize,
+}
+
+impl VecIter {
+    pub fn new(entries: Vec<(Vec<u8>, SeqNo, Entry)>) -> Self {
+        let pos = entries.len();
+        Self { entries, pos }
+    }
+}
+
+impl EntryIter for VecIter {
+    fn valid(&self) -> bool {
+        self.pos < self.entries.len()
+    }
+
+    fn key(&self) -> &[u8] {
+        &self.entries[self.pos].0
+    }
+
+    fn seq(&self) -> SeqNo {
+        self.entries[self.pos].1
+    }
+
+    fn entry(&self) -> &Entry {
+        &self.entries[self.pos].2
+    }
+
+    fn seek_to_first(&mut self) -> Result<()> {
+        self.pos = 0;
+        Ok(())
+    }
+
+    fn seek(&mut self, key: &[u8]) -> Result<()> {
+        self.pos = self.entries.partition_point(|(k, _, _)| k.as_slice() < key);
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<()> {
+        self.pos = (self.pos + 1).min(self.entries.len());
+        Ok(())
+    }
+
+    fn seek_to_last(&mut self) -> Result<()> {
+        self.pos = self.entries.len().saturating_sub(1);
+        Ok(())
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<()> {
+        self.pos = match self
+            .entries
+            .partition_point(|(k, _, _)| k.as_slice() <= key)
+        {
+            0 => self.entries.len(),
+            n => n - 1,
+        };
+        Ok(())
+    }
+
+    fn prev(&mut self) -> Result<()> {
+        self.pos = match self.pos {
+            0 => self.entries.len(),
+            n => n - 1,
+        };
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Direction {
+    Forward,
+    Reverse,
+}
+
+/// Merges several cursors into one, in key order and newest version first.
+///
+/// Every version from every source is surfaced; readers pick the one visible
+/// at their sequence number. Entries with the same key and sequence number,
+/// as in tables written before sequence numbers existed, come from the
+/// earliest source in `children` first, so children are passed newest first.
+pub struct MergingIter {
+    children: Vec<Box<dyn EntryIter>>,
+    current: Option<usize>,
+    /// Moving forward every valid child sits at or after the current entry;
+    /// moving in reverse, at or before it.
+    direction: Direction,
+}
+
+impl MergingIter {
+    pub fn new(children: Vec<Box<dyn EntryIter>>) -> Self {
+        Self {
+            children,
+            current: None,
+            direction: Direction::Forward,
+        }
+    }
+
+    fn order(child: &dyn EntryIter) -> (&[u8], Reverse<SeqNo>) {
+        (child.key(), Reverse(child.seq()))
+    }
+
+    fn find_smallest(&mut self) {
+        let mut best: Option<usize> = None;
+        for (i, child) in self.children.iter().enumerate() {
+            if !child.valid() {
+                continue;
+            }
+            match best {
+                Some(b) if Self::order(&*self.children[b]) <= Self::order(&**child) => {}
+                _ => best = Some(i),
+            }
+        }
+        self.current = best;
+    }
+
+    fn find_largest(&mut self) {
+        let mut best: Option<usize> = None;
+        for (i, child) in self.children.iter().enumerate() {
+            if !child.valid() {
+                continue;
+            }
+            match best {
+                Some(b) if Self::order(&*self.children[b]) > Self::order(&**child) => {}
+                _ => best = Some(i),
+            }
+        }
+        self.current = best;
+    }
+}
+
+impl EntryIter for MergingIter {
+    fn valid(&self) -> bool {
+        self.current.is_some()
+    }
+
+    fn key(&self) -> &[u8] {
+        self.children[self.current.unwrap()].key()
+    }
+
+    fn seq(&self) -> SeqNo {
+        self.children[self.current.unwrap()].seq()
+    }
+
+    fn entry(&self) -> &Entry {
+        self.children[self.current.unwrap()].entry()
+    }
+
+    fn seek_to_first(&mut self) -> Result<()> {
+        for child in &mut self.children {
+            child.seek_to_first()?;
+        }
+        self.direction = Direction::Forward;
+        self.find_smallest();
+        Ok(())
+    }
+
+    fn seek(&mut self, key: &[u8]) -> Result<()> {
+        for child in &mut self.children {
+            child.seek(key)?;
+        }
+        self.direction = Direction::Forward;
+        self.find_smallest();
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<()> {
+        let Some(current) = self.current else {
+            return Ok(());
+        };
+        if self.direction == Direction::Reverse {
+            // Move every other child just past the current entry.
+            let key = self.key().to_vec();
+            let seq = self.seq();
+            for (i, child) in self.children.iter_mut().enumerate() {
+                if i == current {
+                    continue;
+                }
+                child.seek(&key)?;
+                // Ties on sequence number order by child, earliest first.
+                while child.valid()
+                    && child.key() == key.as_slice()
+                    && (child.seq() > seq || (child.seq() == seq && i < current))
+                {
+                    child.next()?;
+                }
+            }
+            self.direction = Direction::Forward;
+        }
+        self.children[current].next()?;
+        self.find_smallest();
+        Ok(())
+    }
+
+    fn seek_to_last(&mut self) -> Result<()> {
+        for child in &mut self.children {
+            child.seek_to_last()?;
+        }
+        self.direction = Direction::Reverse;
+        self.find_largest();
+        Ok(())
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<()> {
+        for child in &mut self.children {
+            child.seek_for_prev(key)?;
+        }
+        self.direction = Direction::Reverse;
+        self.find_largest();
+        Ok(())
+    }
+
+    fn prev(&mut self) -> Result<()> {
+        let Some(current) = self.current else {
+            return Ok(());
+        };
+        if self.direction == Direction::Forward {
+            // Move every other child just before the current entry.
+            let key = self.key().to_vec();
+            let seq = self.seq();
+            for (i, child) in self.children.iter_mut().enumerate() {
+                if i == current {
+                    continue;
+                }
+                child.seek_for_prev(&key)?;
+                while child.valid()
+                    && child.key() == key.as_slice()
+                    && (child.seq() < seq || (child.seq() == seq && i > current))
+                {
+                    child.prev()?;
+                }
+            }
+            self.direction = Direction::Reverse;
+        }
+        self.children[current].prev()?;
+        self.find_largest();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(entries: &[(&str, SeqNo, Option<&str>)]) -> Box<dyn EntryIter> {
+        let entries = entries
+            .iter()
+            .map(|(k, s, v)| {
+                let entry = match v {
+                    Some(v) => Entry::Put(v.as_bytes().to_vec()),
+                    None => Entry::Delete,
+                };
+                (k.as_bytes().to_vec(), *s, entry)
+            })
+            .collect();
+        Box::new(VecIter::new(entries))
+    }
+
+    fn collect(it: &mut MergingIter) -> Vec<(String, SeqNo)> {
+        let mut out = Vec::new();
+        while it.valid() {
+            out.push((String::from_utf8(it.key().to_vec()).unwrap(), it.seq()));
+            it.next().unwrap();
+        }
+        out
+    }
+
+    fn owned(entries: &[(&str, SeqNo)]) -> Vec<(String, SeqNo)> {
+        entries.iter().map(|(k, s)| (k.to_string(), *s)).collect()
+    }
+
+    #[test]
+    fn merges_versions_newest_first() {
+        let newer = run(&[("b", 7, None), ("c", 8, Some("c2"))]);
+        let older = run(&[
+            ("a", 1, Some("a1")),
+            ("b", 2, Some("b1")),
+            ("c", 3, Some("c1")),
+        ]);
+        let mut it = MergingIter::new(vec![newer, older]);
+
+        it.seek_to_first().unwrap();
+        assert_eq!(
+            collect(&mut it),
+            owned(&[("a", 1), ("b", 7), ("b", 2), ("c", 8), ("c", 3)])
+        );
+
+        it.seek(b"bb").unwrap();
+        assert_eq!(collect(&mut it), owned(&[("c", 8), ("c", 3)]));
+    }
+
+    #[test]
+    fn reverse_and_direction_changes() {
+        let newer = run(&[("b", 7, None), ("d", 8, Some("d2"))]);
+        let older = run(&[
+            ("a", 1, Some("a1")),
+            ("b", 2, Some("b1")),
+            ("c", 3, Some("c1")),
+        ]);
+        let mut it = MergingIter::new(vec![newer, older]);
+
+        it.seek_to_last().unwrap();
+        let mut seen = Vec::new();
+        while it.valid() {
+            seen.push((String::from_utf8(it.key().to_vec()).unwrap(), it.seq()));
+            it.prev().unwrap();
+        }
+        assert_eq!(
+            seen,
+            owned(&[("d", 8), ("c", 3), ("b", 2), ("b", 7), ("a", 1)])
+        );
+
+        it.seek_for_prev(b"bz").unwrap();
+        assert_eq!((it.key(), it.seq()), (&b"b"[..], 2));
+        it.prev().unwrap();
+        assert_eq!((it.key(), it.seq()), (&b"b"[..], 7));
+        assert!(matches!(it.entry(), Entry::Delete));
+        it.next().unwrap();
+        assert_eq!((it.key(), it.seq()), (&b"b"[..], 2));
+        it.next().unwrap();
+        assert_eq!(it.key(), b"c");
+        it.prev().unwrap();
+        assert_eq!((it.key(), it.seq()), (&b"b"[..], 2));
+        it.prev().unwrap();
+        it.prev().unwrap();
+        assert_eq!(it.key(), b"a");
+        it.prev().unwrap();
+        assert!(!it.valid());
+    }
+}
@@ -1,124 +1,505 @@
 use std::{
-    fs::{self, File, OpenOptions},
-    io::{BufRead, BufReader, BufWriter, Result, Write},
+    io::{BufWriter, Error, ErrorKind, Result, Write},
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
+use crate::storage::env::{Env, WritableFile};
+use crate::storage::memtable::SeqNo;
 use crate::storage::sstable::TableId;
 
+/// Starts every binary manifest. Files without it are the text manifests
+/// written before, which are still replayed and then rewritten.
+const MANIFEST_MAGIC: &[u8; 8] = b"ZYNKMAN1";
+
+/// Record header: payload length (u32) followed by the payload crc (u32).
+const HEADER_SIZE: usize = 4 + 4;
+
+const TAG_LOG_NUMBER: u8 = 1;
+const TAG_NEXT_TABLE_ID: u8 = 2;
+const TAG_LAST_SEQUENCE: u8 = 3;
+const TAG_REMOVED: u8 = 4;
+const TAG_ADDED: u8 = 5;
+
+/// The log of version edits describing which tables are live.
+///
+/// The file starts with [`MANIFEST_MAGIC`] followed by records of the form
+/// `len | crc | payload`, one edit per record; a torn or corrupt tail ends
+/// replay, as in the WAL. Each payload is a run of tagged fields. Once the
+/// file grows past `max_bytes` it is replaced by a fresh `MANIFEST-NNNNNN`
+/// holding a single edit that recreates the current state, and `CURRENT` is
+/// switched over to it.
 pub struct Manifest {
-    writer: BufWriter<File>,
-    path: PathBuf,
+    env: Arc<dyn Env>,
+    writer: BufWriter<Box<dyn WritableFile>>,
+    data_dir: PathBuf,
+    number: u64,
+    len: u64,
+    max_bytes: u64,
+    state: ManifestState,
+}
+
+/// A table as the manifest records it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableRecord {
+    pub id: TableId,
+    pub level: usize,
+    pub smallest: Vec<u8>,
+    pub largest: Vec<u8>,
+    pub file_size: u64,
+}
+
+/// A set of changes that must be applied together.
+#[derive(Default, Debug, Clone)]
+pub struct VersionEdit {
+    pub added: Vec<TableRecord>,
+    pub removed: Vec<TableId>,
+    /// Logs numbered below this have been flushed and can be discarded.
+    pub log_number: Option<u64>,
+    pub next_table_id: Option<TableId>,
+    /// Highest sequence number written to a table so far.
+    pub last_sequence: Option<SeqNo>,
+}
+
+/// State reconstructed by replaying a manifest.
+#[derive(Default, Debug, Clone)]
+pub struct ManifestState {
+    /// Live tables, in the order they were added.
+    pub tables: Vec<TableRecord>,
+    /// Logs numbered below this have been flushed and can be discarded.
+    pub log_number: u64,
+    /// No table or log uses a number at or above this.
+    pub next_table_id: TableId,
+    /// Highest sequence number written to a flushed table.
+    pub last_sequence: SeqNo,
+}
+
+impl ManifestState {
+    pub fn apply(&mut self, edit: &VersionEdit) {
+        self.tables.retain(|t| !edit.removed.contains(&t.id));
+        self.tables.extend(edit.added.iter().cloned());
+        if let Some(n) = edit.log_number {
+            self.log_number = self.log_number.max(n);
+        }
+        if let Some(n) = edit.next_table_id {
+            self.next_table_id = self.next_table_id.max(n);
+        }
+        if let Some(n) = edit.last_sequence {
+            self.last_sequence = self.last_sequence.max(n);
+        }
+    }
+
+    /// A single edit that rebuilds this state from nothing.
+    fn snapshot(&self) -> VersionEdit {
+        VersionEdit {
+            added: self.tables.clone(),
+            removed: Vec::new(),
+            log_number: Some(self.log_number),
+            next_table_id: Some(self.next_table_id),
+            last_sequence: Some(self.last_sequence),
+        }
+    }
 }
 
 impl Manifest {
-    pub fn new(path: PathBuf) -> std::io::Result<Self> {
-        let file = std::fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&path)?;
-        Ok(Self {
+    /// Opens the manifest `CURRENT` names under `data_dir`, or starts an
+    /// empty one if there is none. The replayed state is carried over into a
+    /// fresh manifest, so appends never follow a torn tail.
+    pub fn open(env: &Arc<dyn Env>, data_dir: &Path, max_bytes: u64) -> Result<Self> {
+        let Some(number) = current_manifest(env.as_ref(), data_dir)? else {
+            return Self::create(env, data_dir, 1, ManifestState::default(), max_bytes);
+        };
+        let old = data_dir.join(manifest_name(number));
+        let state = replay_manifest(env.as_ref(), &old)?;
+        let manifest = Self::create(env, data_dir, number + 1, state, max_bytes)?;
+        env.remove_file(&old)?;
+        Ok(manifest)
+    }
+
+    /// Writes `state` to a new `MANIFEST-{number}` and points `CURRENT` at it.
+    pub fn create(
+        env: &Arc<dyn Env>,
+        data_dir: &Path,
+        number: u64,
+        state: ManifestState,
+        max_bytes: u64,
+    ) -> Result<Self> {
+        let path = data_dir.join(manifest_name(number));
+        let file = env.create(&path)?;
+        let mut manifest = Self {
+            env: env.clone(),
             writer: BufWriter::new(file),
-            path,
-        })
+            data_dir: data_dir.to_path_buf(),
+            number,
+            len: 0,
+            max_bytes,
+            state: ManifestState::default(),
+        };
+        manifest.writer.write_all(MANIFEST_MAGIC)?;
+        manifest.len = MANIFEST_MAGIC.len() as u64;
+        manifest.append(&state.snapshot())?;
+        fsync_dir(env.as_ref(), &path)?;
+        write_current_atomic(env.as_ref(), data_dir, &manifest_name(number))?;
+        Ok(manifest)
     }
 
-    pub fn record_add_table(&mut self, table_id: TableId) -> Result<()> {
-        writeln!(self.writer, "add {table_id}")?;
-        self.sync()
+    pub fn number(&self) -> u64 {
+        self.number
     }
 
-    pub fn record_remove_table(&mut self, table_id: TableId) -> Result<()> {
-        writeln!(self.writer, "remove {table_id}")?;
-        self.sync()
+    pub fn path(&self) -> PathBuf {
+        self.data_dir.join(manifest_name(self.number))
     }
 
-    pub fn replay_manifest(&mut self) -> Result<Vec<u64>> {
-        let file = std::fs::File::open(&self.path)?;
-        let reader = BufReader::new(file);
-        let mut active = Vec::new();
+    /// The state after every edit recorded so far.
+    pub fn state(&self) -> &ManifestState {
+        &self.state
+    }
 
-        for line in reader.lines() {
-            let line = line?;
-            let parts: Vec<_> = line.split_whitespace().collect();
-            match parts.as_slice() {
-                ["add", id] => {
-                    let id: u64 = id.parse().unwrap();
-                    active.push(id);
-                }
-                ["remove", id] => {
-                    let id: u64 = id.parse().unwrap();
-                    active.retain(|&x| x != id);
-                }
-                _ => {}
-            }
+    /// Durably appends `edit`; it is replayed all or nothing.
+    pub fn record(&mut self, edit: &VersionEdit) -> Result<()> {
+        self.append(edit)?;
+        if self.len > self.max_bytes {
+            self.rotate()?;
         }
-        Ok(active)
+        Ok(())
+    }
+
+    fn append(&mut self, edit: &VersionEdit) -> Result<()> {
+        let payload = encode_edit(edit);
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&payload);
+        let crc = hasher.finalize();
+        self.writer
+            .write_all(&(payload.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&crc.to_le_bytes())?;
+        self.writer.write_all(&payload)?;
+        self.sync()?;
+        self.len += (HEADER_SIZE + payload.len()) as u64;
+        self.state.apply(edit);
+        Ok(())
+    }
+
+    /// Replaces this manifest with a fresh one holding only the current state.
+    fn rotate(&mut self) -> Result<()> {
+        let old = self.path();
+        let next = Self::create(
+            &self.env,
+            &self.data_dir,
+            self.number + 1,
+            self.state.clone(),
+            self.max_bytes,
+        )?;
+        *self = next;
+        self.env.remove_file(&old)
     }
 
     pub fn sync(&mut self) -> Result<()> {
         self.writer.flush()?;
-        self.writer.get_ref().sync_all()?;
+        self.writer.get_mut().sync()?;
         Ok(())
     }
 }
 
+fn encode_edit(edit: &VersionEdit) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (tag, value) in [
+        (TAG_LOG_NUMBER, edit.log_number),
+        (TAG_NEXT_TABLE_ID, edit.next_table_id),
+        (TAG_LAST_SEQUENCE, edit.last_sequence),
+    ] {
+        if let Some(v) = value {
+            out.push(tag);
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+    }
+    for id in &edit.removed {
+        out.push(TAG_REMOVED);
+        out.extend_from_slice(&id.to_le_bytes());
+    }
+    for t in &edit.added {
+        out.push(TAG_ADDED);
+        out.extend_from_slice(&t.id.to_le_bytes());
+        out.extend_from_slice(&(t.level as u32).to_le_bytes());
+        out.extend_from_slice(&t.file_size.to_le_bytes());
+        for key in [&t.smallest, &t.largest] {
+            out.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            out.extend_from_slice(key);
+        }
+    }
+    out
+}
+
+fn decode_edit(payload: &[u8]) -> Option<VersionEdit> {
+    let mut edit = VersionEdit::default();
+    let mut p = 0;
+    while p < payload.len() {
+        let tag = payload[p];
+        p += 1;
+        match tag {
+            TAG_LOG_NUMBER => edit.log_number = Some(take_u64(payload, &mut p)?),
+            TAG_NEXT_TABLE_ID => edit.next_table_id = Some(take_u64(payload, &mut p)?),
+            TAG_LAST_SEQUENCE => edit.last_sequence = Some(take_u64(payload, &mut p)?),
+            TAG_REMOVED => edit.removed.push(take_u64(payload, &mut p)?),
+            TAG_ADDED => {
+                let id = take_u64(payload, &mut p)?;
+                let level = take(payload, &mut p, 4)?;
+                let level = u32::from_le_bytes(level.try_into().unwrap()) as usize;
+                let file_size = take_u64(payload, &mut p)?;
+                let smallest = take_key(payload, &mut p)?;
+                let largest = take_key(payload, &mut p)?;
+                edit.added.push(TableRecord {
+                    id,
+                    level,
+                    smallest,
+                    largest,
+                    file_size,
+                });
+            }
+            _ => return None,
+        }
+    }
+    Some(edit)
+}
+
+fn take<'a>(buf: &'a [u8], p: &mut usize, n: usize) -> Option<&'a [u8]> {
+    let out = buf.get(*p..p.checked_add(n)?)?;
+    *p += n;
+    Some(out)
+}
+
+fn take_u64(buf: &[u8], p: &mut usize) -> Option<u64> {
+    Some(u64::from_le_bytes(take(buf, p, 8)?.try_into().unwrap()))
+}
+
+fn take_key(buf: &[u8], p: &mut usize) -> Option<Vec<u8>> {
+    let len = u32::from_le_bytes(take(buf, p, 4)?.try_into().unwrap()) as usize;
+    Some(take(buf, p, len)?.to_vec())
+}
+
+/// Replays the manifest at `path`, binary or text.
+///
+/// Replay stops at the first short, crc-mismatched or undecodable record,
+/// which is what a crash in the middle of an append leaves behind.
+pub fn replay_manifest(env: &dyn Env, path: &Path) -> Result<ManifestState> {
+    let buf = env.read(path)?;
+    let Some(records) = buf.strip_prefix(MANIFEST_MAGIC) else {
+        return Ok(replay_text_manifest(&String::from_utf8_lossy(&buf)));
+    };
+
+    let mut state = ManifestState::default();
+    let mut p = 0usize;
+    while p + HEADER_SIZE <= records.len() {
+        let len = u32::from_le_bytes(records[p..p + 4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(records[p + 4..p + 8].try_into().unwrap());
+        p += HEADER_SIZE;
+        if p + len > records.len() {
+            break;
+        }
+        let payload = &records[p..p + len];
+        p += len;
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(payload);
+        if hasher.finalize() != crc {
+            break;
+        }
+        match decode_edit(payload) {
+            Some(edit) => state.apply(&edit),
+            None => break,
+        }
+    }
+    Ok(state)
+}
+
+/// Replays a manifest of `add N` / `remove N` lines, all of them level-0
+/// tables. These carry no key ranges or sizes; the caller reads them from
+/// the tables.
+fn replay_text_manifest(contents: &str) -> ManifestState {
+    let mut state = ManifestState::default();
+    let table = |id: TableId| TableRecord {
+        id,
+        level: 0,
+        smallest: Vec::new(),
+        largest: Vec::new(),
+        file_size: 0,
+    };
+    // A line without its newline is a torn append and was never acknowledged.
+    let complete = match contents.rfind('\n') {
+        Some(end) => &contents[..end],
+        None => "",
+    };
+    for line in complete.lines() {
+        let parts: Vec<_> = line.split_whitespace().collect();
+        let mut edit = VersionEdit::default();
+        match parts.as_slice() {
+            ["add", id] => edit.added.extend(id.parse().ok().map(table)),
+            ["remove", id] => edit.removed.extend(id.parse::<TableId>().ok()),
+            _ => {}
+        }
+        state.apply(&edit);
+    }
+    state
+}
+
+pub fn manifest_name(number: u64) -> String {
+    format!("MANIFEST-{number:06}")
+}
+
+/// The number of the manifest `CURRENT` names under `data_dir`, or `None`
+/// if there is no manifest yet.
+pub fn current_manifest(env: &dyn Env, data_dir: &Path) -> Result<Option<u64>> {
+    let current = current_path(data_dir);
+    if !env.exists(&current) {
+        // Written by an engine that never pointed CURRENT at it.
+        return Ok(env.exists(&data_dir.join(manifest_name(1))).then_some(1));
+    }
+    let name = String::from_utf8_lossy(&env.read(&current)?)
+        .trim()
+        .to_string();
+    match parse_manifest_name(&name) {
+        Some(number) => Ok(Some(number)),
+        None => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("bad CURRENT: {name:?}"),
+        )),
+    }
+}
+
+/// Parses a manifest file name of the form `MANIFEST-NNNNNN`.
+pub fn parse_manifest_name(name: &str) -> Option<u64> {
+    name.strip_prefix("MANIFEST-")?.parse().ok()
+}
+
 pub fn current_path(data_dir: &Path) -> PathBuf {
     data_dir.join("CURRENT")
 }
 
 /// Fsyncs the parent directory of the given path.
-pub fn fsync_dir(path: &Path) -> Result<()> {
-    if let Some(dir) = path.parent() {
-        let dfile = File::open(dir)?;
-        dfile.sync_all()?;
+pub fn fsync_dir(env: &dyn Env, path: &Path) -> Result<()> {
+    match path.parent() {
+        Some(dir) => env.sync_dir(dir),
+        None => Ok(()),
     }
-    Ok(())
 }
 
 /// Atomically writes CURRENT to point to the given manifest name.
-pub fn write_current_atomic(data_dir: &Path, manifest_name: &str) -> Result<()> {
+pub fn write_current_atomic(env: &dyn Env, data_dir: &Path, manifest_name: &str) -> Result<()> {
     let current = current_path(data_dir);
     let tmp = data_dir.join("CURRENT.tmp");
     {
-        let mut f = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(&tmp)?;
+        let mut f = env.create(&tmp)?;
         writeln!(f, "{manifest_name}")?;
         f.flush()?;
-        f.sync_all()?;
+        f.sync()?;
     }
-    fs::rename(&tmp, &current)?;
-    fsync_dir(&current)?;
+    env.rename(&tmp, &current)?;
+    fsync_dir(env, &current)?;
     Ok(())
 }
 
-/// Reads CURRENT to get the active manifest name; if CURRENT doesn't exist,
-/// initializes it to the provided initial manifest name and creates that file.
-pub fn read_current_or_init(data_dir: &Path, initial_manifest_name: &str) -> Result<String> {
-    let current = current_path(data_dir);
-    if current.exists() {
-        let mut s = String::new();
-        let mut f = File::open(&current)?;
-        use std::io::Read;
-        f.read_to_string(&mut s)?;
-        return Ok(s.trim().to_string());
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::env::RealEnv;
+    use crate::test_util::tmp_dir;
+    use std::fs;
+
+    fn table(id: TableId, level: usize) -> TableRecord {
+        TableRecord {
+            id,
+            level,
+            smallest: format!("a{id}").into_bytes(),
+            largest: format!("z{id}").into_bytes(),
+            file_size: id * 100,
+        }
     }
 
-    let manifest_path = data_dir.join(initial_manifest_name);
-    if !manifest_path.exists() {
-        let _ = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&manifest_path)?;
+    #[test]
+    fn replays_edits_and_tolerates_a_torn_tail() {
+        let dir = tmp_dir("manifest-torn");
+        fs::create_dir_all(&dir).unwrap();
+        let env: Arc<dyn Env> = Arc::new(RealEnv);
+        let path = {
+            let mut m = Manifest::open(&env, &dir, 1 << 20).unwrap();
+            m.record(&VersionEdit {
+                added: vec![table(3, 0), table(4, 0)],
+                log_number: Some(2),
+                last_sequence: Some(10),
+                ..VersionEdit::default()
+            })
+            .unwrap();
+            m.record(&VersionEdit {
+                added: vec![table(5, 1)],
+                removed: vec![3, 4],
+                next_table_id: Some(6),
+                ..VersionEdit::default()
+            })
+            .unwrap();
+            m.record(&VersionEdit {
+                added: vec![table(7, 0)],
+                ..VersionEdit::default()
+            })
+            .unwrap();
+            m.path()
+        };
+        let full = fs::read(&path).unwrap();
+        fs::write(&path, &full[..full.len() - 3]).unwrap();
+
+        let state = replay_manifest(env.as_ref(), &path).unwrap();
+        assert_eq!(state.tables, vec![table(5, 1)]);
+        assert_eq!(
+            (state.log_number, state.next_table_id, state.last_sequence),
+            (2, 6, 10)
+        );
+
+        // Reopening moves the state into a fresh manifest past the torn tail.
+        let mut m = Manifest::open(&env, &dir, 1 << 20).unwrap();
+        assert_eq!(m.number(), 2);
+        assert!(!path.exists());
+        m.record(&VersionEdit {
+            added: vec![table(8, 0)],
+            ..VersionEdit::default()
+        })
+        .unwrap();
+        let state = replay_manifest(env.as_ref(), &m.path()).unwrap();
+        assert_eq!(state.tables, vec![table(5, 1), table(8, 0)]);
+        let _ = fs::remove_dir_all(&dir);
     }
-    write_current_atomic(data_dir, initial_manifest_name)?;
-    Ok(initial_manifest_name.to_string())
-}
 
-/// Opens a Manifest for appending using a manifest file name resolved under the data dir.
-pub fn open_manifest_append(data_dir: &Path, manifest_name: &str) -> Result<Manifest> {
-    Manifest::new(data_dir.join(manifest_name))
+    #[test]
+    fn rotates_once_over_the_size_limit() {
+        let dir = tmp_dir("manifest-rotate");
+        fs::create_dir_all(&dir).unwrap();
+        let env: Arc<dyn Env> = Arc::new(RealEnv);
+        let mut m = Manifest::open(&env, &dir, 512).unwrap();
+        for id in 1..=40 {
+            m.record(&VersionEdit {
+                added: vec![table(id + 1, 0)],
+                removed: vec![id],
+                ..VersionEdit::default()
+            })
+            .unwrap();
+        }
+        assert!(m.number() > 1);
+        assert!(fs::metadata(m.path()).unwrap().len() <= 512);
+        let current = fs::read_to_string(current_path(&dir)).unwrap();
+        assert_eq!(current.trim(), manifest_name(m.number()));
+        assert!(!dir.join(manifest_name(m.number() - 1)).exists());
+        assert_eq!(
+            replay_manifest(env.as_ref(), &m.path()).unwrap().tables,
+            vec![table(41, 0)]
+        );
+
+        // Text manifests from older versions are still understood.
+        fs::write(
+            dir.join("MANIFEST-000100"),
+            "add 3\nadd 4\nremove 3\nadd 7\nadd 8",
+        )
+        .unwrap();
+        write_current_atomic(env.as_ref(), &dir, "MANIFEST-000100").unwrap();
+        let m = Manifest::open(&env, &dir, 512).unwrap();
+        let ids: Vec<_> = m.state().tables.iter().map(|t| (t.id, t.level)).collect();
+        assert_eq!(ids, [(4, 0), (7, 0)]);
+        let _ = fs::remove_dir_all(&dir);
+    }
 }
@@ -1,6 +1,8 @@
-use super::table::{Entry, MemTable};
+use super::table::MemTable;
+use crate::storage::compaction::VersionGc;
+use crate::storage::env::Env;
 use crate::storage::sstable::builder::SsTableBuilder;
-use crate::storage::sstable::TableId;
+use crate::storage::sstable::{TableId, TableOptions};
 use std::path::Path;
 
 pub struct FlushResult {
@@ -10,30 +12,33 @@ pub struct FlushResult {
     pub file_len: u64,
 }
 
+/// Writes `mem` to a table, keeping only the versions `gc` says someone can
+/// still see.
 pub fn flush_memtable_to_sstable(
-    mem: MemTable,
+    env: &dyn Env,
+    mem: &MemTable,
+    gc: &VersionGc,
     tmp_path: &Path,
-    block_size: usize,
+    opts: &TableOptions,
 ) -> std::io::Result<FlushResult> {
-    let mut builder = SsTableBuilder::new(tmp_path, block_size);
+    let mut builder = SsTableBuilder::create(env, tmp_path, opts)?;
     let mut smallest: Option<Vec<u8>> = None;
     let mut largest: Option<Vec<u8>> = None;
-    for (k, v) in mem.iter() {
-        if smallest.is_none() {
-            smallest = Some(k.clone());
+    mem.for_each_key(|k, versions| {
+        // Older versions may live in tables, so nothing is final here.
+        for (seq, entry) in gc.collapse(k, versions.to_vec(), false) {
+            builder.add_entry(k, seq, &entry);
         }
-        largest = Some(k.clone());
-        match v {
-            Entry::Put(val) => builder.add_put(k, val),
-            Entry::Delete => builder.add_delete(k),
+        if smallest.is_none() {
+            smallest = Some(k.to_vec());
         }
-    }
+        largest = Some(k.to_vec());
+    });
     let (id, _index_handle) = builder.finish()?;
-    let meta = std::fs::metadata(tmp_path)?;
     Ok(FlushResult {
         id,
         smallest: smallest.unwrap_or_default(),
         largest: largest.unwrap_or_default(),
-        file_len: meta.len(),
+        file_len: env.file_size(tmp_path)?,
     })
 }
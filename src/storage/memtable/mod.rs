@@ -4,4 +4,4 @@ pub mod table;
 
 pub use flush::{flush_memtable_to_sstable, FlushResult};
 pub use set::MemTableSet;
-pub use table::{Entry, MemTable};
+pub use table::{now_millis, Entry, MemTable, MemTableIter, SeqNo};
@@ -1,15 +1,17 @@
-use super::table::{Entry, MemTable};
+use super::table::{Entry, MemTable, SeqNo};
+use std::sync::Arc;
 
 pub struct MemTableSet {
-    active: MemTable,
-    immutables: Vec<MemTable>,
+    active: Arc<MemTable>,
+    /// Frozen memtables waiting to be flushed, oldest first.
+    immutables: Vec<Arc<MemTable>>,
     max_bytes: usize,
 }
 
 impl MemTableSet {
     pub fn with_capacity(max_bytes: usize) -> Self {
         Self {
-            active: MemTable::new(max_bytes),
+            active: Arc::new(MemTable::new(max_bytes)),
             immutables: Vec::new(),
             max_bytes,
         }
@@ -23,44 +25,69 @@ impl MemTableSet {
         self.immutables.len()
     }
 
-    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Option<MemTable> {
-        self.active.put(key, value);
+    pub fn put(&mut self, key: &[u8], seq: SeqNo, value: &[u8]) -> Option<Arc<MemTable>> {
+        self.active.put(key, seq, value);
         if self.active.over_threshold() {
             return self.rotate();
         }
         None
     }
 
-    pub fn delete(&mut self, key: &[u8]) -> Option<MemTable> {
-        self.active.delete(key);
+    pub fn delete(&mut self, key: &[u8], seq: SeqNo) -> Option<Arc<MemTable>> {
+        self.active.delete(key, seq);
         if self.active.over_threshold() {
             return self.rotate();
         }
         None
     }
 
-    pub fn rotate(&mut self) -> Option<MemTable> {
+    /// Applies every entry to the active memtable, numbered consecutively
+    /// from `first_seq`, before checking the size threshold, so a batch never
+    /// straddles a rotation.
+    pub fn apply(
+        &mut self,
+        entries: &[(Vec<u8>, Entry)],
+        first_seq: SeqNo,
+    ) -> Option<Arc<MemTable>> {
+        for ((key, entry), seq) in entries.iter().zip(first_seq..) {
+            self.active.insert(key, seq, entry.clone());
+        }
+        if self.active.over_threshold() {
+            return self.rotate();
+        }
+        None
+    }
+
+    pub fn rotate(&mut self) -> Option<Arc<MemTable>> {
         if self.active.is_empty() {
             return None;
         }
-        let frozen = std::mem::replace(&mut self.active, MemTable::new(self.max_bytes));
+        let fresh = Arc::new(MemTable::new(self.max_bytes));
+        let frozen = std::mem::replace(&mut self.active, fresh);
         self.immutables.push(frozen);
         self.immutables.last().cloned()
     }
 
-    pub fn pop_immutable(&mut self) -> Option<MemTable> {
-        self.immutables.pop()
+    /// The next memtable to flush.
+    pub fn oldest_immutable(&self) -> Option<Arc<MemTable>> {
+        self.immutables.first().cloned()
     }
 
-    pub fn get(&self, key: &[u8]) -> Option<&Entry> {
-        if let Some(e) = self.active.get(key) {
-            return Some(e);
-        }
-        for mt in self.immutables.iter().rev() {
-            if let Some(e) = mt.get(key) {
-                return Some(e);
-            }
+    /// Drops the oldest immutable once its table is installed.
+    pub fn pop_oldest_immutable(&mut self) -> Option<Arc<MemTable>> {
+        if self.immutables.is_empty() {
+            return None;
         }
-        None
+        Some(self.immutables.remove(0))
+    }
+
+    /// The active memtable followed by the immutables, newest first.
+    pub fn newest_first(&self) -> impl Iterator<Item = &Arc<MemTable>> {
+        std::iter::once(&self.active).chain(self.immutables.iter().rev())
+    }
+
+    /// The newest version of `key` numbered `seq` or below.
+    pub fn get(&self, key: &[u8], seq: SeqNo) -> Option<Entry> {
+        self.newest_first().find_map(|mt| mt.get(key, seq))
     }
 }
@@ -1,91 +1,286 @@
+use crate::storage::iter::EntryIter;
 use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Clone)]
 pub enum Entry {
     Put(Vec<u8>),
+    /// A value that reads as absent, like a tombstone, from `expires_at`
+    /// milliseconds since the Unix epoch.
+    Expiring {
+        value: Vec<u8>,
+        expires_at: u64,
+    },
     Delete,
+    /// An operand for the engine's [`MergeOperator`], applied on top of
+    /// whatever lies beneath it.
+    ///
+    /// [`MergeOperator`]: crate::storage::merge::MergeOperator
+    Merge(Vec<u8>),
 }
 
-#[derive(Clone)]
+impl Entry {
+    pub fn is_expired(&self, now: u64) -> bool {
+        matches!(self, Entry::Expiring { expires_at, .. } if *expires_at <= now)
+    }
+
+    /// The value a read at time `now` sees: `None` for a tombstone or an
+    /// expired value. Merge operands must have been folded by then; one left
+    /// over reads as `None` too.
+    pub fn live_value(&self, now: u64) -> Option<&[u8]> {
+        match self {
+            Entry::Put(v) => Some(v),
+            Entry::Expiring { value, expires_at } if *expires_at > now => Some(value),
+            Entry::Expiring { .. } | Entry::Delete | Entry::Merge(_) => None,
+        }
+    }
+
+    pub fn into_live_value(self, now: u64) -> Option<Vec<u8>> {
+        match self {
+            Entry::Put(v) => Some(v),
+            Entry::Expiring { value, expires_at } if expires_at > now => Some(value),
+            Entry::Expiring { .. } | Entry::Delete | Entry::Merge(_) => None,
+        }
+    }
+}
+
+/// Milliseconds since the Unix epoch, the clock expiry times are kept in.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Orders every mutation. Reads at sequence `n` see exactly the writes
+/// numbered `n` or below; `0` is never assigned to a write.
+pub type SeqNo = u64;
+
+/// Per-entry bookkeeping charged on top of key and value bytes.
+const ENTRY_OVERHEAD: usize = 1 + 4 + 4 + 8;
+
+type Versions = BTreeMap<Vec<u8>, Vec<(SeqNo, Entry)>>;
+
+/// Sorted in-memory writes. Every version of a key is kept, newest first, so
+/// that snapshots taken before an overwrite still see the older value.
+///
+/// The writes sit behind a lock of their own, so a [`MemTableIter`] can go
+/// on reading a memtable it shares with the writers.
 pub struct MemTable {
-    map: BTreeMap<Vec<u8>, Entry>,
-    bytes_used: usize,
+    inner: RwLock<Inner>,
     max_bytes: usize,
 }
 
+struct Inner {
+    map: Versions,
+    bytes_used: usize,
+    max_seq: SeqNo,
+}
+
 impl MemTable {
     pub fn new(max_bytes: usize) -> Self {
         Self {
-            map: BTreeMap::new(),
-            bytes_used: 0,
+            inner: RwLock::new(Inner {
+                map: BTreeMap::new(),
+                bytes_used: 0,
+                max_seq: 0,
+            }),
             max_bytes,
         }
     }
 
+    fn read(&self) -> RwLockReadGuard<'_, Inner> {
+        self.inner.read().unwrap()
+    }
+
+    /// Number of distinct keys.
     pub fn len(&self) -> usize {
-        self.map.len()
+        self.read().map.len()
     }
 
     pub fn is_empty(&self) -> bool {
-        self.map.is_empty()
+        self.read().map.is_empty()
     }
 
     pub fn bytes_used(&self) -> usize {
-        self.bytes_used
+        self.read().bytes_used
     }
 
     pub fn max_bytes(&self) -> usize {
         self.max_bytes
     }
 
-    pub fn put(&mut self, key: &[u8], value: &[u8]) {
-        self.adjust_remove(key);
-        self.bytes_used += 1 + 4 + 4 + key.len() + value.len();
-        self.map.insert(key.to_vec(), Entry::Put(value.to_vec()));
+    /// Highest sequence number written here, `0` while empty.
+    pub fn max_seq(&self) -> SeqNo {
+        self.read().max_seq
+    }
+
+    pub fn put(&self, key: &[u8], seq: SeqNo, value: &[u8]) {
+        self.insert(key, seq, Entry::Put(value.to_vec()));
     }
 
-    pub fn delete(&mut self, key: &[u8]) {
-        self.adjust_remove(key);
-        self.bytes_used += 1 + 4 + 4 + key.len();
-        self.map.insert(key.to_vec(), Entry::Delete);
+    pub fn delete(&self, key: &[u8], seq: SeqNo) {
+        self.insert(key, seq, Entry::Delete);
     }
 
-    pub fn get(&self, key: &[u8]) -> Option<&Entry> {
-        self.map.get(key)
+    pub fn insert(&self, key: &[u8], seq: SeqNo, entry: Entry) {
+        let value_len = match &entry {
+            Entry::Put(v) | Entry::Merge(v) => v.len(),
+            Entry::Expiring { value, .. } => value.len() + 8,
+            Entry::Delete => 0,
+        };
+        let mut inner = self.inner.write().unwrap();
+        inner.bytes_used += ENTRY_OVERHEAD + key.len() + value_len;
+        inner.max_seq = inner.max_seq.max(seq);
+        let versions = inner.map.entry(key.to_vec()).or_default();
+        let pos = versions.partition_point(|&(s, _)| s > seq);
+        versions.insert(pos, (seq, entry));
+    }
+
+    /// The newest version of `key` numbered `seq` or below.
+    pub fn get(&self, key: &[u8], seq: SeqNo) -> Option<Entry> {
+        self.read()
+            .map
+            .get(key)?
+            .iter()
+            .find(|&&(s, _)| s <= seq)
+            .map(|(_, e)| e.clone())
     }
 
     pub fn contains_key(&self, key: &[u8]) -> bool {
-        self.map.contains_key(key)
+        self.read().map.contains_key(key)
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, &Entry)> {
-        self.map.iter()
+    /// Calls `f` with each key in order and its versions, newest first.
+    pub fn for_each_key(&self, mut f: impl FnMut(&[u8], &[(SeqNo, Entry)])) {
+        for (key, versions) in &self.read().map {
+            f(key, versions);
+        }
     }
 
-    pub fn smallest_key(&self) -> Option<&[u8]> {
-        self.map.keys().next().map(|k| k.as_slice())
+    pub fn smallest_key(&self) -> Option<Vec<u8>> {
+        self.read().map.keys().next().cloned()
     }
 
-    pub fn largest_key(&self) -> Option<&[u8]> {
-        self.map.keys().next_back().map(|k| k.as_slice())
+    pub fn largest_key(&self) -> Option<Vec<u8>> {
+        self.read().map.keys().next_back().cloned()
     }
 
     pub fn over_threshold(&self) -> bool {
-        self.bytes_used >= self.max_bytes
-    }
-
-    fn adjust_remove(&mut self, key: &[u8]) {
-        if let Some(prev) = self.map.get(key) {
-            match prev {
-                Entry::Put(v) => {
-                    self.bytes_used = self
-                        .bytes_used
-                        .saturating_sub(1 + 4 + 4 + key.len() + v.len());
-                }
-                Entry::Delete => {
-                    self.bytes_used = self.bytes_used.saturating_sub(1 + 4 + 4 + key.len());
-                }
+        self.bytes_used() >= self.max_bytes
+    }
+}
+
+/// Cursor over a shared memtable that copies out one version at a time.
+///
+/// Each step looks the next version up again, so writes made meanwhile may
+/// show up ahead of the cursor; they are numbered above any reader's
+/// sequence number and read as invisible.
+pub struct MemTableIter {
+    mem: Arc<MemTable>,
+    current: Option<(Vec<u8>, SeqNo, Entry)>,
+}
+
+impl MemTableIter {
+    pub fn new(mem: Arc<MemTable>) -> Self {
+        Self { mem, current: None }
+    }
+
+    fn step(&mut self, find: impl FnOnce(&Versions) -> Option<(&Vec<u8>, &(SeqNo, Entry))>) {
+        let inner = self.mem.read();
+        self.current = find(&inner.map).map(|(k, (s, e))| (k.clone(), *s, e.clone()));
+    }
+}
+
+fn newest<'a>(
+    (key, versions): (&'a Vec<u8>, &'a Vec<(SeqNo, Entry)>),
+) -> (&'a Vec<u8>, &'a (SeqNo, Entry)) {
+    (key, &versions[0])
+}
+
+fn oldest<'a>(
+    (key, versions): (&'a Vec<u8>, &'a Vec<(SeqNo, Entry)>),
+) -> (&'a Vec<u8>, &'a (SeqNo, Entry)) {
+    (key, &versions[versions.len() - 1])
+}
+
+impl EntryIter for MemTableIter {
+    fn valid(&self) -> bool {
+        self.current.is_some()
+    }
+
+    fn key(&self) -> &[u8] {
+        &self.current.as_ref().unwrap().0
+    }
+
+    fn seq(&self) -> SeqNo {
+        self.current.as_ref().unwrap().1
+    }
+
+    fn entry(&self) -> &Entry {
+        &self.current.as_ref().unwrap().2
+    }
+
+    fn seek_to_first(&mut self) -> std::io::Result<()> {
+        self.step(|map| map.iter().next().map(newest));
+        Ok(())
+    }
+
+    fn seek(&mut self, key: &[u8]) -> std::io::Result<()> {
+        self.step(|map| {
+            map.range::<[u8], _>((Bound::Included(key), Bound::Unbounded))
+                .next()
+                .map(newest)
+        });
+        Ok(())
+    }
+
+    fn next(&mut self) -> std::io::Result<()> {
+        let Some((key, seq, _)) = self.current.take() else {
+            return Ok(());
+        };
+        self.step(|map| {
+            let (k, versions) = map.get_key_value(&key)?;
+            match versions.iter().find(|&&(s, _)| s < seq) {
+                Some(older) => Some((k, older)),
+                None => map
+                    .range::<[u8], _>((Bound::Excluded(&key[..]), Bound::Unbounded))
+                    .next()
+                    .map(newest),
             }
-        }
+        });
+        Ok(())
+    }
+
+    fn seek_to_last(&mut self) -> std::io::Result<()> {
+        self.step(|map| map.iter().next_back().map(oldest));
+        Ok(())
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) -> std::io::Result<()> {
+        self.step(|map| {
+            map.range::<[u8], _>((Bound::Unbounded, Bound::Included(key)))
+                .next_back()
+                .map(oldest)
+        });
+        Ok(())
+    }
+
+    fn prev(&mut self) -> std::io::Result<()> {
+        let Some((key, seq, _)) = self.current.take() else {
+            return Ok(());
+        };
+        self.step(|map| {
+            let (k, versions) = map.get_key_value(&key)?;
+            match versions.partition_point(|&(s, _)| s > seq) {
+                0 => map
+                    .range::<[u8], _>((Bound::Unbounded, Bound::Excluded(&key[..])))
+                    .next_back()
+                    .map(oldest),
+                n => Some((k, &versions[n - 1])),
+            }
+        });
+        Ok(())
     }
 }
@@ -0,0 +1,150 @@
+use crate::storage::memtable::{Entry, SeqNo};
+
+/// Combines the operands [`LsmEngine::merge`] writes with the value beneath
+/// them. Reads apply it on the fly; flushes and compactions apply it to
+/// shorten the chains of operands they rewrite.
+///
+/// Operators must be deterministic, and a data dir must always be opened
+/// with the operator that wrote it.
+///
+/// [`LsmEngine::merge`]: crate::engine::kv::LsmEngine::merge
+pub trait MergeOperator: Send + Sync + std::fmt::Debug {
+    fn name(&self) -> &'static str;
+
+    /// The value `key` holds once `operands`, oldest first, are applied on
+    /// top of `existing`, which is `None` when the key has no live value.
+    /// Fails with [`InvalidData`](std::io::ErrorKind::InvalidData) if the
+    /// operands and value don't make sense together.
+    fn full_merge(
+        &self,
+        key: &[u8],
+        existing: Option<&[u8]>,
+        operands: &[&[u8]],
+    ) -> std::io::Result<Vec<u8>>;
+
+    /// One operand with the effect of `older` followed by `newer`, if the
+    /// operator can combine them without knowing the value beneath.
+    fn partial_merge(&self, _key: &[u8], _older: &[u8], _newer: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Vets an operand before it is written, failing with
+    /// [`InvalidInput`](std::io::ErrorKind::InvalidInput) if no merge could
+    /// use it.
+    fn check_operand(&self, _key: &[u8], _operand: &[u8]) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// What a read at time `now` sees of `key`, given the versions visible to
+/// it, newest first: the newest version, with any merge operands on top
+/// applied to the first version below them that isn't one.
+pub fn resolve(
+    op: &dyn MergeOperator,
+    key: &[u8],
+    versions: &[Entry],
+    now: u64,
+) -> std::io::Result<Option<Vec<u8>>> {
+    let base = versions.iter().position(|e| !matches!(e, Entry::Merge(_)));
+    let (operands, base) = match base {
+        Some(0) => return Ok(versions[0].live_value(now).map(<[u8]>::to_vec)),
+        Some(i) => (&versions[..i], versions[i].live_value(now)),
+        None if versions.is_empty() => return Ok(None),
+        None => (versions, None),
+    };
+    let operands = operands_oldest_first(operands.iter());
+    op.full_merge(key, base, &operands).map(Some)
+}
+
+/// The merge operands among `entries`, newest first, as the oldest-first
+/// slices `full_merge` takes. Any other entry is skipped.
+pub(crate) fn operands_oldest_first<'a>(
+    entries: impl DoubleEndedIterator<Item = &'a Entry>,
+) -> Vec<&'a [u8]> {
+    entries
+        .rev()
+        .filter_map(|e| match e {
+            Entry::Merge(operand) => Some(operand.as_slice()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Combines adjacent operands of a chain, newest first, wherever `op` can.
+/// Each combined operand keeps the newest sequence number it covers.
+pub(crate) fn partial_merge_chain(
+    op: &dyn MergeOperator,
+    key: &[u8],
+    chain: Vec<(SeqNo, Entry)>,
+) -> Vec<(SeqNo, Entry)> {
+    let mut out: Vec<(SeqNo, Entry)> = Vec::with_capacity(chain.len());
+    for (seq, entry) in chain.into_iter().rev() {
+        if let (Some((last_seq, Entry::Merge(older))), Entry::Merge(newer)) =
+            (out.last_mut(), &entry)
+        {
+            if let Some(combined) = op.partial_merge(key, older, newer) {
+                *older = combined;
+                *last_seq = seq;
+                continue;
+            }
+        }
+        out.push((seq, entry));
+    }
+    out.reverse();
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Operands are decimal numbers added to the value.
+    #[derive(Debug)]
+    struct Sum;
+
+    impl MergeOperator for Sum {
+        fn name(&self) -> &'static str {
+            "sum"
+        }
+
+        fn full_merge(
+            &self,
+            _key: &[u8],
+            existing: Option<&[u8]>,
+            operands: &[&[u8]],
+        ) -> std::io::Result<Vec<u8>> {
+            let parse = |b: &[u8]| -> i64 { std::str::from_utf8(b).unwrap().parse().unwrap() };
+            let total: i64 =
+                existing.map_or(0, parse) + operands.iter().map(|o| parse(o)).sum::<i64>();
+            Ok(total.to_string().into_bytes())
+        }
+    }
+
+    fn merge(v: &str) -> Entry {
+        Entry::Merge(v.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn resolve_applies_operands_to_the_first_base() {
+        let read = |versions: &[Entry]| {
+            resolve(&Sum, b"k", versions, 0)
+                .unwrap()
+                .map(|v| String::from_utf8(v).unwrap())
+        };
+        assert_eq!(read(&[]), None);
+        assert_eq!(
+            read(&[Entry::Put(b"5".to_vec()), merge("1")]).as_deref(),
+            Some("5")
+        );
+        assert_eq!(read(&[merge("1"), merge("2")]).as_deref(), Some("3"));
+        assert_eq!(
+            read(&[merge("1"), Entry::Put(b"10".to_vec()), merge("100")]).as_deref(),
+            Some("11")
+        );
+        assert_eq!(
+            read(&[merge("1"), Entry::Delete, merge("100")]).as_deref(),
+            Some("1")
+        );
+        assert_eq!(read(&[Entry::Delete, merge("1")]), None);
+    }
+}
@@ -1,3 +1,9 @@
+pub mod compaction;
+pub mod env;
+pub mod iter;
 pub mod manifest;
 pub mod memtable;
+pub mod merge;
 pub mod sstable;
+pub mod version;
+pub mod wal;
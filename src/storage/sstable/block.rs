@@ -1,9 +1,48 @@
+use crate::storage::memtable::{Entry, SeqNo};
+use crate::storage::sstable::cache::DecodedBlock;
+use crate::storage::sstable::compression::{codec_by_id, BlockCodec};
 use crc32fast::Hasher;
+use std::io::{Error, ErrorKind, Result};
 
+/// Every `RESTART_INTERVAL`th record of a block stores its whole key; the
+/// records in between only store what differs from the key before them.
+pub const RESTART_INTERVAL: usize = 16;
+
+/// How the records inside a data block are laid out.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BlockFormat {
+    /// `op u8 | key_len u32 | value_len u32 | key | value` records, written
+    /// by format versions 1 and 2.
+    Plain,
+    /// `op u8 | shared varint | unshared varint | value_len varint | key
+    /// suffix | value` records followed by the restart array, the offsets of
+    /// the full-key records as `u32`s and then their count as a `u32`.
+    /// Written by versions 3 and 4.
+    Prefixed,
+    /// Like `Prefixed`, with a `seq varint` after `value_len`. Versions of
+    /// a key are stored newest first and never split across blocks. From
+    /// version 6, op `2` marks a value with an `expires_at varint` after
+    /// `seq`; from version 7, op `3` marks a merge operand.
+    Versioned,
+}
+
+impl BlockFormat {
+    pub fn for_version(version: u32) -> Self {
+        match version {
+            0..=2 => BlockFormat::Plain,
+            3 | 4 => BlockFormat::Prefixed,
+            _ => BlockFormat::Versioned,
+        }
+    }
+}
+
+/// Builds a [`BlockFormat::Versioned`] block.
 pub struct DataBlock {
     target_bytes: usize,
     payload: Vec<u8>,
     entries: usize,
+    last_key: Vec<u8>,
+    restarts: Vec<u32>,
 }
 
 impl DataBlock {
@@ -12,47 +51,422 @@ impl DataBlock {
             target_bytes,
             payload: Vec::with_capacity(target_bytes),
             entries: 0,
+            last_key: Vec::new(),
+            restarts: Vec::new(),
         }
     }
 
-    pub fn add_put(&mut self, key: &[u8], value: &[u8]) {
-        self.payload.push(0);
-        self.payload
-            .extend_from_slice(&(key.len() as u32).to_le_bytes());
-        self.payload
-            .extend_from_slice(&(value.len() as u32).to_le_bytes());
-        self.payload.extend_from_slice(key);
-        self.payload.extend_from_slice(value);
-        self.entries += 1;
+    pub fn add_put(&mut self, key: &[u8], seq: SeqNo, value: &[u8]) {
+        self.add(0, key, seq, None, value);
+    }
+
+    pub fn add_expiring(&mut self, key: &[u8], seq: SeqNo, value: &[u8], expires_at: u64) {
+        self.add(2, key, seq, Some(expires_at), value);
     }
 
-    pub fn add_delete(&mut self, key: &[u8]) {
-        self.payload.push(1);
-        self.payload
-            .extend_from_slice(&(key.len() as u32).to_le_bytes());
-        self.payload.extend_from_slice(&0u32.to_le_bytes());
-        self.payload.extend_from_slice(key);
+    pub fn add_delete(&mut self, key: &[u8], seq: SeqNo) {
+        self.add(1, key, seq, None, &[]);
+    }
+
+    pub fn add_merge(&mut self, key: &[u8], seq: SeqNo, operand: &[u8]) {
+        self.add(3, key, seq, None, operand);
+    }
+
+    /// The last key added, empty if the block is.
+    pub fn last_key(&self) -> &[u8] {
+        &self.last_key
+    }
+
+    fn add(&mut self, op: u8, key: &[u8], seq: SeqNo, expires_at: Option<u64>, value: &[u8]) {
+        let shared = if self.entries.is_multiple_of(RESTART_INTERVAL) {
+            self.restarts.push(self.payload.len() as u32);
+            0
+        } else {
+            self.last_key
+                .iter()
+                .zip(key)
+                .take_while(|(a, b)| a == b)
+                .count()
+        };
+        self.payload.push(op);
+        put_varint(&mut self.payload, shared as u64);
+        put_varint(&mut self.payload, (key.len() - shared) as u64);
+        put_varint(&mut self.payload, value.len() as u64);
+        put_varint(&mut self.payload, seq);
+        if let Some(expires_at) = expires_at {
+            put_varint(&mut self.payload, expires_at);
+        }
+        self.payload.extend_from_slice(&key[shared..]);
+        self.payload.extend_from_slice(value);
+        self.last_key.truncate(shared);
+        self.last_key.extend_from_slice(&key[shared..]);
         self.entries += 1;
     }
 
     pub fn is_full(&self) -> bool {
-        self.payload.len() >= self.target_bytes && self.entries > 0
+        self.payload_len() >= self.target_bytes && self.entries > 0
     }
 
-    pub fn encode(self) -> Vec<u8> {
-        let mut hasher = Hasher::new();
-        hasher.update(&self.payload);
-        let crc = hasher.finalize();
-        let mut out = self.payload;
-        out.extend_from_slice(&crc.to_le_bytes());
-        out
+    pub fn encode(self, codec: &dyn BlockCodec) -> Vec<u8> {
+        let mut payload = self.payload;
+        for restart in &self.restarts {
+            payload.extend_from_slice(&restart.to_le_bytes());
+        }
+        payload.extend_from_slice(&(self.restarts.len() as u32).to_le_bytes());
+        seal_block(&payload, codec)
     }
 
     pub fn len(&self) -> usize {
         self.entries
     }
 
+    /// Encoded size so far, restart array included.
+    pub fn payload_len(&self) -> usize {
+        self.payload.len() + 4 * self.restarts.len() + 4
+    }
+
     pub fn is_empty(&self) -> bool {
         self.entries == 0
     }
 }
+
+/// Frames a block payload as `data | compression type u8 | crc u32`. The
+/// payload is stored raw instead when `codec` saves less than an eighth.
+pub fn seal_block(payload: &[u8], codec: &dyn BlockCodec) -> Vec<u8> {
+    let compressed = match codec.id() {
+        0 => None,
+        _ => Some(codec.compress(payload)).filter(|c| c.len() < payload.len() - payload.len() / 8),
+    };
+    let mut out = match compressed {
+        Some(mut data) => {
+            data.push(codec.id());
+            data
+        }
+        None => {
+            let mut data = Vec::with_capacity(payload.len() + 5);
+            data.extend_from_slice(payload);
+            data.push(0);
+            data
+        }
+    };
+    let mut hasher = Hasher::new();
+    hasher.update(&out);
+    let crc = hasher.finalize();
+    out.extend_from_slice(&crc.to_le_bytes());
+    out
+}
+
+/// Verifies and decompresses a block framed by [`seal_block`].
+pub fn unseal_block(buf: &[u8]) -> Result<Vec<u8>> {
+    let framed = verify_block(buf)?;
+    let Some((&kind, data)) = framed.split_last() else {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "short block"));
+    };
+    codec_by_id(kind)?.decompress(data)
+}
+
+/// Verifies the trailing crc of an encoded block and returns its payload.
+///
+/// Blocks of format versions before 4 carry no compression type byte.
+pub fn verify_block(buf: &[u8]) -> Result<&[u8]> {
+    if buf.len() < 4 {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "short block"));
+    }
+    let crc_stored = u32::from_le_bytes(buf[buf.len() - 4..].try_into().unwrap());
+    let payload = &buf[..buf.len() - 4];
+    let mut hasher = Hasher::new();
+    hasher.update(payload);
+    if hasher.finalize() != crc_stored {
+        return Err(Error::new(ErrorKind::InvalidData, "block crc"));
+    }
+    Ok(payload)
+}
+
+/// Copies every record of a verified block payload out, in key order.
+pub fn decode_block(payload: &[u8], format: BlockFormat) -> Result<DecodedBlock> {
+    let block = Block::new(payload, format)?;
+    Ok(block.iter().map(|(k, s, v)| (k, s, to_entry(v))).collect())
+}
+
+fn to_entry(value: RecordValue) -> Entry {
+    match value {
+        RecordValue::Put(v) => Entry::Put(v.to_vec()),
+        RecordValue::Expiring(v, expires_at) => Entry::Expiring {
+            value: v.to_vec(),
+            expires_at,
+        },
+        RecordValue::Delete => Entry::Delete,
+        RecordValue::Merge(v) => Entry::Merge(v.to_vec()),
+    }
+}
+
+/// A verified block payload, split into its records and restart array.
+pub struct Block<'a> {
+    records: &'a [u8],
+    restarts: &'a [u8],
+    format: BlockFormat,
+}
+
+impl<'a> Block<'a> {
+    pub fn new(payload: &'a [u8], format: BlockFormat) -> Result<Self> {
+        if format == BlockFormat::Plain {
+            return Ok(Self {
+                records: payload,
+                restarts: &[],
+                format,
+            });
+        }
+        let corrupt = || Error::new(ErrorKind::InvalidData, "bad restart array");
+        let count_at = payload.len().checked_sub(4).ok_or_else(corrupt)?;
+        let count = u32::from_le_bytes(payload[count_at..].try_into().unwrap()) as usize;
+        let restarts_at = count
+            .checked_mul(4)
+            .and_then(|n| count_at.checked_sub(n))
+            .ok_or_else(corrupt)?;
+        Ok(Self {
+            records: &payload[..restarts_at],
+            restarts: &payload[restarts_at..count_at],
+            format,
+        })
+    }
+
+    pub fn iter(&self) -> BlockIter<'a> {
+        self.iter_from(0)
+    }
+
+    fn iter_from(&self, pos: usize) -> BlockIter<'a> {
+        BlockIter {
+            payload: self.records,
+            pos,
+            format: self.format,
+            key: Vec::new(),
+        }
+    }
+
+    pub fn num_restarts(&self) -> usize {
+        self.restarts.len() / 4
+    }
+
+    fn restart(&self, i: usize) -> usize {
+        u32::from_le_bytes(self.restarts[i * 4..i * 4 + 4].try_into().unwrap()) as usize
+    }
+
+    /// Finds the newest version of `key` numbered `seq` or below,
+    /// binary-searching the restart points of a prefixed block and scanning
+    /// forward from the closest one.
+    pub fn get(&self, key: &[u8], seq: SeqNo) -> Option<Entry> {
+        // Restart points before the first one holding a key >= `key`; an
+        // earlier one may still hold newer versions of `key`.
+        let mut lo = 0;
+        let mut hi = self.num_restarts();
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            match self.iter_from(self.restart(mid)).next() {
+                Some((k, _, _)) if k.as_slice() < key => lo = mid + 1,
+                _ => hi = mid,
+            }
+        }
+        let start = match lo {
+            0 => 0,
+            n => self.restart(n - 1),
+        };
+        for (k, s, v) in self.iter_from(start) {
+            match k.as_slice().cmp(key) {
+                std::cmp::Ordering::Less => continue,
+                std::cmp::Ordering::Equal if s > seq => continue,
+                std::cmp::Ordering::Equal => return Some(to_entry(v)),
+                std::cmp::Ordering::Greater => return None,
+            }
+        }
+        None
+    }
+}
+
+/// Walks the records of a block in key order, newest version first.
+///
+/// Stops at the first truncated record. Formats without sequence numbers
+/// report `0`.
+pub struct BlockIter<'a> {
+    payload: &'a [u8],
+    pos: usize,
+    format: BlockFormat,
+    /// The previous key, which prefixed records build on.
+    key: Vec<u8>,
+}
+
+impl<'a> BlockIter<'a> {
+    fn next_plain(&mut self) -> Option<Record<'a>> {
+        let payload = self.payload;
+        let mut p = self.pos;
+        if p + 1 + 4 + 4 > payload.len() {
+            return None;
+        }
+        let op = payload[p];
+        p += 1;
+        let klen = u32::from_le_bytes(payload[p..p + 4].try_into().unwrap()) as usize;
+        p += 4;
+        let vlen = u32::from_le_bytes(payload[p..p + 4].try_into().unwrap()) as usize;
+        p += 4;
+        if p + klen > payload.len() {
+            return None;
+        }
+        let k = &payload[p..p + klen];
+        p += klen;
+        let v = if op == 0 {
+            if p + vlen > payload.len() {
+                return None;
+            }
+            let v = &payload[p..p + vlen];
+            p += vlen;
+            RecordValue::Put(v)
+        } else {
+            RecordValue::Delete
+        };
+        self.pos = p;
+        Some((k.to_vec(), 0, v))
+    }
+
+    fn next_prefixed(&mut self) -> Option<Record<'a>> {
+        let payload = self.payload;
+        let mut p = self.pos;
+        let op = *payload.get(p)?;
+        p += 1;
+        let shared = get_varint(payload, &mut p)? as usize;
+        let unshared = get_varint(payload, &mut p)? as usize;
+        let vlen = get_varint(payload, &mut p)? as usize;
+        let seq = match self.format {
+            BlockFormat::Versioned => get_varint(payload, &mut p)?,
+            _ => 0,
+        };
+        let expires_at = match op {
+            2 => Some(get_varint(payload, &mut p)?),
+            _ => None,
+        };
+        if shared > self.key.len() || payload.len() - p < unshared {
+            return None;
+        }
+        let suffix = &payload[p..p + unshared];
+        p += unshared;
+        let v = if op == 1 {
+            RecordValue::Delete
+        } else {
+            if payload.len() - p < vlen {
+                return None;
+            }
+            let v = &payload[p..p + vlen];
+            p += vlen;
+            match (op, expires_at) {
+                (_, Some(expires_at)) => RecordValue::Expiring(v, expires_at),
+                (3, None) => RecordValue::Merge(v),
+                _ => RecordValue::Put(v),
+            }
+        };
+        self.key.truncate(shared);
+        self.key.extend_from_slice(suffix);
+        self.pos = p;
+        Some((self.key.clone(), seq, v))
+    }
+}
+
+/// What a record holds, borrowed from its block.
+#[derive(Copy, Clone)]
+pub enum RecordValue<'a> {
+    Put(&'a [u8]),
+    /// A value and its expiry time.
+    Expiring(&'a [u8], u64),
+    Delete,
+    Merge(&'a [u8]),
+}
+
+/// A key, its sequence number and what it holds.
+pub type Record<'a> = (Vec<u8>, SeqNo, RecordValue<'a>);
+
+impl<'a> Iterator for BlockIter<'a> {
+    type Item = Record<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.format {
+            BlockFormat::Plain => self.next_plain(),
+            BlockFormat::Prefixed | BlockFormat::Versioned => self.next_prefixed(),
+        }
+    }
+}
+
+fn put_varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn get_varint(buf: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut v = 0u64;
+    for shift in (0..64).step_by(7) {
+        let b = *buf.get(*pos)?;
+        *pos += 1;
+        v |= ((b & 0x7f) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Some(v);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::sstable::compression::{Lz4Codec, NoCompression};
+
+    #[test]
+    fn versioned_blocks_round_trip_and_seek() {
+        let mut block = DataBlock::new(1 << 20);
+        let keys: Vec<String> = (0..100).map(|i| format!("user/profile/{i:05}")).collect();
+        for (i, key) in keys.iter().enumerate() {
+            let seq = i as SeqNo + 1;
+            if i % 5 == 0 {
+                block.add_put(key.as_bytes(), 1000 + seq, b"newer");
+            }
+            if i % 7 == 0 {
+                block.add_delete(key.as_bytes(), seq);
+            } else {
+                block.add_put(key.as_bytes(), seq, format!("v{i}").as_bytes());
+            }
+        }
+        let encoded = block.encode(&NoCompression);
+        let payload = &unseal_block(&encoded).unwrap()[..];
+        // Shared prefixes are stored once per restart interval.
+        assert!(payload.len() < keys.iter().map(|k| k.len()).sum::<usize>());
+        assert!(seal_block(payload, &Lz4Codec).len() < encoded.len());
+        assert_eq!(
+            unseal_block(&seal_block(payload, &Lz4Codec)).unwrap(),
+            payload
+        );
+        // Too small to compress: stored raw.
+        assert_eq!(seal_block(b"abc", &Lz4Codec)[3], 0);
+
+        let block = Block::new(payload, BlockFormat::Versioned).unwrap();
+        assert_eq!(block.num_restarts(), 120usize.div_ceil(RESTART_INTERVAL));
+        let decoded = decode_block(payload, BlockFormat::Versioned).unwrap();
+        assert_eq!(decoded.len(), 120);
+        assert_eq!(decoded[0].0, keys[0].as_bytes());
+        assert_eq!((decoded[0].1, decoded[1].1), (1001, 1));
+        for (i, key) in keys.iter().enumerate() {
+            let seq = i as SeqNo + 1;
+            match (block.get(key.as_bytes(), seq), i % 7) {
+                (Some(Entry::Delete), 0) => {}
+                (Some(Entry::Put(v)), n) if n != 0 && v == format!("v{i}").as_bytes() => {}
+                _ => panic!("wrong entry for {key}"),
+            }
+            let newest = block.get(key.as_bytes(), SeqNo::MAX);
+            assert_eq!(
+                matches!(newest, Some(Entry::Put(v)) if v == b"newer"),
+                i % 5 == 0
+            );
+            assert!(block.get(key.as_bytes(), seq - 1).is_none());
+        }
+        assert!(block.get(b"user/profile/00016x", SeqNo::MAX).is_none());
+        assert!(block.get(b"a", SeqNo::MAX).is_none());
+        assert!(block.get(b"z", SeqNo::MAX).is_none());
+    }
+}
@@ -1,51 +1,111 @@
-use super::{BlockHandle, TableId};
+use super::{BlockHandle, TableId, TableOptions};
+use crate::storage::env::{Env, RealEnv, WritableFile};
+use crate::storage::memtable::{Entry, SeqNo};
 use crate::storage::sstable::{
-    block::DataBlock, index::Index, FOOTER_SIZE, SSTABLE_MAGIC, SSTABLE_VERSION,
+    block::DataBlock,
+    compression::BlockCodec,
+    filter::{bloom_hash, BloomFilter},
+    index::Index,
+    FILTER_HANDLE_SIZE, FOOTER_SIZE, SSTABLE_MAGIC, SSTABLE_VERSION,
 };
-use std::fs::{File, OpenOptions};
-use std::io::{Seek, SeekFrom, Write};
+use std::io::Write;
 use std::path::Path;
 
 pub struct SsTableBuilder {
-    file: File,
+    file: Box<dyn WritableFile>,
+    /// The first failed write, reported by `finish`.
+    error: Option<std::io::Error>,
     block: DataBlock,
     block_size: usize,
     index: Index,
     last_key_in_block: Vec<u8>,
+    written: u64,
+    bloom_bits_per_key: usize,
+    key_hashes: Vec<u64>,
+    codec: &'static dyn BlockCodec,
 }
 
 impl SsTableBuilder {
     pub fn new(tmp_path: &Path, block_size: usize) -> Self {
-        let file = OpenOptions::new()
-            .create(true)
-            .truncate(true)
-            .write(true)
-            .read(true)
-            .open(tmp_path)
-            .expect("open tmp sstable");
-        Self {
-            file,
+        let opts = TableOptions {
+            block_bytes: block_size,
+            ..TableOptions::default()
+        };
+        Self::with_options(tmp_path, &opts)
+    }
+
+    pub fn with_options(tmp_path: &Path, opts: &TableOptions) -> Self {
+        Self::create(&RealEnv, tmp_path, opts).expect("open tmp sstable")
+    }
+
+    pub fn create(env: &dyn Env, tmp_path: &Path, opts: &TableOptions) -> std::io::Result<Self> {
+        let block_size = opts.block_bytes;
+        Ok(Self {
+            file: env.create(tmp_path)?,
+            error: None,
             block: DataBlock::new(block_size),
             block_size,
             index: Index::new(),
             last_key_in_block: Vec::new(),
-        }
+            written: 0,
+            bloom_bits_per_key: opts.bloom_bits_per_key,
+            key_hashes: Vec::new(),
+            codec: opts.compression.codec(),
+        })
     }
 
-    pub fn add_put(&mut self, key: &[u8], value: &[u8]) {
-        if self.block.is_full() {
-            self.flush_block();
+    /// Bytes written so far plus the pending block; used to cut output files.
+    pub fn estimated_size(&self) -> u64 {
+        self.written + self.block.payload_len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.written == 0 && self.block.is_empty()
+    }
+
+    /// Entries must be added in key order, and the versions of a key newest
+    /// first. A block is only ever cut between two different keys.
+    pub fn add_put(&mut self, key: &[u8], seq: SeqNo, value: &[u8]) {
+        self.start_entry(key);
+        self.block.add_put(key, seq, value);
+    }
+
+    /// Adds a value that reads as absent from `expires_at`, in milliseconds
+    /// since the Unix epoch.
+    pub fn add_expiring(&mut self, key: &[u8], seq: SeqNo, value: &[u8], expires_at: u64) {
+        self.start_entry(key);
+        self.block.add_expiring(key, seq, value, expires_at);
+    }
+
+    pub fn add_delete(&mut self, key: &[u8], seq: SeqNo) {
+        self.start_entry(key);
+        self.block.add_delete(key, seq);
+    }
+
+    pub fn add_merge(&mut self, key: &[u8], seq: SeqNo, operand: &[u8]) {
+        self.start_entry(key);
+        self.block.add_merge(key, seq, operand);
+    }
+
+    pub fn add_entry(&mut self, key: &[u8], seq: SeqNo, entry: &Entry) {
+        match entry {
+            Entry::Put(value) => self.add_put(key, seq, value),
+            Entry::Expiring { value, expires_at } => {
+                self.add_expiring(key, seq, value, *expires_at)
+            }
+            Entry::Delete => self.add_delete(key, seq),
+            Entry::Merge(operand) => self.add_merge(key, seq, operand),
         }
-        self.block.add_put(key, value);
-        self.last_key_in_block.clear();
-        self.last_key_in_block.extend_from_slice(key);
     }
 
-    pub fn add_delete(&mut self, key: &[u8]) {
+    fn start_entry(&mut self, key: &[u8]) {
+        if key == self.last_key_in_block.as_slice() && !self.is_empty() {
+            return;
+        }
         if self.block.is_full() {
             self.flush_block();
         }
-        self.block.add_delete(key);
+        self.add_key_hash(key);
         self.last_key_in_block.clear();
         self.last_key_in_block.extend_from_slice(key);
     }
@@ -54,18 +114,31 @@ impl SsTableBuilder {
         if !self.block.is_empty() || !self.last_key_in_block.is_empty() {
             self.flush_block();
         }
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        let filter_offset = self.written;
+        let mut filter_len = 0u32;
+        if self.bloom_bits_per_key > 0 && !self.key_hashes.is_empty() {
+            let filter = BloomFilter::build(&self.key_hashes, self.bloom_bits_per_key).encode();
+            self.file.write_all(&filter)?;
+            filter_len = filter.len() as u32;
+        }
+
         let index_bytes = std::mem::take(&mut self.index).encode();
-        let index_offset = self.file.seek(SeekFrom::End(0))?;
+        let index_offset = filter_offset + filter_len as u64;
         self.file.write_all(&index_bytes)?;
         let index_len = index_bytes.len() as u32;
-        let mut footer = Vec::with_capacity(FOOTER_SIZE);
+        let mut footer = Vec::with_capacity(FILTER_HANDLE_SIZE + FOOTER_SIZE);
+        footer.extend_from_slice(&filter_offset.to_le_bytes());
+        footer.extend_from_slice(&filter_len.to_le_bytes());
         footer.extend_from_slice(&index_offset.to_le_bytes());
         footer.extend_from_slice(&index_len.to_le_bytes());
         footer.extend_from_slice(&SSTABLE_VERSION.to_le_bytes());
         footer.extend_from_slice(&SSTABLE_MAGIC.to_le_bytes());
         self.file.write_all(&footer)?;
         self.file.flush()?;
-        self.file.sync_all()?;
+        self.file.sync()?;
         Ok((
             0 as TableId,
             BlockHandle {
@@ -77,10 +150,20 @@ impl SsTableBuilder {
 }
 
 impl SsTableBuilder {
+    fn add_key_hash(&mut self, key: &[u8]) {
+        if self.bloom_bits_per_key > 0 {
+            self.key_hashes.push(bloom_hash(key));
+        }
+    }
+
     fn flush_block(&mut self) {
-        let start = self.file.seek(SeekFrom::End(0)).expect("seek");
-        let data = std::mem::replace(&mut self.block, DataBlock::new(self.block_size)).encode();
-        self.file.write_all(&data).expect("write block");
+        let start = self.written;
+        let data =
+            std::mem::replace(&mut self.block, DataBlock::new(self.block_size)).encode(self.codec);
+        if self.error.is_none() {
+            self.error = self.file.write_all(&data).err();
+        }
+        self.written += data.len() as u64;
         let handle = BlockHandle {
             offset: start,
             length: data.len() as u32,
@@ -0,0 +1,220 @@
+use super::TableId;
+use crate::storage::memtable::{Entry, SeqNo};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The records of one data block, decoded and crc-verified.
+pub type DecodedBlock = Vec<(Vec<u8>, SeqNo, Entry)>;
+
+const NUM_SHARDS: usize = 16;
+
+/// A capacity-bounded LRU cache of decoded data blocks, keyed by
+/// `(table id, block offset)` and shared by every reader of an engine.
+///
+/// The cache is split into independently locked shards so concurrent
+/// lookups rarely contend; each shard evicts its own least recently used
+/// blocks once over its share of the capacity.
+pub struct BlockCache {
+    shards: Vec<Mutex<Shard>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+struct Shard {
+    capacity: usize,
+    usage: usize,
+    tick: u64,
+    entries: HashMap<(TableId, u64), CacheEntry>,
+    /// Last-use tick of every entry, oldest first.
+    lru: BTreeMap<u64, (TableId, u64)>,
+}
+
+struct CacheEntry {
+    block: Arc<DecodedBlock>,
+    charge: usize,
+    tick: u64,
+}
+
+impl BlockCache {
+    pub fn new(capacity_bytes: usize) -> Self {
+        let per_shard = capacity_bytes.div_ceil(NUM_SHARDS);
+        Self {
+            shards: (0..NUM_SHARDS)
+                .map(|_| {
+                    Mutex::new(Shard {
+                        capacity: per_shard,
+                        usage: 0,
+                        tick: 0,
+                        entries: HashMap::new(),
+                        lru: BTreeMap::new(),
+                    })
+                })
+                .collect(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    fn shard(&self, table: TableId, offset: u64) -> &Mutex<Shard> {
+        let h = (table ^ offset.rotate_left(32)).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        &self.shards[(h >> 60) as usize % NUM_SHARDS]
+    }
+
+    pub fn get(&self, table: TableId, offset: u64) -> Option<Arc<DecodedBlock>> {
+        let mut shard = self.shard(table, offset).lock().unwrap();
+        let found = shard.touch((table, offset));
+        match found {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        found
+    }
+
+    pub fn insert(&self, table: TableId, offset: u64, block: Arc<DecodedBlock>) {
+        let charge = block
+            .iter()
+            .map(|(k, _, e)| {
+                let v = match e {
+                    Entry::Put(v) | Entry::Merge(v) | Entry::Expiring { value: v, .. } => v.len(),
+                    Entry::Delete => 0,
+                };
+                k.len() + v + 32
+            })
+            .sum::<usize>()
+            + 64;
+        let mut shard = self.shard(table, offset).lock().unwrap();
+        shard.remove((table, offset));
+        shard.tick += 1;
+        let tick = shard.tick;
+        shard.entries.insert(
+            (table, offset),
+            CacheEntry {
+                block,
+                charge,
+                tick,
+            },
+        );
+        shard.lru.insert(tick, (table, offset));
+        shard.usage += charge;
+        while shard.usage > shard.capacity {
+            let Some((_, key)) = shard.lru.pop_first() else {
+                break;
+            };
+            if let Some(e) = shard.entries.remove(&key) {
+                shard.usage -= e.charge;
+                self.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Drops every cached block of `table`, once the table has been deleted.
+    pub fn erase_table(&self, table: TableId) {
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap();
+            let keys: Vec<_> = shard
+                .entries
+                .keys()
+                .filter(|(t, _)| *t == table)
+                .copied()
+                .collect();
+            for key in keys {
+                shard.remove(key);
+            }
+        }
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    pub fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+
+    /// Fraction of lookups served from the cache, `0.0` before any lookup.
+    pub fn hit_rate(&self) -> f64 {
+        let hits = self.hits();
+        let total = hits + self.misses();
+        if total == 0 {
+            0.0
+        } else {
+            hits as f64 / total as f64
+        }
+    }
+
+    /// Bytes currently charged against the capacity.
+    pub fn usage(&self) -> usize {
+        self.shards.iter().map(|s| s.lock().unwrap().usage).sum()
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|s| s.lock().unwrap().entries.len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Shard {
+    fn touch(&mut self, key: (TableId, u64)) -> Option<Arc<DecodedBlock>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.entries.get_mut(&key)?;
+        self.lru.remove(&entry.tick);
+        entry.tick = tick;
+        self.lru.insert(tick, key);
+        Some(entry.block.clone())
+    }
+
+    fn remove(&mut self, key: (TableId, u64)) {
+        if let Some(e) = self.entries.remove(&key) {
+            self.lru.remove(&e.tick);
+            self.usage -= e.charge;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(n: usize) -> Arc<DecodedBlock> {
+        Arc::new(vec![(vec![0u8; n], 0, Entry::Delete)])
+    }
+
+    #[test]
+    fn evicts_least_recently_used_and_erases_tables() {
+        // Two blocks fit in a shard; a third evicts the least recently used.
+        let cache = BlockCache::new(NUM_SHARDS * 200);
+        let same_shard: Vec<u64> = (0..10_000u64)
+            .filter(|&off| std::ptr::eq(cache.shard(1, off), cache.shard(1, 0)))
+            .take(3)
+            .collect();
+
+        cache.insert(1, same_shard[0], block(0));
+        cache.insert(1, same_shard[1], block(0));
+        assert!(cache.get(1, same_shard[0]).is_some());
+        cache.insert(1, same_shard[2], block(0));
+        assert!(cache.get(1, same_shard[1]).is_none());
+        assert!(cache.get(1, same_shard[0]).is_some());
+        assert_eq!(cache.evictions(), 1);
+        assert_eq!(cache.hits(), 2);
+        assert_eq!(cache.misses(), 1);
+
+        cache.insert(2, 0, block(10));
+        cache.erase_table(1);
+        assert_eq!(cache.len(), 1);
+        assert!(cache.get(2, 0).is_some());
+    }
+}
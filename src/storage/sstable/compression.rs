@@ -0,0 +1,239 @@
+use std::io::{Error, ErrorKind, Result};
+
+/// Compresses data blocks. Each block records the [`id`](Self::id) of the
+/// codec that wrote it, so tables may mix codecs and still be read back.
+pub trait BlockCodec: Send + Sync {
+    /// Compression type byte stored after a block written by this codec.
+    fn id(&self) -> u8;
+
+    fn name(&self) -> &'static str;
+
+    fn compress(&self, raw: &[u8]) -> Vec<u8>;
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// Built-in codecs, selectable per table through
+/// [`TableOptions`](super::TableOptions).
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    /// Fast LZ77 compression in the LZ4 block format.
+    #[default]
+    Lz4,
+}
+
+impl Compression {
+    pub fn codec(self) -> &'static dyn BlockCodec {
+        match self {
+            Compression::None => &NoCompression,
+            Compression::Lz4 => &Lz4Codec,
+        }
+    }
+}
+
+impl std::str::FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            other => Err(format!("unknown compression {other:?}")),
+        }
+    }
+}
+
+/// Looks up the codec that wrote a block from its type byte.
+pub fn codec_by_id(id: u8) -> Result<&'static dyn BlockCodec> {
+    match id {
+        0 => Ok(&NoCompression),
+        1 => Ok(&Lz4Codec),
+        other => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unknown block compression type {other}"),
+        )),
+    }
+}
+
+pub struct NoCompression;
+
+impl BlockCodec for NoCompression {
+    fn id(&self) -> u8 {
+        0
+    }
+
+    fn name(&self) -> &'static str {
+        "none"
+    }
+
+    fn compress(&self, raw: &[u8]) -> Vec<u8> {
+        raw.to_vec()
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(data.to_vec())
+    }
+}
+
+/// The LZ4 block format, prefixed with the uncompressed length as a `u32`.
+///
+/// Matches are found greedily through a single hash table of 4-byte
+/// sequences, trading some ratio for speed.
+pub struct Lz4Codec;
+
+const MIN_MATCH: usize = 4;
+const HASH_BITS: u32 = 12;
+/// The last match must start this far from the end of the input...
+const MF_LIMIT: usize = 12;
+/// ...and the final bytes are always literals.
+const LAST_LITERALS: usize = 5;
+
+impl BlockCodec for Lz4Codec {
+    fn id(&self) -> u8 {
+        1
+    }
+
+    fn name(&self) -> &'static str {
+        "lz4"
+    }
+
+    fn compress(&self, raw: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(raw.len() / 2 + 16);
+        out.extend_from_slice(&(raw.len() as u32).to_le_bytes());
+        // Position + 1 of the last sequence seen with each hash; 0 is empty.
+        let mut table = vec![0usize; 1 << HASH_BITS];
+        let mut anchor = 0;
+        let mut i = 0;
+        while i + MF_LIMIT < raw.len() {
+            let seq = u32::from_le_bytes(raw[i..i + 4].try_into().unwrap());
+            let h = (seq.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize;
+            let candidate = table[h];
+            table[h] = i + 1;
+            if candidate > 0 {
+                let c = candidate - 1;
+                if i - c <= u16::MAX as usize && raw[c..c + 4] == raw[i..i + 4] {
+                    let max = raw.len() - LAST_LITERALS - i;
+                    let mut len = MIN_MATCH;
+                    while len < max && raw[c + len] == raw[i + len] {
+                        len += 1;
+                    }
+                    emit_sequence(&mut out, &raw[anchor..i], Some(((i - c) as u16, len)));
+                    i += len;
+                    anchor = i;
+                    continue;
+                }
+            }
+            i += 1;
+        }
+        emit_sequence(&mut out, &raw[anchor..], None);
+        out
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let corrupt = || Error::new(ErrorKind::InvalidData, "corrupt lz4 block");
+        if data.len() < 4 {
+            return Err(corrupt());
+        }
+        let raw_len = u32::from_le_bytes(data[..4].try_into().unwrap()) as usize;
+        let mut out = Vec::with_capacity(raw_len);
+        let mut p = 4;
+        loop {
+            let token = *data.get(p).ok_or_else(corrupt)?;
+            p += 1;
+            let literals = read_length(data, &mut p, (token >> 4) as usize).ok_or_else(corrupt)?;
+            let end = p.checked_add(literals).filter(|&e| e <= data.len());
+            let end = end.ok_or_else(corrupt)?;
+            out.extend_from_slice(&data[p..end]);
+            p = end;
+            if p == data.len() {
+                break;
+            }
+            let offset = data.get(p..p + 2).ok_or_else(corrupt)?;
+            let offset = u16::from_le_bytes(offset.try_into().unwrap()) as usize;
+            p += 2;
+            let len = read_length(data, &mut p, (token & 0x0f) as usize).ok_or_else(corrupt)?;
+            let len = len + MIN_MATCH;
+            if offset == 0 || offset > out.len() || out.len() + len > raw_len {
+                return Err(corrupt());
+            }
+            // Byte by byte: a match may overlap the bytes it produces.
+            let start = out.len() - offset;
+            for k in 0..len {
+                out.push(out[start + k]);
+            }
+        }
+        if out.len() != raw_len {
+            return Err(corrupt());
+        }
+        Ok(out)
+    }
+}
+
+fn emit_sequence(out: &mut Vec<u8>, literals: &[u8], matched: Option<(u16, usize)>) {
+    let match_len = matched.map_or(0, |(_, len)| len - MIN_MATCH);
+    out.push(((literals.len().min(15) as u8) << 4) | match_len.min(15) as u8);
+    if literals.len() >= 15 {
+        write_length(out, literals.len() - 15);
+    }
+    out.extend_from_slice(literals);
+    if let Some((offset, _)) = matched {
+        out.extend_from_slice(&offset.to_le_bytes());
+        if match_len >= 15 {
+            write_length(out, match_len - 15);
+        }
+    }
+}
+
+fn write_length(out: &mut Vec<u8>, mut n: usize) {
+    while n >= 255 {
+        out.push(255);
+        n -= 255;
+    }
+    out.push(n as u8);
+}
+
+/// Completes a 4-bit length from the token with any extension bytes.
+fn read_length(data: &[u8], p: &mut usize, nibble: usize) -> Option<usize> {
+    let mut n = nibble;
+    if nibble == 15 {
+        loop {
+            let b = *data.get(*p)?;
+            *p += 1;
+            n += b as usize;
+            if b != 255 {
+                break;
+            }
+        }
+    }
+    Some(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lz4_round_trips_and_rejects_garbage() {
+        let json: Vec<u8> = (0..200)
+            .flat_map(|i| {
+                format!(r#"{{"id":{i},"name":"user{i}","tags":["a","b"]}},"#).into_bytes()
+            })
+            .collect();
+        let runs = vec![7u8; 1000];
+        let noise: Vec<u8> = (0..3000u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect();
+        for raw in [&json[..], &runs, &noise, b"", b"short"] {
+            let compressed = Lz4Codec.compress(raw);
+            assert_eq!(Lz4Codec.decompress(&compressed).unwrap(), raw);
+        }
+        assert!(Lz4Codec.compress(&json).len() < json.len() / 3);
+        assert!(Lz4Codec.compress(&runs).len() < 20);
+
+        let mut bad = Lz4Codec.compress(&json);
+        bad[0] ^= 1;
+        assert!(Lz4Codec.decompress(&bad).is_err());
+        assert!(Lz4Codec.decompress(&[0xff, 0, 0, 0, 0x0f]).is_err());
+    }
+}
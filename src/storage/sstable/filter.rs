@@ -0,0 +1,144 @@
+use crc32fast::Hasher;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A bloom filter over every key in a table, stored as its own block.
+///
+/// Encoded as `bits | probes u8 | crc u32`. Probe positions come from double
+/// hashing the two halves of [`bloom_hash`].
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    probes: u8,
+}
+
+impl BloomFilter {
+    /// Builds a filter from the [`bloom_hash`] of each key.
+    pub fn build(hashes: &[u64], bits_per_key: usize) -> Self {
+        // ln(2) * bits per key minimises the false positive rate.
+        let probes = (bits_per_key * 69 / 100).clamp(1, 30) as u8;
+        let nbytes = (hashes.len() * bits_per_key).max(64).div_ceil(8);
+        let mut filter = Self {
+            bits: vec![0u8; nbytes],
+            probes,
+        };
+        for &h in hashes {
+            for bit in filter.probe_bits(h) {
+                filter.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        filter
+    }
+
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.probe_bits(bloom_hash(key))
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    fn probe_bits(&self, h: u64) -> impl Iterator<Item = usize> {
+        let nbits = (self.bits.len() * 8) as u64;
+        let h1 = h & 0xffff_ffff;
+        let h2 = (h >> 32) | 1;
+        (0..self.probes as u64).map(move |i| {
+            let h = h1.wrapping_add(i.wrapping_mul(h2));
+            (h % nbits) as usize
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.bits.len() + 1 + 4);
+        out.extend_from_slice(&self.bits);
+        out.push(self.probes);
+        let mut hasher = Hasher::new();
+        hasher.update(&out);
+        let crc = hasher.finalize();
+        out.extend_from_slice(&crc.to_le_bytes());
+        out
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        if buf.len() < 1 + 1 + 4 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "short filter"));
+        }
+        let (body, crc) = buf.split_at(buf.len() - 4);
+        let mut hasher = Hasher::new();
+        hasher.update(body);
+        if hasher.finalize() != u32::from_le_bytes(crc.try_into().unwrap()) {
+            return Err(Error::new(ErrorKind::InvalidData, "filter crc"));
+        }
+        let (bits, probes) = body.split_at(body.len() - 1);
+        Ok(Self {
+            bits: bits.to_vec(),
+            probes: probes[0].max(1),
+        })
+    }
+}
+
+/// 64-bit FNV-1a followed by a splitmix64 finaliser to spread the bits.
+pub fn bloom_hash(key: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for &b in key {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    h ^= h >> 30;
+    h = h.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h ^= h >> 27;
+    h = h.wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^ (h >> 31)
+}
+
+/// How often bloom filters were consulted on point lookups.
+#[derive(Default)]
+pub struct FilterStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    false_positives: AtomicU64,
+}
+
+impl FilterStats {
+    /// Lookups the filter let through to the data block.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Lookups the filter answered on its own, skipping the data block read.
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Hits where the key turned out not to be in the table after all.
+    pub fn false_positives(&self) -> u64 {
+        self.false_positives.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_hit(&self, found: bool) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        if !found {
+            self.false_positives.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn record_miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_false_negatives_and_few_false_positives() {
+        let keys: Vec<Vec<u8>> = (0..1000u32)
+            .map(|i| format!("key{i}").into_bytes())
+            .collect();
+        let hashes: Vec<u64> = keys.iter().map(|k| bloom_hash(k)).collect();
+        let filter = BloomFilter::decode(&BloomFilter::build(&hashes, 10).encode()).unwrap();
+
+        assert!(keys.iter().all(|k| filter.may_contain(k)));
+        let false_positives = (0..10_000u32)
+            .filter(|i| filter.may_contain(format!("other{i}").as_bytes()))
+            .count();
+        assert!(false_positives < 300, "{false_positives} false positives");
+    }
+}
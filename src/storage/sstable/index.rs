@@ -16,14 +16,35 @@ impl Index {
         self.entries.push((sep_key.to_vec(), handle));
     }
 
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Separator key (the last key) and handle of the `i`th block.
+    pub fn entry(&self, i: usize) -> (&[u8], BlockHandle) {
+        let (ref sep, handle) = self.entries[i];
+        (sep, handle)
+    }
+
     pub fn find_block(&self, key: &[u8]) -> Option<BlockHandle> {
         if self.entries.is_empty() {
             return None;
         }
+        let idx = self.seek_block(key).min(self.entries.len() - 1);
+        Some(self.entries[idx].1)
+    }
+
+    /// Position of the first block that may hold keys `>= key`, or `len()`
+    /// if every key in the table is smaller.
+    pub fn seek_block(&self, key: &[u8]) -> usize {
         let mut lo = 0usize;
         let mut hi = self.entries.len();
         while lo < hi {
-            let mid = hi + (lo - hi) / 2;
+            let mid = lo + (hi - lo) / 2;
             let (ref sep, _) = self.entries[mid];
             if key <= &sep[..] {
                 hi = mid;
@@ -31,14 +52,7 @@ impl Index {
                 lo = mid + 1;
             }
         }
-
-        let idx = if lo < self.entries.len() {
-            lo
-        } else {
-            self.entries.len() - 1
-        };
-
-        Some(self.entries[idx].1)
+        lo
     }
 
     pub fn encode(self) -> Vec<u8> {
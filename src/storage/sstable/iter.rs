@@ -1,18 +1,214 @@
-/// An iterator yielding key-value pairs from an SSTable in sorted order.
-pub struct SsTableIter;
+use super::cache::DecodedBlock;
+use super::reader::SsTableReader;
+use crate::storage::iter::EntryIter;
+use crate::storage::memtable::{Entry, SeqNo};
+use std::sync::Arc;
+
+/// An iterator over an SSTable's entries in sorted order, every version and
+/// tombstone included.
+///
+/// Blocks are read one at a time as the cursor moves into them, going
+/// through the reader's block cache.
+pub struct SsTableIter {
+    reader: Arc<SsTableReader>,
+    /// Index position of the loaded block; `index().len()` once exhausted.
+    block: usize,
+    records: Arc<DecodedBlock>,
+    pos: usize,
+    fill_cache: bool,
+}
 
 impl SsTableIter {
-    /// Creates a new iterator for the given reader starting at an optional key.
-    pub fn new_seek(_start: Option<&[u8]>) -> Self {
-        unimplemented!()
+    pub fn new(reader: Arc<SsTableReader>) -> Self {
+        let block = reader.index().len();
+        Self {
+            reader,
+            block,
+            records: Arc::default(),
+            pos: 0,
+            fill_cache: true,
+        }
+    }
+
+    /// Whether blocks read from disk are added to the block cache. Turned off
+    /// for bulk reads such as compaction inputs.
+    pub fn set_fill_cache(&mut self, fill_cache: bool) {
+        self.fill_cache = fill_cache;
+    }
+
+    /// Creates an iterator positioned at `start`, or at the first entry.
+    pub fn new_seek(reader: Arc<SsTableReader>, start: Option<&[u8]>) -> std::io::Result<Self> {
+        let mut it = Self::new(reader);
+        match start {
+            Some(key) => it.seek(key)?,
+            None => it.seek_to_first()?,
+        }
+        Ok(it)
+    }
+
+    fn load_block(&mut self, block: usize) -> std::io::Result<()> {
+        self.block = block;
+        self.pos = 0;
+        if block >= self.reader.index().len() {
+            self.records = Arc::default();
+            return Ok(());
+        }
+        let (_, handle) = self.reader.index().entry(block);
+        self.records = self.reader.block(handle, self.fill_cache)?;
+        Ok(())
+    }
+
+    /// Moves past exhausted blocks until an entry is found or the table ends.
+    fn skip_empty_blocks(&mut self) -> std::io::Result<()> {
+        while self.pos >= self.records.len() && self.block < self.reader.index().len() {
+            self.load_block(self.block + 1)?;
+        }
+        Ok(())
+    }
+
+    /// Steps back into earlier blocks until an entry is found; before the
+    /// first block the iterator becomes invalid.
+    fn back_to_previous_block(&mut self) -> std::io::Result<()> {
+        loop {
+            if self.block == 0 {
+                return self.load_block(self.reader.index().len());
+            }
+            self.load_block(self.block - 1)?;
+            if !self.records.is_empty() {
+                self.pos = self.records.len() - 1;
+                return Ok(());
+            }
+        }
     }
 }
 
-impl Iterator for SsTableIter {
-    type Item = (Vec<u8>, Vec<u8>);
+impl EntryIter for SsTableIter {
+    fn valid(&self) -> bool {
+        self.pos < self.records.len()
+    }
+
+    fn key(&self) -> &[u8] {
+        &self.records[self.pos].0
+    }
+
+    fn seq(&self) -> SeqNo {
+        self.records[self.pos].1
+    }
+
+    fn entry(&self) -> &Entry {
+        &self.records[self.pos].2
+    }
+
+    fn seek_to_first(&mut self) -> std::io::Result<()> {
+        self.load_block(0)?;
+        self.skip_empty_blocks()
+    }
+
+    fn seek(&mut self, key: &[u8]) -> std::io::Result<()> {
+        let block = self.reader.index().seek_block(key);
+        self.load_block(block)?;
+        self.pos = self.records.partition_point(|(k, _, _)| k.as_slice() < key);
+        self.skip_empty_blocks()
+    }
+
+    fn next(&mut self) -> std::io::Result<()> {
+        if self.valid() {
+            self.pos += 1;
+            self.skip_empty_blocks()?;
+        }
+        Ok(())
+    }
+
+    fn seek_to_last(&mut self) -> std::io::Result<()> {
+        self.load_block(self.reader.index().len())?;
+        self.back_to_previous_block()
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) -> std::io::Result<()> {
+        let block = self.reader.index().seek_block(key);
+        if block >= self.reader.index().len() {
+            return self.seek_to_last();
+        }
+        self.load_block(block)?;
+        match self
+            .records
+            .partition_point(|(k, _, _)| k.as_slice() <= key)
+        {
+            0 => self.back_to_previous_block(),
+            n => {
+                self.pos = n - 1;
+                Ok(())
+            }
+        }
+    }
+
+    fn prev(&mut self) -> std::io::Result<()> {
+        if !self.valid() {
+            return Ok(());
+        }
+        if self.pos > 0 {
+            self.pos -= 1;
+            return Ok(());
+        }
+        self.back_to_previous_block()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::sstable::builder::SsTableBuilder;
+
+    #[test]
+    fn walks_blocks_and_seeks() {
+        let path = std::env::temp_dir().join(format!("zynk-sst-iter-{}.sst", std::process::id()));
+        let mut builder = SsTableBuilder::new(&path, 64);
+        for i in 0..100u32 {
+            let key = format!("k{:03}", i * 2);
+            if i % 10 == 0 {
+                builder.add_delete(key.as_bytes(), 1);
+            } else {
+                builder.add_put(key.as_bytes(), 1, format!("v{i}").as_bytes());
+            }
+        }
+        builder.finish().unwrap();
+        let reader = Arc::new(SsTableReader::open(&path).unwrap());
+        assert!(reader.index().len() > 1);
+
+        let mut it = SsTableIter::new_seek(reader.clone(), None).unwrap();
+        let mut n = 0;
+        while it.valid() {
+            assert_eq!(it.key(), format!("k{:03}", n * 2).as_bytes());
+            assert_eq!(matches!(it.entry(), Entry::Delete), n % 10 == 0);
+            it.next().unwrap();
+            n += 1;
+        }
+        assert_eq!(n, 100);
+
+        let mut it = SsTableIter::new_seek(reader.clone(), Some(b"k101")).unwrap();
+        assert_eq!(it.key(), b"k102");
+        it.seek(b"k198").unwrap();
+        assert_eq!(it.key(), b"k198");
+        it.next().unwrap();
+        assert!(!it.valid());
+        it.seek(b"k999").unwrap();
+        assert!(!it.valid());
+
+        it.seek_to_last().unwrap();
+        let mut n = 100;
+        while it.valid() {
+            n -= 1;
+            assert_eq!(it.key(), format!("k{:03}", n * 2).as_bytes());
+            it.prev().unwrap();
+        }
+        assert_eq!(n, 0);
 
-    /// Advances the iterator and returns the next item if any.
-    fn next(&mut self) -> Option<Self::Item> {
-        unimplemented!()
+        it.seek_for_prev(b"k101").unwrap();
+        assert_eq!(it.key(), b"k100");
+        it.seek_for_prev(b"k999").unwrap();
+        assert_eq!(it.key(), b"k198");
+        it.seek_for_prev(b"a").unwrap();
+        assert!(!it.valid());
+        let _ = std::fs::remove_file(&path);
     }
 }
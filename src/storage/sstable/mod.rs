@@ -1,10 +1,16 @@
 pub mod block;
 pub mod builder;
+pub mod cache;
+pub mod compression;
+pub mod filter;
 pub mod index;
 pub mod iter;
 pub mod reader;
+pub mod table_cache;
 
-#[derive(Copy, Clone)]
+use compression::Compression;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct BlockHandle {
     pub offset: u64,
     pub length: u32,
@@ -12,6 +18,58 @@ pub struct BlockHandle {
 
 pub type TableId = u64;
 
-pub const SSTABLE_VERSION: u32 = 1;
+/// Version 2 adds a bloom filter block, referenced by a handle stored just
+/// before the footer. Version 3 prefix-compresses keys within data blocks
+/// (see [`block::BlockFormat`]). Version 4 follows each data block with a
+/// compression type byte (see [`block::seal_block`]). Version 5 stores a
+/// sequence number with every record; older tables are still readable and
+/// their records read as sequence number `0`. Version 6 adds records that
+/// expire, and version 7 merge operands.
+pub const SSTABLE_VERSION: u32 = 7;
 pub const SSTABLE_MAGIC: u64 = 0xF3515A5453544142;
 pub const FOOTER_SIZE: usize = 8 + 4 + 4 + 8;
+/// Filter block offset (u64) and length (u32); a zero length means no filter.
+pub const FILTER_HANDLE_SIZE: usize = 8 + 4;
+
+/// The last [`FOOTER_SIZE`] bytes of a table: where its index is, the
+/// format version and the magic number.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Footer {
+    pub index: BlockHandle,
+    pub version: u32,
+    pub magic: u64,
+}
+
+impl Footer {
+    pub fn decode(buf: &[u8; FOOTER_SIZE]) -> Self {
+        Self {
+            index: BlockHandle {
+                offset: u64::from_le_bytes(buf[0..8].try_into().unwrap()),
+                length: u32::from_le_bytes(buf[8..12].try_into().unwrap()),
+            },
+            version: u32::from_le_bytes(buf[12..16].try_into().unwrap()),
+            magic: u64::from_le_bytes(buf[16..24].try_into().unwrap()),
+        }
+    }
+}
+
+/// How new tables are laid out.
+#[derive(Copy, Clone, Debug)]
+pub struct TableOptions {
+    /// Target size of a data block.
+    pub block_bytes: usize,
+    /// Bloom filter bits per key; `0` writes no filter.
+    pub bloom_bits_per_key: usize,
+    /// Codec data blocks are compressed with.
+    pub compression: Compression,
+}
+
+impl Default for TableOptions {
+    fn default() -> Self {
+        Self {
+            block_bytes: 8 * 1024,
+            bloom_bits_per_key: 10,
+            compression: Compression::default(),
+        }
+    }
+}
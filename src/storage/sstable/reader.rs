@@ -1,114 +1,395 @@
-use super::TableId;
-use crate::storage::sstable::{index::Index, FOOTER_SIZE, SSTABLE_MAGIC, SSTABLE_VERSION};
-use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
+use super::{BlockHandle, TableId};
+use crate::storage::env::{Env, RandomAccessFile, RealEnv};
+use crate::storage::memtable::{Entry, SeqNo};
+use crate::storage::sstable::block::{
+    decode_block, unseal_block, verify_block, Block, BlockFormat,
+};
+use crate::storage::sstable::cache::{BlockCache, DecodedBlock};
+use crate::storage::sstable::filter::{BloomFilter, FilterStats};
+use crate::storage::sstable::{
+    index::Index, Footer, FILTER_HANDLE_SIZE, FOOTER_SIZE, SSTABLE_MAGIC, SSTABLE_VERSION,
+};
 use std::path::Path;
+use std::sync::Arc;
+
+/// State shared by every reader an engine opens.
+#[derive(Clone)]
+pub struct ReaderContext {
+    pub env: Arc<dyn Env>,
+    pub block_cache: Option<Arc<BlockCache>>,
+    pub filter_stats: Arc<FilterStats>,
+}
+
+impl Default for ReaderContext {
+    fn default() -> Self {
+        Self {
+            env: Arc::new(RealEnv),
+            block_cache: None,
+            filter_stats: Arc::default(),
+        }
+    }
+}
 
 pub struct SsTableReader {
-    file: File,
+    id: TableId,
+    file: Box<dyn RandomAccessFile>,
     index: Index,
+    filter: Option<BloomFilter>,
+    footer: Footer,
+    format: BlockFormat,
+    ctx: ReaderContext,
 }
 
 impl SsTableReader {
     pub fn open(path: &Path) -> std::io::Result<Self> {
-        let mut file = File::open(path)?;
-        let len = file.metadata()?.len();
+        Self::open_in(path, 0, &ReaderContext::default())
+    }
+
+    /// Opens table `id` at `path`, sharing `ctx`'s block cache and filter counters.
+    pub fn open_in(path: &Path, id: TableId, ctx: &ReaderContext) -> std::io::Result<Self> {
+        let file = ctx.env.open(path)?;
+        let len = file.size()?;
         if len < FOOTER_SIZE as u64 {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::UnexpectedEof,
                 "short sstable",
             ));
         }
-        file.seek(SeekFrom::Start(len - FOOTER_SIZE as u64))?;
-        let mut footer = [0u8; 24];
-        file.read_exact(&mut footer)?;
-        let index_offset = u64::from_le_bytes(footer[0..8].try_into().unwrap());
-        let index_len = u32::from_le_bytes(footer[8..12].try_into().unwrap()) as usize;
-        let version = u32::from_le_bytes(footer[12..16].try_into().unwrap());
-        let magic = u64::from_le_bytes(footer[16..24].try_into().unwrap());
-        if magic != SSTABLE_MAGIC {
+        let mut buf = [0u8; FOOTER_SIZE];
+        file.read_exact_at(&mut buf, len - FOOTER_SIZE as u64)?;
+        let footer = Footer::decode(&buf);
+        let version = footer.version;
+        if footer.magic != SSTABLE_MAGIC {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
                 "bad magic",
             ));
         }
-        if version != SSTABLE_VERSION {
+        if version == 0 || version > SSTABLE_VERSION {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
                 "bad version",
             ));
         }
-        file.seek(SeekFrom::Start(index_offset))?;
-        let mut index_buf = vec![0u8; index_len];
-        file.read_exact(&mut index_buf)?;
+        let mut index_buf = vec![0u8; footer.index.length as usize];
+        file.read_exact_at(&mut index_buf, footer.index.offset)?;
         let index = Index::decode(&index_buf[..])?;
-        Ok(Self { file, index })
+
+        let mut filter = None;
+        if version >= 2 {
+            if len < (FOOTER_SIZE + FILTER_HANDLE_SIZE) as u64 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "short sstable",
+                ));
+            }
+            let mut handle = [0u8; FILTER_HANDLE_SIZE];
+            file.read_exact_at(&mut handle, len - (FOOTER_SIZE + FILTER_HANDLE_SIZE) as u64)?;
+            let filter_offset = u64::from_le_bytes(handle[0..8].try_into().unwrap());
+            let filter_len = u32::from_le_bytes(handle[8..12].try_into().unwrap()) as usize;
+            if filter_len > 0 {
+                let mut buf = vec![0u8; filter_len];
+                file.read_exact_at(&mut buf, filter_offset)?;
+                filter = Some(BloomFilter::decode(&buf)?);
+            }
+        }
+        Ok(Self {
+            id,
+            file,
+            index,
+            filter,
+            footer,
+            format: BlockFormat::for_version(version),
+            ctx: ctx.clone(),
+        })
+    }
+
+    pub fn has_filter(&self) -> bool {
+        self.filter.is_some()
     }
 
     pub fn table_id(&self) -> TableId {
-        0
+        self.id
+    }
+
+    pub fn footer(&self) -> Footer {
+        self.footer
+    }
+
+    pub fn index(&self) -> &Index {
+        &self.index
     }
 
-    pub fn get(&self, key: &[u8]) -> std::io::Result<Option<Vec<u8>>> {
+    pub fn file_len(&self) -> std::io::Result<u64> {
+        self.file.size()
+    }
+
+    /// Reads the block at `handle` and returns its crc-verified,
+    /// decompressed payload.
+    pub fn read_block(&self, handle: BlockHandle) -> std::io::Result<Vec<u8>> {
+        // Positioned reads: the reader is shared by lookups and background compaction.
+        let mut buf = vec![0u8; handle.length as usize];
+        self.file.read_exact_at(&mut buf, handle.offset)?;
+        if self.footer.version >= 4 {
+            return unseal_block(&buf);
+        }
+        let payload_len = verify_block(&buf)?.len();
+        buf.truncate(payload_len);
+        Ok(buf)
+    }
+
+    /// Returns the decoded records of the block at `handle`, from the block
+    /// cache when possible. With `fill_cache` unset a block read from disk is
+    /// not added to the cache, so one-off scans don't push out hot blocks.
+    pub fn block(
+        &self,
+        handle: BlockHandle,
+        fill_cache: bool,
+    ) -> std::io::Result<Arc<DecodedBlock>> {
+        let cache = self.ctx.block_cache.as_ref();
+        if let Some(block) = cache.and_then(|c| c.get(self.id, handle.offset)) {
+            return Ok(block);
+        }
+        let block = Arc::new(decode_block(&self.read_block(handle)?, self.format)?);
+        if let Some(cache) = cache.filter(|_| fill_cache) {
+            cache.insert(self.id, handle.offset, block.clone());
+        }
+        Ok(block)
+    }
+
+    /// Looks up the newest version of `key` numbered `seq` or below,
+    /// returning `Some(Entry::Delete)` for a tombstone so the caller can stop
+    /// searching older tables.
+    ///
+    /// The bloom filter, if the table has one, is checked before any data
+    /// block is read.
+    pub fn get(&self, key: &[u8], seq: SeqNo) -> std::io::Result<Option<Entry>> {
+        let Some(filter) = &self.filter else {
+            return self.get_from_blocks(key, seq);
+        };
+        if !filter.may_contain(key) {
+            self.ctx.filter_stats.record_miss();
+            return Ok(None);
+        }
+        let found = self.get_from_blocks(key, seq)?;
+        self.ctx.filter_stats.record_hit(found.is_some());
+        Ok(found)
+    }
+
+    fn get_from_blocks(&self, key: &[u8], seq: SeqNo) -> std::io::Result<Option<Entry>> {
         let handle = match self.index.find_block(key) {
             Some(h) => h,
             None => return Ok(None),
         };
-        let mut buf = vec![0u8; handle.length as usize];
-        let mut f = &self.file;
-        f.seek(SeekFrom::Start(handle.offset))?;
-        f.read_exact(&mut buf)?;
-        if buf.len() < 4 {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::UnexpectedEof,
-                "short block",
-            ));
+        if self.ctx.block_cache.is_none() {
+            // Nothing to cache into, so search the block in place.
+            let payload = self.read_block(handle)?;
+            return Ok(Block::new(&payload, self.format)?.get(key, seq));
         }
-        let crc_stored = u32::from_le_bytes(buf[buf.len() - 4..].try_into().unwrap());
-        let payload = &buf[..buf.len() - 4];
-        let mut hasher = crc32fast::Hasher::new();
-        hasher.update(payload);
-        let crc_calc = hasher.finalize();
-        if crc_calc != crc_stored {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "block crc",
-            ));
+        let block = self.block(handle, true)?;
+        let pos = block
+            .partition_point(|(k, s, _)| (k.as_slice(), SeqNo::MAX - s) < (key, SeqNo::MAX - seq));
+        Ok(block
+            .get(pos)
+            .filter(|(k, _, _)| k.as_slice() == key)
+            .map(|(_, _, e)| e.clone()))
+    }
+
+    /// Smallest and largest key in the table, or `None` if it is empty.
+    pub fn key_range(&self) -> std::io::Result<Option<(Vec<u8>, Vec<u8>)>> {
+        if self.index.is_empty() {
+            return Ok(None);
+        }
+        let (_, first) = self.index.entry(0);
+        let payload = self.read_block(first)?;
+        let smallest = match Block::new(&payload, self.format)?.iter().next() {
+            Some((k, _, _)) => k,
+            None => return Ok(None),
+        };
+        let (largest, _) = self.index.entry(self.index.len() - 1);
+        Ok(Some((smallest, largest.to_vec())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::sstable::builder::SsTableBuilder;
+    use crate::storage::sstable::TableOptions;
+
+    fn build(path: &Path, bloom_bits_per_key: usize) {
+        let opts = TableOptions {
+            block_bytes: 64,
+            bloom_bits_per_key,
+            ..TableOptions::default()
+        };
+        let mut builder = SsTableBuilder::with_options(path, &opts);
+        for i in 0..200u32 {
+            builder.add_put(format!("k{i:03}").as_bytes(), i as SeqNo + 1, b"v");
+        }
+        builder.finish().unwrap();
+    }
+
+    #[test]
+    fn filter_skips_absent_keys() {
+        let path = std::env::temp_dir().join(format!("zynk-bloom-{}.sst", std::process::id()));
+        build(&path, 10);
+        let ctx = ReaderContext::default();
+        let stats = ctx.filter_stats.clone();
+        let reader = SsTableReader::open_in(&path, 1, &ctx).unwrap();
+        assert!(reader.has_filter());
+
+        for i in 0..200u32 {
+            let key = format!("k{i:03}");
+            assert!(reader.get(key.as_bytes(), SeqNo::MAX).unwrap().is_some());
+        }
+        for i in 0..200u32 {
+            let key = format!("x{i:03}");
+            assert!(reader.get(key.as_bytes(), SeqNo::MAX).unwrap().is_none());
         }
-        let mut p = 0usize;
-        let mut found: Option<(u8, Vec<u8>)> = None;
-        while p < payload.len() {
-            if p + 1 + 4 + 4 > payload.len() {
-                break;
+        assert_eq!(stats.hits() + stats.misses(), 400);
+        assert!(stats.misses() > 180);
+        assert_eq!(stats.hits() - stats.false_positives(), 200);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn compressed_blocks_read_back() {
+        use crate::storage::sstable::compression::Compression;
+        let value = br#"{"name":"zynk","tags":["lsm","crdt"],"active":true}"#;
+        let mut sizes = Vec::new();
+        for compression in [Compression::None, Compression::Lz4] {
+            let path = std::env::temp_dir().join(format!(
+                "zynk-compressed-{compression:?}-{}.sst",
+                std::process::id()
+            ));
+            let opts = TableOptions {
+                block_bytes: 1024,
+                compression,
+                ..TableOptions::default()
+            };
+            let mut builder = SsTableBuilder::with_options(&path, &opts);
+            for i in 0..500u32 {
+                builder.add_put(format!("doc/{i:05}").as_bytes(), 1, value);
             }
-            let op = payload[p];
-            p += 1;
-            let klen = u32::from_le_bytes(payload[p..p + 4].try_into().unwrap()) as usize;
-            p += 4;
-            let vlen = u32::from_le_bytes(payload[p..p + 4].try_into().unwrap()) as usize;
-            p += 4;
-            if p + klen > payload.len() {
-                break;
+            builder.finish().unwrap();
+
+            let reader = SsTableReader::open(&path).unwrap();
+            for i in (0..500u32).step_by(7) {
+                let key = format!("doc/{i:05}");
+                let found = reader.get(key.as_bytes(), SeqNo::MAX).unwrap();
+                assert!(matches!(found, Some(Entry::Put(v)) if v == value));
             }
-            let k = &payload[p..p + klen];
-            p += klen;
-            if op == 0 {
-                if p + vlen > payload.len() {
-                    break;
-                }
-                let v = &payload[p..p + vlen];
-                p += vlen;
-                if k == key {
-                    found = Some((0, v.to_vec()));
-                }
-            } else if k == key {
-                found = Some((1, Vec::new()));
+            sizes.push(reader.file_len().unwrap());
+            let _ = std::fs::remove_file(&path);
+        }
+        assert!(sizes[1] < sizes[0] / 2, "{sizes:?}");
+    }
+
+    #[test]
+    fn reads_the_version_visible_at_a_sequence() {
+        let path = std::env::temp_dir().join(format!("zynk-versions-{}.sst", std::process::id()));
+        let opts = TableOptions {
+            block_bytes: 64,
+            ..TableOptions::default()
+        };
+        let mut builder = SsTableBuilder::with_options(&path, &opts);
+        builder.add_put(b"a", 1, b"a1");
+        // Far more versions than fit in one 64-byte block.
+        for seq in (10..60).rev() {
+            builder.add_put(b"b", seq, format!("b{seq}").as_bytes());
+        }
+        builder.add_delete(b"b", 5);
+        builder.add_put(b"c", 2, b"c2");
+        builder.add_expiring(b"d", 3, b"d3", 1234);
+        builder.add_merge(b"e", 8, b"+1");
+        builder.add_put(b"e", 7, b"e7");
+        builder.finish().unwrap();
+
+        let ctx = ReaderContext {
+            block_cache: Some(Arc::new(BlockCache::new(1 << 20))),
+            ..ReaderContext::default()
+        };
+        for reader in [
+            SsTableReader::open(&path).unwrap(),
+            SsTableReader::open_in(&path, 1, &ctx).unwrap(),
+        ] {
+            let get = |key: &[u8], seq| match reader.get(key, seq).unwrap() {
+                Some(Entry::Put(v)) => Some(String::from_utf8(v).unwrap()),
+                Some(Entry::Expiring { value, expires_at }) => Some(format!(
+                    "{}@{expires_at}",
+                    String::from_utf8(value).unwrap()
+                )),
+                Some(Entry::Delete) => Some("deleted".to_string()),
+                Some(Entry::Merge(v)) => Some(format!("merge {}", String::from_utf8(v).unwrap())),
+                None => None,
+            };
+            assert_eq!(get(b"b", SeqNo::MAX).as_deref(), Some("b59"));
+            assert_eq!(get(b"b", 30).as_deref(), Some("b30"));
+            assert_eq!(get(b"b", 9).as_deref(), Some("deleted"));
+            assert_eq!(get(b"b", 4), None);
+            assert_eq!(get(b"a", 1).as_deref(), Some("a1"));
+            assert_eq!(get(b"c", 1), None);
+            assert_eq!(get(b"d", 3).as_deref(), Some("d3@1234"));
+            assert_eq!(get(b"e", 8).as_deref(), Some("merge +1"));
+            assert_eq!(get(b"e", 7).as_deref(), Some("e7"));
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    /// Writes `keys` the way version 1 did: plain records, no filter handle.
+    fn write_v1(path: &Path, keys: &[String]) {
+        let mut bytes = Vec::new();
+        let mut index = Index::new();
+        for chunk in keys.chunks(20) {
+            let mut block = Vec::new();
+            for key in chunk {
+                block.push(0);
+                block.extend_from_slice(&(key.len() as u32).to_le_bytes());
+                block.extend_from_slice(&1u32.to_le_bytes());
+                block.extend_from_slice(key.as_bytes());
+                block.push(b'v');
             }
+            block.extend_from_slice(&crc32fast::hash(&block).to_le_bytes());
+            let handle = BlockHandle {
+                offset: bytes.len() as u64,
+                length: block.len() as u32,
+            };
+            index.add(chunk.last().unwrap().as_bytes(), handle);
+            bytes.extend_from_slice(&block);
         }
-        match found {
-            Some((0, v)) => Ok(Some(v)),
-            Some((1, _)) => Ok(None),
-            _ => Ok(None),
+        let index_offset = bytes.len() as u64;
+        let index = index.encode();
+        bytes.extend_from_slice(&index);
+        bytes.extend_from_slice(&index_offset.to_le_bytes());
+        bytes.extend_from_slice(&(index.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        bytes.extend_from_slice(&SSTABLE_MAGIC.to_le_bytes());
+        std::fs::write(path, &bytes).unwrap();
+    }
+
+    #[test]
+    fn reads_version_1_tables() {
+        let path = std::env::temp_dir().join(format!("zynk-v1-{}.sst", std::process::id()));
+        let keys: Vec<String> = (0..200u32).map(|i| format!("k{i:03}")).collect();
+        write_v1(&path, &keys);
+
+        let ctx = ReaderContext {
+            block_cache: Some(Arc::new(BlockCache::new(1 << 20))),
+            ..ReaderContext::default()
+        };
+        for reader in [
+            SsTableReader::open(&path).unwrap(),
+            SsTableReader::open_in(&path, 1, &ctx).unwrap(),
+        ] {
+            assert!(!reader.has_filter());
+            assert_eq!(reader.footer().version, 1);
+            let found = reader.get(b"k150", SeqNo::MAX).unwrap();
+            assert!(matches!(found, Some(Entry::Put(v)) if v == b"v"));
+            assert!(reader.get(b"k999", SeqNo::MAX).unwrap().is_none());
+            let range = reader.key_range().unwrap().unwrap();
+            assert_eq!(range, (b"k000".to_vec(), b"k199".to_vec()));
         }
+        let _ = std::fs::remove_file(&path);
     }
 }
@@ -0,0 +1,163 @@
+use super::reader::{ReaderContext, SsTableReader};
+use super::TableId;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Keeps at most `capacity` tables open at once, opening them on first use
+/// and reopening evicted ones on demand.
+///
+/// Evicting a table drops its file handle and decoded index as soon as no
+/// iterator still holds the reader; its cached blocks stay valid.
+pub struct TableCache {
+    ctx: ReaderContext,
+    capacity: usize,
+    inner: Mutex<Inner>,
+    opens: AtomicU64,
+    evictions: AtomicU64,
+}
+
+struct Inner {
+    tick: u64,
+    open: HashMap<TableId, (Arc<SsTableReader>, u64)>,
+    /// Last-use tick of every open table, oldest first.
+    lru: BTreeMap<u64, TableId>,
+}
+
+impl TableCache {
+    pub fn new(capacity: usize, ctx: ReaderContext) -> Self {
+        Self {
+            ctx,
+            capacity: capacity.max(1),
+            inner: Mutex::new(Inner {
+                tick: 0,
+                open: HashMap::new(),
+                lru: BTreeMap::new(),
+            }),
+            opens: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    pub fn context(&self) -> &ReaderContext {
+        &self.ctx
+    }
+
+    /// Returns the reader for table `id`, opening `path` if it isn't open.
+    pub fn get(&self, id: TableId, path: &Path) -> std::io::Result<Arc<SsTableReader>> {
+        if let Some(reader) = self.inner.lock().unwrap().touch(id) {
+            return Ok(reader);
+        }
+        // Open outside the lock so lookups of other tables aren't held up.
+        let reader = Arc::new(SsTableReader::open_in(path, id, &self.ctx)?);
+        self.opens.fetch_add(1, Ordering::Relaxed);
+
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(raced) = inner.touch(id) {
+            return Ok(raced);
+        }
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.open.insert(id, (reader.clone(), tick));
+        inner.lru.insert(tick, id);
+        while inner.open.len() > self.capacity {
+            let Some((_, victim)) = inner.lru.pop_first() else {
+                break;
+            };
+            inner.open.remove(&victim);
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(reader)
+    }
+
+    /// Forgets table `id` once its file has been deleted, dropping its
+    /// handle and any of its blocks in the block cache.
+    pub fn remove(&self, id: TableId) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some((_, tick)) = inner.open.remove(&id) {
+            inner.lru.remove(&tick);
+        }
+        drop(inner);
+        if let Some(cache) = &self.ctx.block_cache {
+            cache.erase_table(id);
+        }
+    }
+
+    /// Tables currently held open.
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().open.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Times a table file was opened, including reopens after eviction.
+    pub fn opens(&self) -> u64 {
+        self.opens.load(Ordering::Relaxed)
+    }
+
+    pub fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+}
+
+impl Inner {
+    fn touch(&mut self, id: TableId) -> Option<Arc<SsTableReader>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (reader, last) = self.open.get_mut(&id)?;
+        self.lru.remove(last);
+        *last = tick;
+        self.lru.insert(tick, id);
+        Some(reader.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memtable::SeqNo;
+    use crate::storage::sstable::builder::SsTableBuilder;
+
+    #[test]
+    fn bounds_open_tables_and_reopens_evicted_ones() {
+        let dir = std::env::temp_dir().join(format!("zynk-table-cache-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let paths: Vec<_> = (1..=3u64)
+            .map(|id| {
+                let path = dir.join(format!("{id:06}.sst"));
+                let mut builder = SsTableBuilder::new(&path, 64);
+                builder.add_put(format!("k{id}").as_bytes(), 1, b"v");
+                builder.finish().unwrap();
+                path
+            })
+            .collect();
+
+        let tables = TableCache::new(2, ReaderContext::default());
+        tables.get(1, &paths[0]).unwrap();
+        tables.get(2, &paths[1]).unwrap();
+        tables.get(1, &paths[0]).unwrap();
+        // Table 2 is the least recently used, so opening 3 closes it.
+        let held = tables.get(3, &paths[2]).unwrap();
+        assert_eq!(
+            (tables.len(), tables.opens(), tables.evictions()),
+            (2, 3, 1)
+        );
+
+        let reader = tables.get(2, &paths[1]).unwrap();
+        assert!(reader.get(b"k2", SeqNo::MAX).unwrap().is_some());
+        assert_eq!(
+            (tables.len(), tables.opens(), tables.evictions()),
+            (2, 4, 2)
+        );
+        // An evicted reader that is still referenced keeps working.
+        assert!(held.get(b"k3", SeqNo::MAX).unwrap().is_some());
+
+        tables.remove(2);
+        assert_eq!(tables.len(), 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,160 @@
+use crate::storage::memtable::{Entry, SeqNo};
+use crate::storage::sstable::table_cache::TableCache;
+use crate::storage::sstable::{reader::SsTableReader, TableId};
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// An SSTable together with the metadata compaction needs. The file itself
+/// is opened through the shared table cache when it is read.
+pub struct TableMeta {
+    pub id: TableId,
+    pub path: PathBuf,
+    pub tables: Arc<TableCache>,
+    pub smallest: Vec<u8>,
+    pub largest: Vec<u8>,
+    pub file_size: u64,
+    /// Set once compaction has replaced the table; the file is deleted when
+    /// the last version referring to it is dropped.
+    pub retired: AtomicBool,
+}
+
+impl TableMeta {
+    pub fn reader(&self) -> std::io::Result<Arc<SsTableReader>> {
+        self.tables.get(self.id, &self.path)
+    }
+
+    pub fn retire(&self) {
+        self.retired.store(true, Ordering::Release);
+    }
+
+    pub fn overlaps(&self, smallest: &[u8], largest: &[u8]) -> bool {
+        self.smallest.as_slice() <= largest && smallest <= self.largest.as_slice()
+    }
+
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.smallest.as_slice() <= key && key <= self.largest.as_slice()
+    }
+
+    /// Whether any key between `start` and `end` may be in the table.
+    pub fn overlaps_range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> bool {
+        let after_start = match start {
+            Bound::Included(s) => s <= self.largest.as_slice(),
+            Bound::Excluded(s) => s < self.largest.as_slice(),
+            Bound::Unbounded => true,
+        };
+        let before_end = match end {
+            Bound::Included(e) => self.smallest.as_slice() <= e,
+            Bound::Excluded(e) => self.smallest.as_slice() < e,
+            Bound::Unbounded => true,
+        };
+        after_start && before_end
+    }
+}
+
+impl Drop for TableMeta {
+    fn drop(&mut self) {
+        if *self.retired.get_mut() {
+            // A leftover file is not referenced by the manifest and is harmless.
+            let _ = self.tables.context().env.remove_file(&self.path);
+            self.tables.remove(self.id);
+        }
+    }
+}
+
+/// The set of live tables, arranged in levels.
+///
+/// Level 0 holds freshly flushed tables in flush order (oldest first) and its
+/// tables may overlap. Every deeper level is sorted by key and its tables are
+/// disjoint; data in level `n` is always newer than data in level `n + 1`.
+#[derive(Clone)]
+pub struct Version {
+    levels: Vec<Vec<Arc<TableMeta>>>,
+}
+
+impl Version {
+    pub fn new(num_levels: usize) -> Self {
+        Self {
+            levels: (0..num_levels.max(2)).map(|_| Vec::new()).collect(),
+        }
+    }
+
+    pub fn num_levels(&self) -> usize {
+        self.levels.len()
+    }
+
+    pub fn level(&self, level: usize) -> &[Arc<TableMeta>] {
+        &self.levels[level]
+    }
+
+    pub fn level_bytes(&self, level: usize) -> u64 {
+        self.levels[level].iter().map(|t| t.file_size).sum()
+    }
+
+    pub fn add(&mut self, level: usize, table: Arc<TableMeta>) {
+        let tables = &mut self.levels[level];
+        if level == 0 {
+            tables.push(table);
+        } else {
+            let pos = tables.partition_point(|t| t.smallest < table.smallest);
+            tables.insert(pos, table);
+        }
+    }
+
+    pub fn remove(&mut self, id: TableId) -> Option<Arc<TableMeta>> {
+        for tables in &mut self.levels {
+            if let Some(pos) = tables.iter().position(|t| t.id == id) {
+                return Some(tables.remove(pos));
+            }
+        }
+        None
+    }
+
+    /// Tables in `level` whose key range intersects `[smallest, largest]`.
+    pub fn overlapping(
+        &self,
+        level: usize,
+        smallest: &[u8],
+        largest: &[u8],
+    ) -> Vec<Arc<TableMeta>> {
+        self.levels[level]
+            .iter()
+            .filter(|t| t.overlaps(smallest, largest))
+            .cloned()
+            .collect()
+    }
+
+    /// Every table, newest data first: level 0 from newest to oldest, then
+    /// each deeper level in key order.
+    pub fn tables_newest_first(&self) -> impl Iterator<Item = &Arc<TableMeta>> {
+        self.levels[0]
+            .iter()
+            .rev()
+            .chain(self.levels[1..].iter().flatten())
+    }
+
+    /// Finds the newest entry for `key` no later than `seq`, including
+    /// tombstones.
+    pub fn get(&self, key: &[u8], seq: SeqNo) -> std::io::Result<Option<Entry>> {
+        for table in self.levels[0].iter().rev() {
+            if !table.may_contain(key) {
+                continue;
+            }
+            if let Some(e) = table.reader()?.get(key, seq)? {
+                return Ok(Some(e));
+            }
+        }
+        for tables in &self.levels[1..] {
+            let pos = tables.partition_point(|t| t.largest.as_slice() < key);
+            if let Some(table) = tables.get(pos) {
+                if table.may_contain(key) {
+                    if let Some(e) = table.reader()?.get(key, seq)? {
+                        return Ok(Some(e));
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+}
@@ -0,0 +1,357 @@
+use crate::storage::env::{Env, WritableFile};
+use crate::storage::memtable::{Entry, SeqNo};
+use std::io::{BufWriter, Result, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Record header: payload length (u32) followed by the payload crc (u32).
+const HEADER_SIZE: usize = 4 + 4;
+
+/// When appended records are forced to stable storage.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// fsync before acknowledging every write (or write group).
+    #[default]
+    Always,
+    /// fsync once at least this many milliseconds have passed since the last sync.
+    EveryMillis(u64),
+    /// fsync once at least this many bytes have been appended since the last sync.
+    EveryBytes(u64),
+    /// Leave syncing to the OS; records still reach the page cache on every write.
+    Never,
+}
+
+impl std::str::FromStr for SyncPolicy {
+    type Err = String;
+
+    /// Parses `always`, `never`, `ms:<n>` or `bytes:<n>`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let parse_n = |n: &str| n.parse::<u64>().map_err(|e| format!("{s}: {e}"));
+        match s.split_once(':') {
+            None if s == "always" => Ok(SyncPolicy::Always),
+            None if s == "never" => Ok(SyncPolicy::Never),
+            Some(("ms", n)) => Ok(SyncPolicy::EveryMillis(parse_n(n)?)),
+            Some(("bytes", n)) => Ok(SyncPolicy::EveryBytes(parse_n(n)?)),
+            _ => Err(format!("unknown sync policy: {s}")),
+        }
+    }
+}
+
+impl std::fmt::Display for SyncPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SyncPolicy::Always => write!(f, "always"),
+            SyncPolicy::EveryMillis(ms) => write!(f, "ms:{ms}"),
+            SyncPolicy::EveryBytes(n) => write!(f, "bytes:{n}"),
+            SyncPolicy::Never => write!(f, "never"),
+        }
+    }
+}
+
+/// The entries carried by one log record, applied together on replay.
+pub type WalRecord = Vec<(Vec<u8>, Entry)>;
+
+/// Append-only log of mutations that have not yet reached an SSTable.
+///
+/// Each record is `len | crc | payload`, where the payload holds an entry
+/// count, the sequence number of the first entry and then the entries,
+/// encoded like version 1 data block records and numbered consecutively.
+/// A record is replayed in full or not at all; a torn or corrupt tail ends
+/// replay. Appends are handed to the OS immediately; when they are fsynced is
+/// decided by the [`SyncPolicy`].
+pub struct Wal {
+    writer: BufWriter<Box<dyn WritableFile>>,
+    path: PathBuf,
+    number: u64,
+    policy: SyncPolicy,
+    unsynced_bytes: u64,
+    last_sync: Instant,
+    /// Set once an append or sync fails. What reached the file is unknown
+    /// from then on, so every later append and sync fails too.
+    failed: bool,
+}
+
+impl Wal {
+    pub fn create(env: &dyn Env, path: PathBuf, number: u64, policy: SyncPolicy) -> Result<Self> {
+        let file = env.create(&path)?;
+        Ok(Self {
+            writer: BufWriter::new(file),
+            path,
+            number,
+            policy,
+            unsynced_bytes: 0,
+            last_sync: Instant::now(),
+            failed: false,
+        })
+    }
+
+    pub fn number(&self) -> u64 {
+        self.number
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn append_put(&mut self, key: &[u8], seq: SeqNo, value: &[u8]) -> Result<()> {
+        let mut payload = Vec::with_capacity(4 + 8 + 1 + 4 + 4 + key.len() + value.len());
+        payload.extend_from_slice(&1u32.to_le_bytes());
+        payload.extend_from_slice(&seq.to_le_bytes());
+        encode_entry(&mut payload, key, Some(value));
+        self.append_record(&payload)
+    }
+
+    pub fn append_delete(&mut self, key: &[u8], seq: SeqNo) -> Result<()> {
+        let mut payload = Vec::with_capacity(4 + 8 + 1 + 4 + 4 + key.len());
+        payload.extend_from_slice(&1u32.to_le_bytes());
+        payload.extend_from_slice(&seq.to_le_bytes());
+        encode_entry(&mut payload, key, None);
+        self.append_record(&payload)
+    }
+
+    /// Appends all entries as a single record, so replay sees all of them or
+    /// none. They are numbered consecutively from `first_seq`.
+    pub fn append_batch(&mut self, entries: &[(Vec<u8>, Entry)], first_seq: SeqNo) -> Result<()> {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+        payload.extend_from_slice(&first_seq.to_le_bytes());
+        for (key, entry) in entries {
+            match entry {
+                Entry::Put(v) => encode_entry(&mut payload, key, Some(v)),
+                Entry::Expiring { value, expires_at } => {
+                    encode_expiring(&mut payload, key, value, *expires_at)
+                }
+                Entry::Delete => encode_entry(&mut payload, key, None),
+                Entry::Merge(operand) => encode_merge(&mut payload, key, operand),
+            }
+        }
+        self.append_record(&payload)
+    }
+
+    pub fn policy(&self) -> SyncPolicy {
+        self.policy
+    }
+
+    pub fn unsynced_bytes(&self) -> u64 {
+        self.unsynced_bytes
+    }
+
+    /// Syncs if the policy says the records appended so far are due.
+    ///
+    /// Returns whether everything appended is now on stable storage.
+    pub fn sync_if_due(&mut self) -> Result<bool> {
+        if self.unsynced_bytes == 0 {
+            return Ok(true);
+        }
+        let due = match self.policy {
+            SyncPolicy::Always => true,
+            SyncPolicy::EveryMillis(ms) => self.last_sync.elapsed() >= Duration::from_millis(ms),
+            SyncPolicy::EveryBytes(n) => self.unsynced_bytes >= n,
+            SyncPolicy::Never => false,
+        };
+        if due {
+            self.sync()?;
+        }
+        Ok(due)
+    }
+
+    pub fn sync(&mut self) -> Result<()> {
+        self.check_usable()?;
+        let res = self
+            .writer
+            .flush()
+            .and_then(|()| self.writer.get_mut().sync());
+        self.failed = res.is_err();
+        res?;
+        self.unsynced_bytes = 0;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    fn append_record(&mut self, payload: &[u8]) -> Result<()> {
+        self.check_usable()?;
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(payload);
+        let crc = hasher.finalize();
+        let res = (|| {
+            self.writer
+                .write_all(&(payload.len() as u32).to_le_bytes())?;
+            self.writer.write_all(&crc.to_le_bytes())?;
+            self.writer.write_all(payload)?;
+            self.writer.flush()
+        })();
+        self.failed = res.is_err();
+        res?;
+        self.unsynced_bytes += (HEADER_SIZE + payload.len()) as u64;
+        Ok(())
+    }
+
+    fn check_usable(&self) -> Result<()> {
+        if self.failed {
+            return Err(std::io::Error::other(format!(
+                "log {} failed earlier and takes no more writes",
+                self.number
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn encode_entry(out: &mut Vec<u8>, key: &[u8], value: Option<&[u8]>) {
+    match value {
+        Some(v) => {
+            out.push(0);
+            out.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            out.extend_from_slice(&(v.len() as u32).to_le_bytes());
+            out.extend_from_slice(key);
+            out.extend_from_slice(v);
+        }
+        None => {
+            out.push(1);
+            out.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            out.extend_from_slice(&0u32.to_le_bytes());
+            out.extend_from_slice(key);
+        }
+    }
+}
+
+/// Like a put, followed by the expiry time as a `u64`.
+fn encode_expiring(out: &mut Vec<u8>, key: &[u8], value: &[u8], expires_at: u64) {
+    out.push(2);
+    out.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    out.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    out.extend_from_slice(key);
+    out.extend_from_slice(value);
+    out.extend_from_slice(&expires_at.to_le_bytes());
+}
+
+/// Like a put, with op `3`.
+fn encode_merge(out: &mut Vec<u8>, key: &[u8], operand: &[u8]) {
+    out.push(3);
+    out.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    out.extend_from_slice(&(operand.len() as u32).to_le_bytes());
+    out.extend_from_slice(key);
+    out.extend_from_slice(operand);
+}
+
+fn decode_entries(payload: &[u8]) -> Option<(SeqNo, WalRecord)> {
+    if payload.len() < 4 + 8 {
+        return None;
+    }
+    let count = u32::from_le_bytes(payload[0..4].try_into().unwrap()) as usize;
+    let first_seq = u64::from_le_bytes(payload[4..12].try_into().unwrap());
+    let mut p = 12usize;
+    let mut out = Vec::with_capacity(count);
+    for _ in 0..count {
+        if p + 1 + 4 + 4 > payload.len() {
+            return None;
+        }
+        let op = payload[p];
+        p += 1;
+        let klen = u32::from_le_bytes(payload[p..p + 4].try_into().unwrap()) as usize;
+        p += 4;
+        let vlen = u32::from_le_bytes(payload[p..p + 4].try_into().unwrap()) as usize;
+        p += 4;
+        if p + klen + vlen > payload.len() {
+            return None;
+        }
+        let key = payload[p..p + klen].to_vec();
+        p += klen;
+        let entry = match op {
+            0 => Entry::Put(payload[p..p + vlen].to_vec()),
+            1 => Entry::Delete,
+            2 => {
+                let expires_at = payload.get(p + vlen..p + vlen + 8)?;
+                let expires_at = u64::from_le_bytes(expires_at.try_into().unwrap());
+                let value = payload[p..p + vlen].to_vec();
+                p += 8;
+                Entry::Expiring { value, expires_at }
+            }
+            3 => Entry::Merge(payload[p..p + vlen].to_vec()),
+            _ => return None,
+        };
+        p += vlen;
+        out.push((key, entry));
+    }
+    Some((first_seq, out))
+}
+
+/// Reads every intact record from the log at `path`, in append order, with
+/// the sequence number of its first entry.
+///
+/// Replay stops at the first short or crc-mismatched record, which is what a
+/// crash in the middle of an append leaves behind.
+pub fn replay_wal(env: &dyn Env, path: &Path) -> Result<Vec<(SeqNo, WalRecord)>> {
+    let buf = env.read(path)?;
+
+    let mut records = Vec::new();
+    let mut p = 0usize;
+    while p + HEADER_SIZE <= buf.len() {
+        let len = u32::from_le_bytes(buf[p..p + 4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(buf[p + 4..p + 8].try_into().unwrap());
+        p += HEADER_SIZE;
+        if p + len > buf.len() {
+            break;
+        }
+        let payload = &buf[p..p + len];
+        p += len;
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(payload);
+        if hasher.finalize() != crc {
+            break;
+        }
+        match decode_entries(payload) {
+            Some(entries) => records.push(entries),
+            None => break,
+        }
+    }
+    Ok(records)
+}
+
+/// Parses a log file name of the form `NNNNNN.log`.
+pub fn parse_wal_name(name: &str) -> Option<u64> {
+    name.strip_suffix(".log")?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::env::MemEnv;
+
+    #[test]
+    fn replay_stops_at_torn_tail() {
+        let env = MemEnv::new();
+        env.create_dir_all(Path::new("/wal")).unwrap();
+        let path = PathBuf::from("/wal/000001.log");
+        {
+            let mut wal = Wal::create(&env, path.clone(), 1, SyncPolicy::Always).unwrap();
+            wal.append_put(b"k1", 7, b"v1").unwrap();
+            wal.append_delete(b"k2", 8).unwrap();
+        }
+        let full = env.read(&path).unwrap();
+        env.create(&path)
+            .unwrap()
+            .write_all(&full[..full.len() - 3])
+            .unwrap();
+
+        let records = replay_wal(&env, &path).unwrap();
+        assert_eq!(records.len(), 1);
+        let (seq, entries) = &records[0];
+        assert_eq!(*seq, 7);
+        assert_eq!(entries[0].0, b"k1".to_vec());
+        assert!(matches!(&entries[0].1, Entry::Put(v) if v == b"v1"));
+    }
+
+    #[test]
+    fn sync_policy_round_trips_through_str() {
+        for p in [
+            SyncPolicy::Always,
+            SyncPolicy::EveryMillis(5),
+            SyncPolicy::EveryBytes(4096),
+            SyncPolicy::Never,
+        ] {
+            assert_eq!(p.to_string().parse::<SyncPolicy>().unwrap(), p);
+        }
+        assert!("sometimes".parse::<SyncPolicy>().is_err());
+    }
+}
@@ -0,0 +1,29 @@
+//! Helpers shared by the unit tests.
+
+use crate::engine::options::EngineOptions;
+use crate::storage::compaction::CompactionOptions;
+use crate::storage::env::Env;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Options for an engine on `env`, such as a
+/// [`MemEnv`](crate::storage::env::MemEnv), that compacts level 0 once it
+/// holds `level0_file_trigger` tables. Everything else is the default.
+pub fn options<E: Env + 'static>(env: &Arc<E>, level0_file_trigger: usize) -> EngineOptions {
+    EngineOptions {
+        compaction: CompactionOptions {
+            level0_file_trigger,
+            ..CompactionOptions::default()
+        },
+        env: env.clone(),
+        ..EngineOptions::default()
+    }
+}
+
+/// A path in the system temp dir for the test `name`, cleared of whatever
+/// an earlier run left there. The directory itself is not created.
+pub fn tmp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("zynk-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}